@@ -0,0 +1,588 @@
+//! Append-only audit log of reads and writes. Every record carries the hash
+//! of the one before it, and checkpoint records periodically sign the head of
+//! the chain with the store's Ed25519 key, so that removing, reordering or
+//! editing records is detectable.
+
+use chrono::{DateTime, Utc};
+use ring::{
+    digest,
+    signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// Hash the first record in the chain points back to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// What a record in the audit log describes.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A caller read, listed or wrote a path
+    Access {
+        identity: Option<String>,
+        operation: String,
+        path: String,
+        outcome: String,
+        request_id: Option<String>,
+    },
+    /// A signature over the hash of the preceding record
+    Checkpoint { signature: String },
+}
+
+/// The hashed part of a record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    seq: u64,
+    timestamp: DateTime<Utc>,
+    prev_hash: String,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+impl AuditEntry {
+    fn hash(&self) -> String {
+        let bytes = serde_json::to_vec(self).unwrap();
+        hex::encode(digest::digest(&digest::SHA256, &bytes))
+    }
+}
+
+/// A single line of the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AuditRecord {
+    #[serde(flatten)]
+    entry: AuditEntry,
+    hash: String,
+}
+
+/// Requests the writer thread handles, in the order they were made.
+enum WriteRequest {
+    /// Appends a record of an access
+    Append(AuditEvent),
+    /// Signs the head of the chain if anything is unsigned
+    Checkpoint,
+}
+
+/// Most records a single sync covers, so that the first request in a batch is
+/// not held up behind a long backlog.
+const MAX_BATCH: usize = 256;
+
+/// Appends records to the end of the chain. It is owned by a single thread,
+/// which writes every request queued since its last sync and then syncs
+/// once for all of them.
+struct Writer {
+    seq: u64,
+    hash: String,
+    /// Whether any access has been recorded since the last checkpoint
+    unsigned: bool,
+    /// Length of the file once every record written so far is on disk
+    len: u64,
+    file: File,
+    key: Ed25519KeyPair,
+    synced_len: Arc<AtomicU64>,
+}
+
+impl Writer {
+    fn run(mut self, mut requests: mpsc::UnboundedReceiver<(WriteRequest, Reply)>) {
+        while let Some(first) = requests.blocking_recv() {
+            let mut batch = vec![first];
+            while batch.len() < MAX_BATCH {
+                match requests.try_recv() {
+                    Ok(request) => batch.push(request),
+                    Err(_) => break,
+                }
+            }
+
+            // A record that fails to be written leaves the end of the file in
+            // an unknown state, so nothing after it in the batch is written
+            let mut outcomes = vec![];
+            let mut failed = false;
+            for (request, reply) in batch {
+                let outcome = if failed {
+                    Err(io::Error::new(
+                        ErrorKind::Other,
+                        "An earlier audit record could not be written",
+                    ))
+                } else {
+                    self.write(request)
+                };
+                failed |= outcome.is_err();
+                outcomes.push((outcome, reply));
+            }
+
+            let synced = self.file.sync_data();
+            if synced.is_ok() {
+                self.synced_len.store(self.len, Ordering::Release);
+            }
+            for (outcome, reply) in outcomes {
+                let outcome = match (&synced, outcome) {
+                    (Err(e), Ok(_)) => Err(io::Error::new(e.kind(), e.to_string())),
+                    (_, outcome) => outcome,
+                };
+                // The caller may have gone away, in which case nobody is waiting
+                let _ = reply.send(outcome);
+            }
+        }
+    }
+
+    /// Writes a record for the request without syncing it, returning whether
+    /// one was written.
+    fn write(&mut self, request: WriteRequest) -> io::Result<bool> {
+        match request {
+            WriteRequest::Append(event) => {
+                self.append(event)?;
+                self.unsigned = true;
+                Ok(true)
+            }
+            WriteRequest::Checkpoint if !self.unsigned => Ok(false),
+            WriteRequest::Checkpoint => {
+                let signature = hex::encode(self.key.sign(self.hash.as_bytes()));
+                self.append(AuditEvent::Checkpoint { signature })?;
+                self.unsigned = false;
+                Ok(true)
+            }
+        }
+    }
+
+    fn append(&mut self, event: AuditEvent) -> io::Result<()> {
+        let entry = AuditEntry {
+            seq: self.seq + 1,
+            timestamp: Utc::now(),
+            prev_hash: self.hash.clone(),
+            event,
+        };
+        let record = AuditRecord {
+            hash: entry.hash(),
+            entry,
+        };
+
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+
+        self.seq = record.entry.seq;
+        self.hash = record.hash;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+type Reply = oneshot::Sender<io::Result<bool>>;
+
+pub struct AuditLog {
+    path: PathBuf,
+    public_key: UnparsedPublicKey<Vec<u8>>,
+    /// Length of the prefix of the file holding only synced, whole records
+    synced_len: Arc<AtomicU64>,
+    requests: mpsc::UnboundedSender<(WriteRequest, Reply)>,
+}
+
+/// Reads every record in the first `limit` bytes of the log, in order.
+fn read_records(path: &Path, limit: u64) -> io::Result<Vec<AuditRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = vec![];
+    for (index, line) in BufReader::new(file.take(limit)).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Audit log line {} is not a valid record: {}", index + 1, e),
+            )
+        })?);
+    }
+    Ok(records)
+}
+
+impl AuditLog {
+    /// Opens the log for appending, continuing the chain from its last record,
+    /// and starts the thread that writes to it.
+    pub fn open(path: PathBuf, key: Ed25519KeyPair) -> io::Result<Self> {
+        let (seq, hash, unsigned) = match read_records(&path, u64::MAX)?.pop() {
+            Some(last) => {
+                let unsigned = !matches!(last.entry.event, AuditEvent::Checkpoint { .. });
+                (last.entry.seq, last.hash, unsigned)
+            }
+            None => (0, GENESIS_HASH.to_owned(), false),
+        };
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+
+        let public_key =
+            UnparsedPublicKey::new(&signature::ED25519, key.public_key().as_ref().to_vec());
+        let synced_len = Arc::new(AtomicU64::new(len));
+        let (requests, receiver) = mpsc::unbounded_channel();
+        let writer = Writer {
+            seq,
+            hash,
+            unsigned,
+            len,
+            file,
+            key,
+            synced_len: synced_len.clone(),
+        };
+        thread::Builder::new()
+            .name("audit-writer".to_owned())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok(AuditLog {
+            path,
+            public_key,
+            synced_len,
+            requests,
+        })
+    }
+
+    /// Queues a request for the writer and waits until its record is on disk.
+    async fn submit(&self, request: WriteRequest) -> io::Result<bool> {
+        let (reply, outcome) = oneshot::channel();
+        let stopped = || io::Error::new(ErrorKind::Other, "The audit log writer has stopped");
+        self.requests
+            .send((request, reply))
+            .map_err(|_| stopped())?;
+        outcome.await.map_err(|_| stopped())?
+    }
+
+    /// Appends a record of a caller accessing a path, returning once it has
+    /// been synced along with any other records queued alongside it.
+    pub async fn record(
+        &self,
+        identity: Option<String>,
+        operation: &str,
+        path: &str,
+        outcome: &str,
+        request_id: Option<String>,
+    ) -> io::Result<()> {
+        let event = AuditEvent::Access {
+            identity,
+            operation: operation.to_owned(),
+            path: path.to_owned(),
+            outcome: outcome.to_owned(),
+            request_id,
+        };
+        self.submit(WriteRequest::Append(event)).await.map(|_| ())
+    }
+
+    /// Signs the head of the chain if anything was recorded since the last
+    /// checkpoint, returning whether a checkpoint was written.
+    pub async fn checkpoint(&self) -> io::Result<bool> {
+        self.submit(WriteRequest::Checkpoint).await
+    }
+
+    /// Verifies the chain up to the last synced record. Writers carry on
+    /// meanwhile, and anything they append is left for the next verification.
+    pub fn verify(&self) -> io::Result<Verification> {
+        let synced_len = self.synced_len.load(Ordering::Acquire);
+        Ok(verify_records(
+            read_records(&self.path, synced_len)?,
+            &self.public_key,
+        ))
+    }
+
+    /// Writes a checkpoint on the given interval for as long as the server runs.
+    pub fn spawn_signer(self: Arc<Self>, interval: Duration) {
+        tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.checkpoint().await {
+                    log::error!("Could not sign the audit log: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Where and why verification of the chain failed.
+#[derive(Debug, Serialize)]
+pub struct VerificationError {
+    pub seq: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "record {}: {}", self.seq, self.reason)
+    }
+}
+
+/// Result of checking the whole chain.
+#[derive(Debug, Serialize)]
+pub struct Verification {
+    pub valid: bool,
+    pub records: u64,
+    pub checkpoints: u64,
+    /// Last record covered by a valid checkpoint signature
+    pub last_signed_seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<VerificationError>,
+}
+
+/// Checks that every record hashes correctly and links to the one before it,
+/// and that every checkpoint carries a valid signature by the given key.
+pub fn verify<B: AsRef<[u8]>>(
+    path: &Path,
+    public_key: &UnparsedPublicKey<B>,
+) -> io::Result<Verification> {
+    Ok(verify_records(read_records(path, u64::MAX)?, public_key))
+}
+
+fn verify_records<B: AsRef<[u8]>>(
+    records: Vec<AuditRecord>,
+    public_key: &UnparsedPublicKey<B>,
+) -> Verification {
+    let mut verification = Verification {
+        valid: true,
+        records: 0,
+        checkpoints: 0,
+        last_signed_seq: 0,
+        error: None,
+    };
+
+    let mut prev_hash = GENESIS_HASH.to_owned();
+    for record in records {
+        let entry = &record.entry;
+        let problem = if entry.seq != verification.records + 1 {
+            Some(format!(
+                "expected sequence number {}",
+                verification.records + 1
+            ))
+        } else if entry.prev_hash != prev_hash {
+            Some("does not link to the previous record".to_owned())
+        } else if entry.hash() != record.hash {
+            Some("contents do not match its hash".to_owned())
+        } else if let AuditEvent::Checkpoint { signature } = &entry.event {
+            let signature_valid = hex::decode(signature)
+                .map(|signature| {
+                    public_key
+                        .verify(entry.prev_hash.as_bytes(), &signature)
+                        .is_ok()
+                })
+                .unwrap_or(false);
+            if signature_valid {
+                verification.checkpoints += 1;
+                verification.last_signed_seq = entry.seq;
+                None
+            } else {
+                Some("checkpoint signature is invalid".to_owned())
+            }
+        } else {
+            None
+        };
+
+        if let Some(reason) = problem {
+            verification.valid = false;
+            verification.error = Some(VerificationError {
+                seq: entry.seq,
+                reason,
+            });
+            break;
+        }
+        verification.records += 1;
+        prev_hash = record.hash;
+    }
+
+    verification
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    fn pkcs8() -> Vec<u8> {
+        Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .unwrap()
+            .as_ref()
+            .to_vec()
+    }
+
+    fn key(pkcs8: &[u8]) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_pkcs8(pkcs8).unwrap()
+    }
+
+    fn public_key(pkcs8: &[u8]) -> UnparsedPublicKey<Vec<u8>> {
+        UnparsedPublicKey::new(
+            &signature::ED25519,
+            key(pkcs8).public_key().as_ref().to_vec(),
+        )
+    }
+
+    /// Path of a log in a directory of its own, removed when dropped.
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+            TempLog(dir.join("audit.log"))
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    /// Writes two access records followed by a checkpoint.
+    async fn write_log(path: &Path, key: Ed25519KeyPair) -> Arc<AuditLog> {
+        let audit_log = Arc::new(AuditLog::open(path.to_owned(), key).unwrap());
+        for path in [".a.", ".b."] {
+            audit_log
+                .record(Some("CN=client".to_owned()), "read", path, "success", None)
+                .await
+                .unwrap();
+        }
+        assert!(audit_log.checkpoint().await.unwrap());
+        audit_log
+    }
+
+    fn edit_lines(path: &Path, edit: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        edit(&mut lines);
+        fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[tokio::test]
+    async fn verifies_an_untouched_chain() {
+        let log = TempLog::new();
+        let pkcs8 = pkcs8();
+        write_log(&log.0, key(&pkcs8)).await;
+
+        let verification = verify(&log.0, &public_key(&pkcs8)).unwrap();
+        assert!(verification.valid);
+        assert_eq!(verification.records, 3);
+        assert_eq!(verification.checkpoints, 1);
+        assert_eq!(verification.last_signed_seq, 3);
+        assert!(verification.error.is_none());
+    }
+
+    #[tokio::test]
+    async fn verifies_the_open_log_with_its_own_key() {
+        let log = TempLog::new();
+        let audit_log = write_log(&log.0, key(&pkcs8())).await;
+        audit_log
+            .record(None, "read", ".c.", "not_found", None)
+            .await
+            .unwrap();
+
+        let verification = audit_log.verify().unwrap();
+        assert!(verification.valid);
+        assert_eq!(verification.records, 4);
+        assert_eq!(verification.last_signed_seq, 3);
+    }
+
+    #[tokio::test]
+    async fn checkpoints_only_when_something_is_unsigned() {
+        let log = TempLog::new();
+        let audit_log = write_log(&log.0, key(&pkcs8())).await;
+
+        assert!(!audit_log.checkpoint().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn continues_the_chain_after_reopening() {
+        let log = TempLog::new();
+        let pkcs8 = pkcs8();
+        write_log(&log.0, key(&pkcs8)).await;
+        write_log(&log.0, key(&pkcs8)).await;
+
+        let verification = verify(&log.0, &public_key(&pkcs8)).unwrap();
+        assert!(verification.valid);
+        assert_eq!(verification.records, 6);
+        assert_eq!(verification.checkpoints, 2);
+    }
+
+    #[tokio::test]
+    async fn detects_edited_records() {
+        let log = TempLog::new();
+        let pkcs8 = pkcs8();
+        write_log(&log.0, key(&pkcs8)).await;
+        edit_lines(&log.0, |lines| {
+            lines[0] = lines[0].replace(".a.", ".c.");
+        });
+
+        let verification = verify(&log.0, &public_key(&pkcs8)).unwrap();
+        assert!(!verification.valid);
+        assert_eq!(verification.records, 0);
+        let error = verification.error.unwrap();
+        assert_eq!(error.seq, 1);
+        assert_eq!(error.reason, "contents do not match its hash");
+    }
+
+    #[tokio::test]
+    async fn detects_removed_records() {
+        let log = TempLog::new();
+        let pkcs8 = pkcs8();
+        write_log(&log.0, key(&pkcs8)).await;
+        edit_lines(&log.0, |lines| {
+            lines.remove(1);
+        });
+
+        let error = verify(&log.0, &public_key(&pkcs8)).unwrap().error.unwrap();
+        assert_eq!(error.seq, 3);
+        assert_eq!(error.reason, "expected sequence number 2");
+    }
+
+    #[tokio::test]
+    async fn detects_reordered_records() {
+        let log = TempLog::new();
+        let pkcs8 = pkcs8();
+        write_log(&log.0, key(&pkcs8)).await;
+        edit_lines(&log.0, |lines| lines.swap(0, 1));
+
+        let error = verify(&log.0, &public_key(&pkcs8)).unwrap().error.unwrap();
+        assert_eq!(error.seq, 2);
+        assert_eq!(error.reason, "expected sequence number 1");
+    }
+
+    #[tokio::test]
+    async fn detects_checkpoints_signed_by_another_key() {
+        let log = TempLog::new();
+        write_log(&log.0, key(&pkcs8())).await;
+
+        let verification = verify(&log.0, &public_key(&pkcs8())).unwrap();
+        assert!(!verification.valid);
+        assert_eq!(verification.records, 2);
+        assert_eq!(verification.last_signed_seq, 0);
+        let error = verification.error.unwrap();
+        assert_eq!(error.seq, 3);
+        assert_eq!(error.reason, "checkpoint signature is invalid");
+    }
+
+    #[test]
+    fn a_missing_log_is_an_empty_valid_chain() {
+        let log = TempLog::new();
+
+        let verification = verify(&log.0, &public_key(&pkcs8())).unwrap();
+        assert!(verification.valid);
+        assert_eq!(verification.records, 0);
+    }
+}
@@ -0,0 +1,159 @@
+//! Construction of the storage backends and connectivity checks against them.
+
+use crate::settings::Settings;
+use chrono::{DateTime, TimeZone, Utc};
+use mongodb::{Client, Database};
+use redact_crypto::{
+    storage::{gcs::GoogleCloudStorer, NonIndexedTypeStorer},
+    CryptoError, MongoStorer, Storer, Type, TypeStorer,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// Path that is never written to, looked up to prove a backend is reachable.
+const PING_PATH: &str = ".redact-store.ping.";
+
+/// Creates the indexed (Mongo) and blob (Google Cloud Storage) storers.
+pub fn storers(settings: &Settings) -> (Arc<MongoStorer>, Arc<TypeStorer>) {
+    let mongo_storer = Arc::new(MongoStorer::new(&settings.db.url, &settings.db.name));
+
+    let google_storer = Arc::new(TypeStorer::NonIndexed(NonIndexedTypeStorer::GoogleCloud(
+        GoogleCloudStorer::new(settings.google.storage_bucket_name.clone()),
+    )));
+
+    (mongo_storer, google_storer)
+}
+
+/// Connects to the database the indexed storer uses, for the collections the
+/// store manages itself.
+pub async fn database(settings: &Settings) -> Result<Database, mongodb::error::Error> {
+    let client = Client::with_uri_str(&settings.db.url).await?;
+    Ok(client.database(&settings.db.name))
+}
+
+/// Converts a timestamp to the form it is stored in the database in.
+pub fn to_bson(at: DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(at.timestamp_millis())
+}
+
+/// Converts a timestamp read from the database.
+pub fn to_chrono(at: bson::DateTime) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(at.timestamp_millis())
+        .single()
+        .unwrap_or_else(Utc::now)
+}
+
+/// Regular expression matching the paths that start with a prefix.
+pub fn prefix_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    pattern.push('^');
+    for c in prefix.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+/// Checks that a storer can be reached by looking up a path that does not
+/// exist; a not-found answer means the backend responded.
+pub async fn ping<S: Storer>(storer: &S) -> Result<(), CryptoError> {
+    match storer.get::<Type>(PING_PATH).await {
+        Ok(_) | Err(CryptoError::NotFound { .. }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Outcome of pinging a single backend.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "status")]
+pub enum BackendStatus {
+    Ok,
+    Unreachable { error: String },
+    Timeout { after_ms: u128 },
+}
+
+/// Whether every backend responded, along with the status of each one.
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub backends: BTreeMap<&'static str, BackendStatus>,
+}
+
+/// Pings the indexed and blob storers, remembering the result for a short
+/// time so that frequent probes do not hammer the backends.
+pub struct ReadinessProbe {
+    mongo_storer: Arc<MongoStorer>,
+    blob_storer: Arc<TypeStorer>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cached: Mutex<Option<(Instant, Arc<Readiness>)>>,
+}
+
+impl ReadinessProbe {
+    pub fn new(
+        mongo_storer: Arc<MongoStorer>,
+        blob_storer: Arc<TypeStorer>,
+        timeout: Duration,
+        cache_ttl: Duration,
+    ) -> Self {
+        ReadinessProbe {
+            mongo_storer,
+            blob_storer,
+            timeout,
+            cache_ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Returns the cached result if it is recent enough, otherwise pings the
+    /// backends. Concurrent callers wait for a single round of pings.
+    pub async fn check(&self) -> Arc<Readiness> {
+        let mut cached = self.cached.lock().await;
+        if let Some((checked_at, readiness)) = cached.as_ref() {
+            if checked_at.elapsed() < self.cache_ttl {
+                return readiness.clone();
+            }
+        }
+
+        let readiness = Arc::new(self.check_now().await);
+        *cached = Some((Instant::now(), readiness.clone()));
+        readiness
+    }
+
+    /// Pings both backends concurrently, bypassing the cache.
+    pub async fn check_now(&self) -> Readiness {
+        let (db, blob) = futures::join!(
+            self.status(ping(self.mongo_storer.as_ref())),
+            self.status(ping(self.blob_storer.as_ref()))
+        );
+        let backends: BTreeMap<&'static str, BackendStatus> =
+            [("db", db), ("google.storage", blob)].into_iter().collect();
+
+        Readiness {
+            ready: backends
+                .values()
+                .all(|status| matches!(status, BackendStatus::Ok)),
+            backends,
+        }
+    }
+
+    async fn status(&self, ping: impl Future<Output = Result<(), CryptoError>>) -> BackendStatus {
+        match tokio::time::timeout(self.timeout, ping).await {
+            Ok(Ok(())) => BackendStatus::Ok,
+            Ok(Err(e)) => BackendStatus::Unreachable {
+                error: e.to_string(),
+            },
+            Err(_) => BackendStatus::Timeout {
+                after_ms: self.timeout.as_millis(),
+            },
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Direct access to the blob storage bucket for the operations the blob
+//! storer does not offer. Objects are named after the path they were stored
+//! under, the same way `GoogleCloudStorer` names them.
+
+use cloud_storage::{Client, Error};
+
+pub struct BlobBucket {
+    client: Client,
+    bucket: String,
+}
+
+impl BlobBucket {
+    pub fn new(bucket: String) -> Self {
+        BlobBucket {
+            client: Client::default(),
+            bucket,
+        }
+    }
+
+    /// Deletes the object stored under a path, returning whether there was
+    /// one. Succeeds if it is already gone.
+    pub async fn delete(&self, path: &str) -> Result<bool, Error> {
+        match self.client.object().delete(&self.bucket, path).await {
+            Ok(()) => Ok(true),
+            Err(Error::Google(response)) if response.error.code == 404 => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -1,34 +1,71 @@
+use crate::{
+    identity::Identity,
+    listener::Listener,
+    logging, metrics,
+    revocation::CrlStore,
+    settings::HttpSettings,
+    telemetry,
+    xfcc::{TrustedProxy, Xfcc, XFCC_HEADER},
+};
 use futures::TryFutureExt;
-use std::{io, sync::Arc, time::SystemTime};
-use tokio::net;
+use hyper::server::conn::Http;
+use std::{fs::File, io, path::Path, sync::Arc, time::Duration};
 use tokio_rustls::{
-    rustls::{
-        server::{ClientCertVerified, ClientCertVerifier},
-        Certificate, ServerConfig,
-    },
+    rustls::{Certificate, RootCertStore, ServerConfig},
     TlsAcceptor,
 };
+use tracing::Instrument;
 use warp::hyper::service::{self, Service};
 
-pub struct AllowAnyClient {}
-impl ClientCertVerifier for AllowAnyClient {
-    fn client_auth_root_subjects(&self) -> Option<tokio_rustls::rustls::DistinguishedNames> {
-        Some(vec![])
+/// Loads the PEM-encoded CA certificates of a trust bundle.
+pub fn trust_bundle(path: &Path) -> io::Result<Vec<Certificate>> {
+    let file = File::open(path)?;
+    let mut reader = io::BufReader::new(file);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No CA certificates found in {}", path.display()),
+        ));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Loads the CA certificates that client certificate chains must lead to.
+pub fn client_roots(path: &Path) -> io::Result<RootCertStore> {
+    let certs: Vec<Vec<u8>> = trust_bundle(path)?.into_iter().map(|cert| cert.0).collect();
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("No CA certificates found in {}", path.display()),
+        ));
     }
+    Ok(roots)
+}
+
+/// ALPN protocols offered on the TLS listener, in order of preference.
+pub const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
 
-    fn verify_client_cert(
-        &self,
-        _: &tokio_rustls::rustls::Certificate,
-        _: &[tokio_rustls::rustls::Certificate],
-        _: SystemTime,
-    ) -> Result<ClientCertVerified, tokio_rustls::rustls::Error> {
-        Ok(ClientCertVerified::assertion())
+/// Builds the connection settings used to serve every accepted connection.
+/// Without TLS to negotiate the protocol, connections opening with the HTTP/2
+/// preface are served as h2c and all others as HTTP/1.1.
+pub fn http(settings: &HttpSettings) -> Http {
+    let mut http = Http::new();
+    http.http1_keep_alive(settings.keep_alive)
+        .http1_header_read_timeout(Duration::from_secs(settings.header_read_timeout))
+        .http2_max_concurrent_streams(settings.max_concurrent_streams);
+    if settings.keep_alive_interval > 0 {
+        http.http2_keep_alive_interval(Some(Duration::from_secs(settings.keep_alive_interval)));
     }
+    http
 }
 
 pub async fn serve_mtls<F>(
-    listener: &net::TcpListener,
+    listener: &Listener,
     tls_config: Arc<ServerConfig>,
+    http: Http,
     warp_filter: F,
 ) -> io::Result<()>
 where
@@ -37,13 +74,14 @@ where
 {
     let tls_acceptor = TlsAcceptor::from(tls_config);
 
-    // Wait for an incoming TCP connection
-    let (socket, _) = listener.accept().await?;
+    // Wait for an incoming connection
+    let socket = listener.accept().await?;
 
     // Interpret data coming through the TCP stream as a TLS stream
     let stream = tls_acceptor
         .accept(socket)
         .map_err(|err| {
+            metrics::tls_handshake_failed();
             io::Error::new(
                 io::ErrorKind::Other,
                 format!("Problem accepting TLS connection: {:?}", err),
@@ -53,8 +91,10 @@ where
 
     // Hand off actual request handling to a new tokio task
     tokio::task::spawn(async move {
-        // Pull the client certificate out of the TLS session
+        // Pull the client certificate and negotiated protocol out of the TLS session
         let (_, server_connection) = stream.get_ref();
+        let mut http = http;
+        http.http2_only(server_connection.alpn_protocol() == Some(b"h2"));
         let client_cert = server_connection.peer_certificates().and_then(|certs| {
             if certs.is_empty() {
                 None
@@ -69,15 +109,31 @@ where
         // into the request extentions before it goes into the filter.
         let mut svc = warp::service(warp_filter.clone());
         let service = service::service_fn(move |mut req| {
+            let span = telemetry::request_span(&req);
+            let identity = span.in_scope(|| {
+                let _extract = tracing::info_span!("identity.extract").entered();
+                client_cert
+                    .as_ref()
+                    .and_then(|cert| match Identity::from_certificate(cert) {
+                        Ok(identity) => Some(identity),
+                        Err(e) => {
+                            log::warn!("Could not extract identity from client certificate: {}", e);
+                            None
+                        }
+                    })
+            });
+
             if let Some(cert) = client_cert.to_owned() {
                 req.extensions_mut().insert(cert);
             }
-            svc.call(req)
+            if let Some(identity) = identity {
+                span.record("identity", tracing::field::display(&identity));
+                req.extensions_mut().insert(identity);
+            }
+            let context = logging::RequestContext::new(&req);
+            logging::scope(context, svc.call(req)).instrument(span)
         });
-        if let Err(e) = hyper::server::conn::Http::new()
-            .serve_connection(stream, service)
-            .await
-        {
+        if let Err(e) = http.serve_connection(stream, service).await {
             eprintln!("Error handling request: {}", e);
         }
     });
@@ -85,13 +141,26 @@ where
     Ok(())
 }
 
-pub async fn serve_xfcc<F>(listener: &net::TcpListener, warp_filter: F) -> io::Result<()>
+pub async fn serve_xfcc<F>(
+    listener: &Listener,
+    http: Http,
+    warp_filter: F,
+    crls: Arc<CrlStore>,
+    trusted_proxies: Arc<[TrustedProxy]>,
+) -> io::Result<()>
 where
     F: warp::Filter + Clone + Send + Sync + 'static,
     <F::Future as futures::TryFuture>::Ok: warp::Reply,
 {
-    // Wait for an incoming TCP connection
-    let (socket, _) = listener.accept().await?;
+    // Wait for an incoming connection
+    let socket = listener.accept().await?;
+
+    // Only the proxies that authenticated the caller may forward its certificate
+    let peer = socket.peer_addr();
+    let trusted = match &peer {
+        Ok(peer) => trusted_proxies.iter().any(|proxy| proxy.trusts(*peer)),
+        Err(_) => false,
+    };
 
     // Hand off actual request handling to a new tokio task
     tokio::task::spawn(async move {
@@ -101,31 +170,71 @@ where
         // into the request extentions before it goes into the filter.
         let mut svc = warp::service(warp_filter.clone());
         let service = service::service_fn(move |mut req| {
-            let cert = req
-                .headers()
-                .get("x-forwarded-client-cert")
-                .and_then(|xfcc_header| xfcc_header.to_str().ok())
-                .and_then(|xfcc_header_str| {
-                    xfcc_header_str
-                        .split(';')
-                        .find(|&elem| &elem[0..5] == "Cert=")
-                })
-                .and_then(|encoded_cert| {
-                    urlencoding::decode(&encoded_cert[6..encoded_cert.len() - 1]).ok()
-                })
-                .and_then(|cert| pem::parse(cert.into_owned()).ok());
-
-            if let Some(cert) = cert {
-                req.extensions_mut()
-                    .insert(Certificate(cert.into_contents()));
+            let span = telemetry::request_span(&req);
+            let extract = tracing::info_span!(parent: &span, "identity.extract").entered();
+            let xfcc_header = req.headers().get(XFCC_HEADER);
+            if xfcc_header.is_some() && !trusted {
+                match &peer {
+                    Ok(peer) => log::warn!("Ignoring XFCC header from untrusted peer {}", peer),
+                    Err(e) => log::warn!("Ignoring XFCC header from unknown peer: {}", e),
+                }
+            }
+            let xfcc = xfcc_header.filter(|_| trusted).and_then(|xfcc_header| {
+                match xfcc_header.to_str() {
+                    Ok(xfcc_header_str) => match xfcc_header_str.parse::<Xfcc>() {
+                        Ok(xfcc) => Some(xfcc),
+                        Err(e) => {
+                            metrics::xfcc_parse_failed();
+                            log::warn!("Could not parse XFCC header: {}", e);
+                            None
+                        }
+                    },
+                    Err(e) => {
+                        metrics::xfcc_parse_failed();
+                        log::warn!("XFCC header contains non-visible ASCII characters: {}", e);
+                        None
+                    }
+                }
+            });
+
+            // Forwarded certificates get the same revocation check as mTLS peers,
+            // a revoked forwarded certificate means the request carries no identity
+            let xfcc = xfcc.filter(|xfcc| {
+                let revoked = xfcc.peer().is_some_and(|peer| {
+                    peer.cert
+                        .iter()
+                        .chain(peer.chain.iter())
+                        .any(|cert| crls.is_revoked(cert))
+                });
+                if revoked {
+                    log::warn!("Ignoring XFCC header containing a revoked certificate");
+                }
+                !revoked
+            });
+
+            if let Some(xfcc) = xfcc {
+                if let Some(peer) = xfcc.peer() {
+                    if let Some(cert) = peer.cert.clone() {
+                        req.extensions_mut().insert(cert);
+                    }
+                    match Identity::from_xfcc(peer) {
+                        Some(identity) => {
+                            req.extensions_mut().insert(identity);
+                        }
+                        None => log::warn!("Ignoring XFCC element that identifies no caller"),
+                    }
+                }
+                req.extensions_mut().insert(xfcc);
+            }
+            if let Some(identity) = req.extensions().get::<Identity>() {
+                span.record("identity", tracing::field::display(identity));
             }
+            drop(extract);
 
-            svc.call(req)
+            let context = logging::RequestContext::new(&req);
+            logging::scope(context, svc.call(req)).instrument(span)
         });
-        if let Err(e) = hyper::server::conn::Http::new()
-            .serve_connection(socket, service)
-            .await
-        {
+        if let Err(e) = http.serve_connection(socket, service).await {
             eprintln!("Error handling request: {}", e);
         }
     });
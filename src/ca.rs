@@ -0,0 +1,603 @@
+//! The store's own certificate authority, used to issue the server
+//! certificate and client certificates from PKCS#10 certificate signing
+//! requests.
+//!
+//! Ed25519 keys are signed with redact-crypto's certificate tooling, every
+//! other key algorithm goes through rcgen.
+
+use crate::keys::StoreKey;
+use base64::{engine::general_purpose as b64_general_purpose, Engine};
+use chrono::{Duration, Utc};
+use redact_crypto::{
+    key::sodiumoxide::{
+        SodiumOxideEd25519PublicAsymmetricKey, SodiumOxideEd25519PublicAsymmetricKeyBuilder,
+        SodiumOxideEd25519SecretAsymmetricKey,
+    },
+    x509::DistinguishedName,
+    Builder, HasPublicKey, PublicAsymmetricKey,
+};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use x509_parser::{
+    certification_request::X509CertificationRequest,
+    extensions::{GeneralName, ParsedExtension},
+    oid_registry::OID_SIG_ED25519,
+    prelude::FromDer,
+};
+
+#[derive(Debug)]
+pub enum CaError {
+    /// The CSR could not be decoded from PEM or DER
+    InvalidCsr { reason: String },
+    /// The CSR signature does not match its public key
+    InvalidCsrSignature,
+    /// The CSR subject or requested validity violates the issuance policy
+    PolicyViolation { reason: String },
+    /// Building or signing the certificate failed
+    Signing { reason: String },
+}
+
+impl fmt::Display for CaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CaError::InvalidCsr { reason } => write!(f, "CSR is invalid: {}", reason),
+            CaError::InvalidCsrSignature => write!(f, "CSR signature is invalid"),
+            CaError::PolicyViolation { reason } => {
+                write!(f, "CSR violates the issuance policy: {}", reason)
+            }
+            CaError::Signing { reason } => write!(f, "Could not sign certificate: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CaError {}
+
+fn signing_error(e: impl fmt::Display) -> CaError {
+    CaError::Signing {
+        reason: e.to_string(),
+    }
+}
+
+/// A subject alternative name written into an issued certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SubjectAltName {
+    Dns(String),
+    Ip(IpAddr),
+}
+
+impl FromStr for SubjectAltName {
+    type Err = String;
+
+    /// Parses an IP address if possible, and a DNS name otherwise.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<IpAddr>() {
+            Ok(ip) => Ok(SubjectAltName::Ip(ip)),
+            Err(_) if is_dns_name(s) => Ok(SubjectAltName::Dns(s.to_owned())),
+            Err(_) => Err(format!("'{}' is neither a DNS name nor an IP address", s)),
+        }
+    }
+}
+
+/// Whether a name is made of letters, digits and hyphens in labels of at most
+/// 63 characters, optionally behind a `*.` wildcard label.
+fn is_dns_name(name: &str) -> bool {
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+impl fmt::Display for SubjectAltName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubjectAltName::Dns(dns_name) => write!(f, "{}", dns_name),
+            SubjectAltName::Ip(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// Constraints applied to every client certificate issued by the CA.
+#[derive(Debug, Clone)]
+pub struct IssuancePolicy {
+    /// Organization written into every issued certificate, overriding the CSR
+    pub o: String,
+    /// Organizational unit written into every issued certificate, overriding the CSR
+    pub ou: String,
+    /// Domain suffixes the requested common name and DNS names must end with;
+    /// any name is allowed if empty
+    pub cn_suffixes: Vec<String>,
+    /// DNS names of the server certificate, which no client certificate may carry
+    pub reserved_names: Vec<String>,
+    /// Validity in days when the request does not specify one
+    pub default_expires_in: i64,
+    /// Longest validity in days a request may ask for
+    pub max_expires_in: i64,
+}
+
+impl IssuancePolicy {
+    /// Whether a client certificate may carry a name, as its common name or
+    /// as a DNS name.
+    fn allows_name(&self, name: &str) -> bool {
+        let reserved = self
+            .reserved_names
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(name));
+        let suffixed = self.cn_suffixes.is_empty()
+            || self
+                .cn_suffixes
+                .iter()
+                .any(|suffix| has_domain_suffix(name, suffix));
+        !reserved && suffixed
+    }
+}
+
+/// Whether a name is a domain suffix or ends with it on a label boundary, so
+/// that `evilexample.com` does not match `example.com`.
+fn has_domain_suffix(name: &str, suffix: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let suffix = suffix.trim_start_matches('.').to_ascii_lowercase();
+    !suffix.is_empty()
+        && (name == suffix
+            || name
+                .strip_suffix(&suffix)
+                .is_some_and(|label| label.ends_with('.')))
+}
+
+pub struct CertificateAuthority {
+    key: StoreKey,
+    cert_der: Vec<u8>,
+    o: String,
+    ou: String,
+    cn: String,
+    policy: IssuancePolicy,
+}
+
+impl CertificateAuthority {
+    pub fn new(
+        key: StoreKey,
+        cert_der: Vec<u8>,
+        o: String,
+        ou: String,
+        cn: String,
+        policy: IssuancePolicy,
+    ) -> Self {
+        CertificateAuthority {
+            key,
+            cert_der,
+            o,
+            ou,
+            cn,
+            policy,
+        }
+    }
+
+    /// Creates a self-signed CA certificate for the key, valid from now for
+    /// the given number of days.
+    pub fn generate_certificate(
+        key: &StoreKey,
+        dn: &DistinguishedName,
+        expires_in: i64,
+    ) -> Result<Vec<u8>, CaError> {
+        match key {
+            StoreKey::Ed25519 { key, .. } => {
+                let not_before = Utc::now();
+                let not_after = not_before + Duration::days(expires_in);
+                redact_crypto::cert::setup_cert::<_, PublicAsymmetricKey>(
+                    key, None, dn, None, not_before, not_after, true, None,
+                )
+                .map_err(signing_error)
+            }
+            StoreKey::Pkcs8 { .. } => {
+                let mut params = rcgen_params(key, dn, expires_in, &[])?;
+                params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+                rcgen::Certificate::from_params(params)
+                    .and_then(|cert| cert.serialize_der())
+                    .map_err(signing_error)
+            }
+        }
+    }
+
+    /// Verifies a PEM or DER encoded CSR and issues a DER-encoded client
+    /// certificate for it, valid for the given number of days or the policy
+    /// default.
+    pub fn sign_csr(&self, csr: &[u8], expires_in: Option<i64>) -> Result<Vec<u8>, CaError> {
+        let csr_der = match pem::parse(csr) {
+            Ok(pem) => pem.into_contents(),
+            Err(_) => csr.to_vec(),
+        };
+        let (_, csr) =
+            X509CertificationRequest::from_der(&csr_der).map_err(|e| CaError::InvalidCsr {
+                reason: e.to_string(),
+            })?;
+        csr.verify_signature()
+            .map_err(|_| CaError::InvalidCsrSignature)?;
+
+        let expires_in = expires_in.unwrap_or(self.policy.default_expires_in);
+        if expires_in < 1 || expires_in > self.policy.max_expires_in {
+            return Err(CaError::PolicyViolation {
+                reason: format!(
+                    "validity must be between 1 and {} days",
+                    self.policy.max_expires_in
+                ),
+            });
+        }
+
+        let info = &csr.certification_request_info;
+        let cn = info
+            .subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .ok_or_else(|| CaError::PolicyViolation {
+                reason: "subject must contain a common name".to_owned(),
+            })?;
+        if !self.policy.allows_name(cn) {
+            return Err(CaError::PolicyViolation {
+                reason: format!("common name '{}' is not allowed", cn),
+            });
+        }
+
+        // Carry over any DNS names from the requested subject alternative
+        // names, which are held to the same policy as the common name
+        let dns_names: Vec<&str> = csr
+            .requested_extensions()
+            .into_iter()
+            .flatten()
+            .filter_map(|extension| match extension {
+                ParsedExtension::SubjectAlternativeName(san) => Some(san),
+                _ => None,
+            })
+            .flat_map(|san| san.general_names.iter())
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns_name) => Some(*dns_name),
+                _ => None,
+            })
+            .collect();
+        if let Some(dns_name) = dns_names
+            .iter()
+            .find(|dns_name| !self.policy.allows_name(dns_name))
+        {
+            return Err(CaError::PolicyViolation {
+                reason: format!("subject alternative name '{}' is not allowed", dns_name),
+            });
+        }
+
+        let subject_dn = DistinguishedName {
+            o: &self.policy.o,
+            ou: &self.policy.ou,
+            cn,
+        };
+        match &self.key {
+            StoreKey::Ed25519 { key, .. }
+                if info.subject_pki.algorithm.algorithm == OID_SIG_ED25519 =>
+            {
+                let subject_key = SodiumOxideEd25519PublicAsymmetricKeyBuilder {}
+                    .build(Some(&info.subject_pki.subject_public_key.data))
+                    .map_err(|e| CaError::InvalidCsr {
+                        reason: e.to_string(),
+                    })?;
+                self.issue_ed25519(key, &subject_key, &subject_dn, expires_in, &dns_names)
+            }
+            _ => {
+                let mut request = rcgen::CertificateSigningRequest::from_der(&csr_der)
+                    .map_err(|e| CaError::InvalidCsr {
+                        reason: e.to_string(),
+                    })?;
+                let (not_before, not_after) = rcgen_validity(expires_in);
+                request.params.distinguished_name = rcgen_dn(&subject_dn);
+                request.params.subject_alt_names = dns_names
+                    .iter()
+                    .map(|dns_name| rcgen::SanType::DnsName((*dns_name).to_owned()))
+                    .collect();
+                request.params.not_before = not_before;
+                request.params.not_after = not_after;
+                request
+                    .serialize_der_with_signer(&self.rcgen_signer()?)
+                    .map_err(signing_error)
+            }
+        }
+    }
+
+    /// Issues a DER-encoded leaf certificate for the given key, valid from now
+    /// for the given number of days.
+    pub fn issue(
+        &self,
+        subject_key: &StoreKey,
+        subject_dn: &DistinguishedName,
+        expires_in: i64,
+        sans: &[SubjectAltName],
+    ) -> Result<Vec<u8>, CaError> {
+        // redact-crypto can only write DNS names, IP addresses need rcgen
+        let dns_names: Option<Vec<&str>> = sans
+            .iter()
+            .map(|san| match san {
+                SubjectAltName::Dns(dns_name) => Some(dns_name.as_str()),
+                SubjectAltName::Ip(_) => None,
+            })
+            .collect();
+
+        match (&self.key, subject_key, dns_names) {
+            (
+                StoreKey::Ed25519 { key: ca_key, .. },
+                StoreKey::Ed25519 { key, .. },
+                Some(dns_names),
+            ) => {
+                let public_key = key.public_key().map_err(signing_error)?;
+                self.issue_ed25519(ca_key, &public_key, subject_dn, expires_in, &dns_names)
+            }
+            _ => {
+                let signer = self.rcgen_signer()?;
+                let params = rcgen_params(subject_key, subject_dn, expires_in, sans)?;
+                rcgen::Certificate::from_params(params)
+                    .and_then(|cert| cert.serialize_der_with_signer(&signer))
+                    .map_err(signing_error)
+            }
+        }
+    }
+
+    fn issue_ed25519(
+        &self,
+        ca_key: &SodiumOxideEd25519SecretAsymmetricKey,
+        subject_key: &SodiumOxideEd25519PublicAsymmetricKey,
+        subject_dn: &DistinguishedName,
+        expires_in: i64,
+        dns_names: &[&str],
+    ) -> Result<Vec<u8>, CaError> {
+        let issuer_dn = DistinguishedName {
+            o: &self.o,
+            ou: &self.ou,
+            cn: &self.cn,
+        };
+        let not_before = Utc::now();
+        let not_after = not_before + Duration::days(expires_in);
+        redact_crypto::cert::setup_cert(
+            ca_key,
+            Some(subject_key),
+            &issuer_dn,
+            Some(subject_dn),
+            not_before,
+            not_after,
+            false,
+            if dns_names.is_empty() {
+                None
+            } else {
+                Some(dns_names)
+            },
+        )
+        .map_err(signing_error)
+    }
+
+    /// Rebuilds the CA as an rcgen certificate so it can sign with rcgen.
+    fn rcgen_signer(&self) -> Result<rcgen::Certificate, CaError> {
+        let key_pair = self.key.to_key_pair().map_err(signing_error)?;
+        rcgen::CertificateParams::from_ca_cert_der(&self.cert_der, key_pair)
+            .and_then(rcgen::Certificate::from_params)
+            .map_err(signing_error)
+    }
+}
+
+fn rcgen_validity(expires_in: i64) -> (time::OffsetDateTime, time::OffsetDateTime) {
+    let not_before = time::OffsetDateTime::now_utc();
+    (not_before, not_before + time::Duration::days(expires_in))
+}
+
+fn rcgen_dn(dn: &DistinguishedName) -> rcgen::DistinguishedName {
+    let mut rcgen_dn = rcgen::DistinguishedName::new();
+    rcgen_dn.push(rcgen::DnType::OrganizationName, dn.o);
+    rcgen_dn.push(rcgen::DnType::OrganizationalUnitName, dn.ou);
+    rcgen_dn.push(rcgen::DnType::CommonName, dn.cn);
+    rcgen_dn
+}
+
+fn rcgen_sans(sans: &[SubjectAltName]) -> Vec<rcgen::SanType> {
+    sans.iter()
+        .map(|san| match san {
+            SubjectAltName::Dns(dns_name) => rcgen::SanType::DnsName(dns_name.clone()),
+            SubjectAltName::Ip(ip) => rcgen::SanType::IpAddress(*ip),
+        })
+        .collect()
+}
+
+fn rcgen_params(
+    key: &StoreKey,
+    dn: &DistinguishedName,
+    expires_in: i64,
+    sans: &[SubjectAltName],
+) -> Result<rcgen::CertificateParams, CaError> {
+    let key_pair = key.to_key_pair().map_err(signing_error)?;
+    let (not_before, not_after) = rcgen_validity(expires_in);
+    let mut params = rcgen::CertificateParams::default();
+    params.alg = key_pair.algorithm();
+    params.key_pair = Some(key_pair);
+    params.distinguished_name = rcgen_dn(dn);
+    params.subject_alt_names = rcgen_sans(sans);
+    params.not_before = not_before;
+    params.not_after = not_after;
+    Ok(params)
+}
+
+/// Reads the first certificate from a PEM file as DER.
+pub fn read_certificate(path: &Path) -> io::Result<Vec<u8>> {
+    let file = fs::File::open(path)?;
+    let mut reader = io::BufReader::new(file);
+    rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No certificate found in {}", path.display()),
+            )
+        })
+}
+
+/// Encodes a DER certificate as a PEM document.
+pub fn certificate_pem(der: &[u8]) -> String {
+    let mut pem = String::from("-----BEGIN CERTIFICATE-----\n");
+    b64_general_purpose::STANDARD
+        .encode(der)
+        .as_bytes()
+        .chunks(64)
+        .for_each(|chunk| {
+            pem.push_str(std::str::from_utf8(chunk).unwrap());
+            pem.push('\n');
+        });
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+/// Writes a DER certificate to disk as PEM, creating parent directories as
+/// needed. The certificate is written to a temporary file that then replaces
+/// the target, so a certificate being renewed is never seen half written.
+pub fn write_certificate(path: &Path, der: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(certificate_pem(der).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Reads the DNS and IP subject alternative names of a DER certificate.
+pub fn certificate_sans(der: &[u8]) -> io::Result<Vec<SubjectAltName>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let sans = cert
+        .subject_alternative_name()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(dns_name) => {
+                        Some(SubjectAltName::Dns((*dns_name).to_owned()))
+                    }
+                    GeneralName::IPAddress(octets) => match octets.len() {
+                        4 => <[u8; 4]>::try_from(*octets)
+                            .ok()
+                            .map(|octets| SubjectAltName::Ip(IpAddr::from(octets))),
+                        16 => <[u8; 16]>::try_from(*octets)
+                            .ok()
+                            .map(|octets| SubjectAltName::Ip(IpAddr::from(octets))),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(sans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(cn_suffixes: &[&str], reserved_names: &[&str]) -> IssuancePolicy {
+        IssuancePolicy {
+            o: "pauwels".to_owned(),
+            ou: "client".to_owned(),
+            cn_suffixes: cn_suffixes
+                .iter()
+                .map(|suffix| suffix.to_string())
+                .collect(),
+            reserved_names: reserved_names.iter().map(|name| name.to_string()).collect(),
+            default_expires_in: 30,
+            max_expires_in: 90,
+        }
+    }
+
+    #[test]
+    fn allows_any_name_without_suffixes() {
+        assert!(policy(&[], &[]).allows_name("anything.example.com"));
+    }
+
+    #[test]
+    fn cn_suffixes_match_on_label_boundaries() {
+        let policy = policy(&["example.com", ".clients.internal"], &[]);
+
+        assert!(policy.allows_name("example.com"));
+        assert!(policy.allows_name("worker.example.com"));
+        assert!(policy.allows_name("a.b.clients.internal"));
+        assert!(policy.allows_name("clients.internal"));
+        assert!(!policy.allows_name("evilexample.com"));
+        assert!(!policy.allows_name("example.com.evil.org"));
+        assert!(!policy.allows_name("otherclients.internal"));
+    }
+
+    #[test]
+    fn cn_suffixes_ignore_case() {
+        let policy = policy(&["Example.COM"], &[]);
+
+        assert!(policy.allows_name("Worker.example.com"));
+    }
+
+    #[test]
+    fn empty_cn_suffixes_match_nothing() {
+        let policy = policy(&[".", ""], &[]);
+
+        assert!(!policy.allows_name("example.com"));
+    }
+
+    #[test]
+    fn refuses_the_server_names() {
+        let policy = policy(&["example.com"], &["store.example.com"]);
+
+        assert!(!policy.allows_name("store.example.com"));
+        assert!(!policy.allows_name("STORE.example.com"));
+        assert!(policy.allows_name("client.example.com"));
+        assert!(!policy(&[], &["localhost"]).allows_name("localhost"));
+    }
+
+    #[test]
+    fn parses_subject_alt_names() {
+        assert_eq!(
+            "store.example.com".parse(),
+            Ok(SubjectAltName::Dns("store.example.com".to_owned()))
+        );
+        assert_eq!(
+            "*.store-1.internal".parse(),
+            Ok(SubjectAltName::Dns("*.store-1.internal".to_owned()))
+        );
+        assert_eq!(
+            "::1".parse(),
+            Ok(SubjectAltName::Ip(IpAddr::V6(
+                std::net::Ipv6Addr::LOCALHOST
+            )))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_subject_alt_names() {
+        for san in [
+            "",
+            "store..example.com",
+            "store example.com",
+            "-store.example.com",
+            "store_1.example.com",
+            "a.*.example.com",
+            "10.0.0.256/8",
+        ] {
+            assert!(san.parse::<SubjectAltName>().is_err(), "{}", san);
+        }
+    }
+}
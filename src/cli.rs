@@ -0,0 +1,24 @@
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(author, version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Generate any missing CA and server TLS material, then print a summary of it
+    InitTls {
+        /// Only print a summary of the existing material without generating anything
+        #[arg(long)]
+        inspect: bool,
+    },
+    /// Validate the config and check that the storage backends are reachable
+    CheckConfig,
+    /// Verify the hash chain and checkpoint signatures of the audit log
+    VerifyAudit,
+    /// Run the storage server (the default when no subcommand is given)
+    Serve,
+}
@@ -0,0 +1,686 @@
+//! Erasure of everything tied to one client identity or path prefix, run as
+//! a tracked background job. A job deletes the matching index entries, trash
+//! items, versions and their blobs, then the keys stored here that sealed
+//! them once nothing left in the store uses those keys. It ends with a
+//! receipt signed with the store's Ed25519 key.
+
+use crate::{
+    audit::AuditLog,
+    blobs::BlobBucket,
+    expiry::ExpiryStore,
+    history::{self, HistoryError, VersionStore},
+    index::EntryIndex,
+    policy::PolicyStore,
+    trash::{TrashError, TrashStore},
+};
+use bson::{doc, Bson, Document};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    options::{FindOptions, IndexOptions, UpdateOptions},
+    Collection, Database, IndexModel,
+};
+use ring::{
+    digest,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Collection erasure jobs are tracked in.
+const ERASURES_COLLECTION: &str = "redact_store_erasures";
+
+/// Collection the paths erased by each job are recorded in.
+const ERASED_PATHS_COLLECTION: &str = "redact_store_erased_paths";
+
+#[derive(Debug)]
+pub enum ErasureError {
+    /// The jobs, entries or expiries could not be read or written
+    Database(mongodb::error::Error),
+    /// The versions of a path could not be read or deleted
+    History(HistoryError),
+    /// The trash items of a path could not be deleted
+    Trash(TrashError),
+    /// The blob of an erased entry could not be deleted
+    Blob(cloud_storage::Error),
+}
+
+impl fmt::Display for ErasureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErasureError::Database(e) => write!(f, "Erasure jobs are unavailable: {}", e),
+            ErasureError::History(e) => write!(f, "{}", e),
+            ErasureError::Trash(e) => write!(f, "{}", e),
+            ErasureError::Blob(e) => write!(f, "Could not delete erased blob: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ErasureError {}
+
+impl From<mongodb::error::Error> for ErasureError {
+    fn from(e: mongodb::error::Error) -> Self {
+        ErasureError::Database(e)
+    }
+}
+
+impl From<HistoryError> for ErasureError {
+    fn from(e: HistoryError) -> Self {
+        ErasureError::History(e)
+    }
+}
+
+impl From<TrashError> for ErasureError {
+    fn from(e: TrashError) -> Self {
+        ErasureError::Trash(e)
+    }
+}
+
+/// What a job erases.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ErasureScope {
+    /// Every version written by an identity, along with the entries and trash
+    /// items holding those versions
+    Identity { identity: String },
+    /// Every entry, version and trash item under a path prefix
+    Prefix { prefix: String },
+}
+
+impl ErasureScope {
+    /// The identity or prefix being erased.
+    pub fn target(&self) -> &str {
+        match self {
+            ErasureScope::Identity { identity } => identity,
+            ErasureScope::Prefix { prefix } => prefix,
+        }
+    }
+
+    /// The identity whose writes are erased, none when everything is.
+    fn written_by(&self) -> Option<&str> {
+        match self {
+            ErasureScope::Identity { identity } => Some(identity),
+            ErasureScope::Prefix { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErasureStatus {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+}
+
+/// How much of a job has been done.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ErasureProgress {
+    pub paths_found: u64,
+    pub paths_erased: u64,
+    pub entries_erased: u64,
+    pub versions_erased: u64,
+    pub blobs_erased: u64,
+    pub trash_items_erased: u64,
+    pub keys_erased: u64,
+}
+
+/// What a finished job attests to have erased.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErasureReceipt {
+    pub job_id: String,
+    pub scope: ErasureScope,
+    pub requested_by: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub completed_at: DateTime<Utc>,
+    pub progress: ErasureProgress,
+    /// Paths left in place because a legal hold covers them
+    pub held_paths: Vec<String>,
+    /// SHA-256 of the erased paths, sorted and separated by newlines
+    pub erased_paths_digest: String,
+}
+
+/// A receipt along with the Ed25519 signature over its JSON serialization.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedReceipt {
+    pub receipt: ErasureReceipt,
+    pub public_key: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErasureJob {
+    pub id: String,
+    pub scope: ErasureScope,
+    pub status: ErasureStatus,
+    pub requested_by: Option<String>,
+    pub requested_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub progress: ErasureProgress,
+    pub held_paths: Vec<String>,
+    pub error: Option<String>,
+    pub receipt: Option<SignedReceipt>,
+}
+
+/// A path erased by a job, kept apart from the job so that erasing any
+/// number of paths does not grow the job document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ErasedPath {
+    job_id: String,
+    path: String,
+}
+
+/// What erasing a single path deleted.
+#[derive(Default)]
+struct Erased {
+    entries: i64,
+    versions: i64,
+    blobs: i64,
+    trash_items: i64,
+}
+
+/// Collects the paths of the stored keys an entry document was sealed with,
+/// at any depth. Only the key entries inside sealed states count, which
+/// redact_crypto serializes as `{"t": "Sealed", "c": {"unsealable": ...}}`;
+/// other references, such as an entry pointing at data elsewhere, are not keys.
+fn collect_references(document: &Document, references: &mut HashSet<String>) {
+    for value in document.values() {
+        collect_nested_references(value, references);
+    }
+}
+
+fn collect_nested_references(value: &Bson, references: &mut HashSet<String>) {
+    match value {
+        Bson::Document(document) => {
+            let unsealable = match (document.get_str("t"), document.get_document("c")) {
+                (Ok("Sealed"), Ok(state)) => state.get("unsealable"),
+                _ => None,
+            };
+            match unsealable {
+                Some(unsealable) => collect_keys(unsealable, references),
+                None => collect_references(document, references),
+            }
+        }
+        Bson::Array(values) => {
+            for value in values {
+                collect_nested_references(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the paths of the key entries within an unsealable that refer to
+/// a key kept in a storer, including the keys embedded keys were sealed with.
+fn collect_keys(value: &Bson, references: &mut HashSet<String>) {
+    match value {
+        Bson::Document(document) => {
+            let referenced = match document.get_document("value") {
+                Ok(state) if document.contains_key("path") => {
+                    match (state.get_str("t"), state.get_document("c")) {
+                        (Ok("Referenced"), Ok(state)) => state.get_str("path").ok(),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            match referenced {
+                Some(path) => {
+                    references.insert(path.to_owned());
+                }
+                // A key embedded in the unsealable may itself be sealed with a stored key
+                None => {
+                    for value in document.values() {
+                        collect_keys(value, references);
+                    }
+                }
+            }
+        }
+        Bson::Array(values) => {
+            for value in values {
+                collect_keys(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the references of the entry snapshot kept by a version.
+fn collect_version_references(
+    version: &history::Version,
+    references: &mut HashSet<String>,
+) -> Result<(), HistoryError> {
+    if let Some(entry) = &version.entry {
+        let document: Document =
+            serde_json::from_str(entry).map_err(HistoryError::Serialization)?;
+        collect_references(&document, references);
+    }
+    Ok(())
+}
+
+pub struct ErasureStore {
+    jobs: Collection<ErasureJob>,
+    erased_paths: Collection<ErasedPath>,
+    index: Arc<EntryIndex>,
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    trash: Arc<TrashStore>,
+    blobs: Arc<BlobBucket>,
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+    key: Ed25519KeyPair,
+    /// Held by the job being run, so that jobs run one at a time
+    running: Mutex<()>,
+}
+
+impl ErasureStore {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        database: &Database,
+        index: Arc<EntryIndex>,
+        versions: Arc<VersionStore>,
+        expiries: Arc<ExpiryStore>,
+        trash: Arc<TrashStore>,
+        blobs: Arc<BlobBucket>,
+        policies: Arc<PolicyStore>,
+        audit_log: Option<Arc<AuditLog>>,
+        key: Ed25519KeyPair,
+    ) -> Result<Self, mongodb::error::Error> {
+        let jobs = database.collection::<ErasureJob>(ERASURES_COLLECTION);
+        jobs.create_index(
+            IndexModel::builder()
+                .keys(doc! { "id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            None,
+        )
+        .await?;
+        let erased_paths = database.collection::<ErasedPath>(ERASED_PATHS_COLLECTION);
+        erased_paths
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "job_id": 1, "path": 1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        Ok(ErasureStore {
+            jobs,
+            erased_paths,
+            index,
+            versions,
+            expiries,
+            trash,
+            blobs,
+            policies,
+            audit_log,
+            key,
+            running: Mutex::new(()),
+        })
+    }
+
+    /// Records a new job and starts running it in the background.
+    pub async fn start(
+        self: &Arc<Self>,
+        scope: ErasureScope,
+        requested_by: Option<String>,
+    ) -> Result<ErasureJob, mongodb::error::Error> {
+        let job = ErasureJob {
+            id: Uuid::new_v4().to_string(),
+            scope,
+            status: ErasureStatus::Pending,
+            requested_by,
+            requested_at: Utc::now(),
+            finished_at: None,
+            progress: ErasureProgress::default(),
+            held_paths: vec![],
+            error: None,
+            receipt: None,
+        };
+        self.jobs.insert_one(&job, None).await?;
+        self.clone().spawn(job.clone());
+        Ok(job)
+    }
+
+    /// Reads a job, none if there is no such job.
+    pub async fn get(&self, id: &str) -> Result<Option<ErasureJob>, mongodb::error::Error> {
+        self.jobs.find_one(doc! { "id": id }, None).await
+    }
+
+    /// Starts again every job that had not finished when the server last
+    /// stopped. Erasing is idempotent, so they pick up where they left off.
+    pub async fn resume(self: Arc<Self>) -> Result<(), mongodb::error::Error> {
+        let unfinished: Vec<ErasureJob> = self
+            .jobs
+            .find(doc! { "status": { "$in": ["pending", "running"] } }, None)
+            .await?
+            .try_collect()
+            .await?;
+        for job in unfinished {
+            self.clone().spawn(job);
+        }
+        Ok(())
+    }
+
+    fn spawn(self: Arc<Self>, job: ErasureJob) {
+        tokio::task::spawn(async move {
+            let _running = self.running.lock().await;
+            let result = self.run(&job).await;
+            if let Err(e) = &result {
+                log::error!("Erasure job {} failed: {}", job.id, e);
+                let failed = self
+                    .jobs
+                    .update_one(
+                        doc! { "id": &job.id },
+                        doc! { "$set": {
+                            "status": bson::to_bson(&ErasureStatus::Failed).unwrap(),
+                            "finished_at": bson::to_bson(&Utc::now()).unwrap(),
+                            "error": e.to_string(),
+                        } },
+                        None,
+                    )
+                    .await;
+                if let Err(e) = failed {
+                    log::error!("Could not mark erasure job {} as failed: {}", job.id, e);
+                }
+            }
+
+            if let Some(audit_log) = &self.audit_log {
+                let outcome = if result.is_ok() { "success" } else { "failure" };
+                if let Err(e) = audit_log
+                    .record(
+                        job.requested_by.clone(),
+                        "erase",
+                        job.scope.target(),
+                        outcome,
+                        None,
+                    )
+                    .await
+                {
+                    log::error!(
+                        "Could not write audit record for erasure job {}: {}",
+                        job.id,
+                        e
+                    );
+                }
+            }
+        });
+    }
+
+    async fn run(&self, job: &ErasureJob) -> Result<(), ErasureError> {
+        let paths = self.paths_in(&job.scope).await?;
+        self.jobs
+            .update_one(
+                doc! { "id": &job.id },
+                doc! { "$set": {
+                    "status": bson::to_bson(&ErasureStatus::Running).unwrap(),
+                    "progress.paths_found": (job.progress.paths_erased + paths.len() as u64) as i64,
+                } },
+                None,
+            )
+            .await?;
+
+        let mut held = vec![];
+        let mut erased = HashSet::new();
+        let mut references = HashSet::new();
+        for path in paths {
+            // Nothing under a legal hold may be deleted, not even to honour an erasure
+            if self.policies.is_held(&path).await? {
+                held.push(path);
+                continue;
+            }
+            let counts = self
+                .erase_path(&path, job.scope.written_by(), &mut references)
+                .await?;
+            self.record(&job.id, &path, &counts, false).await?;
+            erased.insert(path);
+        }
+
+        for key in self
+            .unshared_keys(references, &erased, job.scope.written_by())
+            .await?
+        {
+            if self.policies.is_held(&key).await? {
+                held.push(key);
+                continue;
+            }
+            let counts = self
+                .erase_path(&key, job.scope.written_by(), &mut HashSet::new())
+                .await?;
+            self.record(&job.id, &key, &counts, true).await?;
+        }
+
+        self.complete(job, held).await
+    }
+
+    /// Paths that may hold something in the scope of a job, in order.
+    async fn paths_in(&self, scope: &ErasureScope) -> Result<BTreeSet<String>, ErasureError> {
+        let mut paths = BTreeSet::new();
+        match scope {
+            // Trashed entries keep their version, so the versions cover the trash too
+            ErasureScope::Identity { identity } => {
+                paths.extend(self.versions.paths_written_by(identity).await?);
+            }
+            ErasureScope::Prefix { prefix } => {
+                paths.extend(self.index.paths_under(prefix).await?);
+                paths.extend(self.versions.paths_under(prefix).await?);
+                paths.extend(self.trash.paths_under(prefix).await?);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Erases what is at a path, or only what the given identity wrote there,
+    /// collecting the paths the erased entries refer to.
+    async fn erase_path(
+        &self,
+        path: &str,
+        written_by: Option<&str>,
+        references: &mut HashSet<String>,
+    ) -> Result<Erased, ErasureError> {
+        let mut erased = Erased::default();
+
+        // An identity only takes the entry at a path with it if it wrote the current value
+        let takes_entry = match written_by {
+            None => true,
+            Some(identity) => self
+                .versions
+                .current(path)
+                .await?
+                .is_some_and(|current| current.created_by.as_deref() == Some(identity)),
+        };
+        if takes_entry {
+            if let Some(entry) = self.index.get(path).await? {
+                collect_references(&entry, references);
+                if self.index.delete(path).await? {
+                    erased.entries += 1;
+                }
+            }
+            self.expiries.clear(path).await?;
+        }
+        // Entries written before versions were kept have their blob under their own path
+        if written_by.is_none() && self.blobs.delete(path).await.map_err(ErasureError::Blob)? {
+            erased.blobs += 1;
+        }
+
+        let versions = self.versions.all_of(path, written_by).await?;
+        let numbers: HashSet<i64> = versions.iter().map(|version| version.version).collect();
+        // Trash items go before the versions they hold, so none is left pointing at a missing blob
+        let trashed = match written_by {
+            None => self.trash.erase(path, None).await?,
+            Some(_) => self.trash.erase(path, Some(&numbers)).await?,
+        };
+        for entry in &trashed {
+            collect_references(entry, references);
+            erased.trash_items += 1;
+        }
+        for version in &versions {
+            collect_version_references(version, references)?;
+            self.versions.erase(version).await?;
+            erased.versions += 1;
+            if version.blob_path.is_some() {
+                erased.blobs += 1;
+            }
+        }
+        Ok(erased)
+    }
+
+    /// Picks the keys kept in the store out of the paths erased entries
+    /// were sealed with, leaving out those that an entry or version outside
+    /// the erasure still uses. Erasing an identity only takes the keys it
+    /// wrote.
+    async fn unshared_keys(
+        &self,
+        references: HashSet<String>,
+        erased: &HashSet<String>,
+        written_by: Option<&str>,
+    ) -> Result<BTreeSet<String>, ErasureError> {
+        let mut keys = BTreeSet::new();
+        for path in references {
+            if erased.contains(&path)
+                || history::is_blob_path(&path)
+                || !self.index.exists(&path).await?
+            {
+                continue;
+            }
+            let owned = match written_by {
+                None => true,
+                Some(identity) => self
+                    .versions
+                    .current(&path)
+                    .await?
+                    .is_some_and(|current| current.created_by.as_deref() == Some(identity)),
+            };
+            if owned {
+                keys.insert(path);
+            }
+        }
+        if keys.is_empty() {
+            return Ok(keys);
+        }
+
+        let mut entries = self.index.documents().await?;
+        while let Some(entry) = entries.try_next().await? {
+            let mut used = HashSet::new();
+            collect_references(&entry, &mut used);
+            if !entry.get_str("path").is_ok_and(|path| keys.contains(path)) {
+                keys.retain(|key| !used.contains(key));
+            }
+        }
+        let mut versions = self.versions.everything().await?;
+        while let Some(version) = versions.try_next().await? {
+            if !keys.contains(&version.path) {
+                let mut used = HashSet::new();
+                collect_version_references(&version, &mut used)?;
+                keys.retain(|key| !used.contains(key));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Adds what erasing a path deleted to the progress of a job.
+    async fn record(
+        &self,
+        id: &str,
+        path: &str,
+        erased: &Erased,
+        key: bool,
+    ) -> Result<(), mongodb::error::Error> {
+        self.jobs
+            .update_one(
+                doc! { "id": id },
+                doc! {
+                    "$inc": {
+                        "progress.paths_erased": 1_i64,
+                        "progress.entries_erased": erased.entries,
+                        "progress.versions_erased": erased.versions,
+                        "progress.blobs_erased": erased.blobs,
+                        "progress.trash_items_erased": erased.trash_items,
+                        "progress.keys_erased": key as i64,
+                    },
+                },
+                None,
+            )
+            .await?;
+        // A resumed job may erase a path it had already recorded
+        self.erased_paths
+            .update_one(
+                doc! { "job_id": id, "path": path },
+                doc! { "$setOnInsert": { "job_id": id, "path": path } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Signs the receipt of a job whose paths have all been erased.
+    async fn complete(
+        &self,
+        job: &ErasureJob,
+        held_paths: Vec<String>,
+    ) -> Result<(), ErasureError> {
+        let progress = self
+            .get(&job.id)
+            .await?
+            .map(|recorded| recorded.progress)
+            .unwrap_or_default();
+
+        // The paths are read back in order and hashed as they come
+        let mut erased_paths = self
+            .erased_paths
+            .find(
+                doc! { "job_id": &job.id },
+                FindOptions::builder().sort(doc! { "path": 1 }).build(),
+            )
+            .await?;
+        let mut digest = digest::Context::new(&digest::SHA256);
+        let mut first = true;
+        while let Some(erased) = erased_paths.try_next().await? {
+            if !first {
+                digest.update(b"\n");
+            }
+            digest.update(erased.path.as_bytes());
+            first = false;
+        }
+
+        let receipt = ErasureReceipt {
+            job_id: job.id.clone(),
+            scope: job.scope.clone(),
+            requested_by: job.requested_by.clone(),
+            requested_at: job.requested_at,
+            completed_at: Utc::now(),
+            progress,
+            held_paths,
+            erased_paths_digest: hex::encode(digest.finish()),
+        };
+        let signature = self.key.sign(&serde_json::to_vec(&receipt).unwrap());
+        let signed = SignedReceipt {
+            public_key: hex::encode(self.key.public_key().as_ref()),
+            signature: hex::encode(signature),
+            receipt,
+        };
+
+        self.jobs
+            .update_one(
+                doc! { "id": &job.id },
+                doc! { "$set": {
+                    "status": bson::to_bson(&ErasureStatus::Completed).unwrap(),
+                    "finished_at": bson::to_bson(&signed.receipt.completed_at).unwrap(),
+                    "held_paths": signed.receipt.held_paths.clone(),
+                    "receipt": bson::to_bson(&signed).unwrap(),
+                } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+}
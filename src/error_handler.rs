@@ -1,36 +1,265 @@
-use crate::routes::error::{BadRequestRejection, NotFoundRejection};
+use crate::{
+    ca::CaError,
+    logging,
+    routes::error::{
+        AuditErrorRejection, BadRequestRejection, CaErrorRejection, ConflictRejection,
+        CryptoErrorRejection, DatabaseErrorRejection, DeniedRejection, ForbiddenRejection,
+        HistoryErrorRejection, MetricsErrorRejection, NotFoundRejection, PolicyErrorRejection,
+        PolicyViolationRejection, TrashErrorRejection, UnauthorizedRejection, X509ErrorRejection,
+    },
+};
 use serde::Serialize;
-use std::convert::Infallible;
+use std::{convert::Infallible, error::Error, fmt};
 use warp::http::StatusCode;
-use warp::{Rejection, Reply};
+use warp::{
+    body::BodyDeserializeError,
+    ext::MissingExtension,
+    reject::{
+        InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader,
+        PayloadTooLarge, UnsupportedMediaType,
+    },
+    Rejection, Reply,
+};
+
+/// Every kind of error the API returns. The code of each is part of the API
+/// and must not change once released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    BadRequest,
+    InvalidQuery,
+    InvalidHeader,
+    InvalidBody,
+    InvalidClientCertificate,
+    InvalidCsr,
+    Unauthenticated,
+    Forbidden,
+    PolicyViolation,
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    LengthRequired,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    UnprocessableEntity,
+    StorageUnavailable,
+    AuditUnavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::InvalidQuery => "invalid_query",
+            ErrorCode::InvalidHeader => "invalid_header",
+            ErrorCode::InvalidBody => "invalid_body",
+            ErrorCode::InvalidClientCertificate => "invalid_client_certificate",
+            ErrorCode::InvalidCsr => "invalid_csr",
+            ErrorCode::Unauthenticated => "unauthenticated",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::PolicyViolation => "policy_violation",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::MethodNotAllowed => "method_not_allowed",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::LengthRequired => "length_required",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::UnsupportedMediaType => "unsupported_media_type",
+            ErrorCode::UnprocessableEntity => "unprocessable_entity",
+            ErrorCode::StorageUnavailable => "storage_unavailable",
+            ErrorCode::AuditUnavailable => "audit_unavailable",
+            ErrorCode::Internal => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest
+            | ErrorCode::InvalidQuery
+            | ErrorCode::InvalidHeader
+            | ErrorCode::InvalidBody
+            | ErrorCode::InvalidClientCertificate
+            | ErrorCode::InvalidCsr => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden | ErrorCode::PolicyViolation => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::UnprocessableEntity => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::StorageUnavailable | ErrorCode::AuditUnavailable => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "The request is invalid",
+            ErrorCode::InvalidQuery => "The query string is invalid",
+            ErrorCode::InvalidHeader => "A request header is missing or invalid",
+            ErrorCode::InvalidBody => "The request body is not valid JSON",
+            ErrorCode::InvalidClientCertificate => "The client certificate could not be parsed",
+            ErrorCode::InvalidCsr => "The certificate signing request is invalid",
+            ErrorCode::Unauthenticated => "The request carries no caller identity",
+            ErrorCode::Forbidden => "The caller is not allowed to perform this request",
+            ErrorCode::PolicyViolation => "The request violates the store's policy",
+            ErrorCode::NotFound => "Nothing exists at the requested path",
+            ErrorCode::MethodNotAllowed => "The method is not allowed on this path",
+            ErrorCode::Conflict => "The request conflicts with the current state of the store",
+            ErrorCode::LengthRequired => "The request must include a content length",
+            ErrorCode::PayloadTooLarge => "The request body is too large",
+            ErrorCode::UnsupportedMediaType => "The request body must be JSON",
+            ErrorCode::UnprocessableEntity => {
+                "The request body is valid JSON but does not describe a valid request"
+            }
+            ErrorCode::StorageUnavailable => "A storage backend could not complete the request",
+            ErrorCode::AuditUnavailable => "The request could not be recorded in the audit log",
+            ErrorCode::Internal => "An internal error occurred",
+        }
+    }
+}
 
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 struct ErrorMessage {
     code: u16,
+    error: &'static str,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
-// This function receives a `Rejection` and tries to return a custom
-// value, otherwise simply passes the rejection along.
-pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    let code;
-    let message;
-    if err.find::<NotFoundRejection>().is_some() {
-        code = StatusCode::NOT_FOUND;
-        message = "NOT FOUND";
+/// Why a single item of a batch request failed, in the terms of the error catalog.
+#[derive(Debug, Serialize)]
+pub struct ItemError {
+    code: u16,
+    error: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl ItemError {
+    pub fn from_rejection(err: &Rejection) -> Self {
+        let (error_code, detail) = classify(err);
+        ItemError {
+            code: error_code.status().as_u16(),
+            error: error_code.code(),
+            message: error_code.message().to_owned(),
+            detail,
+        }
+    }
+}
+
+/// Tells apart malformed JSON from well-formed JSON of the wrong shape.
+fn classify_body_error(e: &BodyDeserializeError) -> ErrorCode {
+    match e
+        .source()
+        .and_then(|source| source.downcast_ref::<serde_json::Error>())
+    {
+        Some(e) if e.is_data() => ErrorCode::UnprocessableEntity,
+        _ => ErrorCode::InvalidBody,
+    }
+}
+
+/// Backend errors can name hosts, collections and buckets, so they are only
+/// logged, where the request ID ties them to the response.
+fn storage_unavailable(e: &impl fmt::Display) -> (ErrorCode, Option<String>) {
+    log::error!("A storage backend failed: {}", e);
+    (ErrorCode::StorageUnavailable, None)
+}
+
+/// Finds the catalog entry for a rejection, along with any detail worth
+/// passing back to the caller.
+fn classify(err: &Rejection) -> (ErrorCode, Option<String>) {
+    if err.is_not_found() || err.find::<NotFoundRejection>().is_some() {
+        (ErrorCode::NotFound, None)
     } else if err.find::<BadRequestRejection>().is_some() {
-        code = StatusCode::BAD_REQUEST;
-        message = "BAD REQUEST";
+        (ErrorCode::BadRequest, None)
+    } else if let Some(DeniedRejection { identity, .. }) = err.find::<DeniedRejection>() {
+        match identity {
+            Some(_) => (ErrorCode::Forbidden, None),
+            None => (ErrorCode::Unauthenticated, None),
+        }
+    } else if err.find::<UnauthorizedRejection>().is_some() {
+        (ErrorCode::Unauthenticated, None)
+    } else if err.find::<ForbiddenRejection>().is_some() {
+        (ErrorCode::Forbidden, None)
+    } else if let Some(ConflictRejection { reason }) = err.find::<ConflictRejection>() {
+        (ErrorCode::Conflict, Some(reason.clone()))
+    } else if let Some(PolicyViolationRejection { reason }) = err.find::<PolicyViolationRejection>()
+    {
+        (ErrorCode::PolicyViolation, Some(reason.clone()))
+    } else if let Some(CaErrorRejection(e)) = err.find::<CaErrorRejection>() {
+        match e {
+            CaError::InvalidCsr { .. } | CaError::InvalidCsrSignature => {
+                (ErrorCode::InvalidCsr, Some(e.to_string()))
+            }
+            CaError::PolicyViolation { .. } => (ErrorCode::PolicyViolation, Some(e.to_string())),
+            CaError::Signing { .. } => (ErrorCode::Internal, None),
+        }
+    } else if let Some(X509ErrorRejection(e)) = err.find::<X509ErrorRejection>() {
+        (ErrorCode::InvalidClientCertificate, Some(e.to_string()))
+    } else if let Some(CryptoErrorRejection(e)) = err.find::<CryptoErrorRejection>() {
+        storage_unavailable(e)
+    } else if let Some(HistoryErrorRejection(e)) = err.find::<HistoryErrorRejection>() {
+        storage_unavailable(e)
+    } else if let Some(DatabaseErrorRejection(e)) = err.find::<DatabaseErrorRejection>() {
+        storage_unavailable(e)
+    } else if let Some(PolicyErrorRejection(e)) = err.find::<PolicyErrorRejection>() {
+        storage_unavailable(e)
+    } else if let Some(TrashErrorRejection(e)) = err.find::<TrashErrorRejection>() {
+        storage_unavailable(e)
+    } else if err.find::<AuditErrorRejection>().is_some() {
+        (ErrorCode::AuditUnavailable, None)
+    } else if let Some(MetricsErrorRejection(e)) = err.find::<MetricsErrorRejection>() {
+        log::error!("Metrics could not be rendered: {}", e);
+        (ErrorCode::Internal, None)
+    } else if let Some(e) = err.find::<BodyDeserializeError>() {
+        (classify_body_error(e), Some(e.to_string()))
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        (ErrorCode::InvalidQuery, Some(e.to_string()))
+    } else if let Some(e) = err.find::<MissingHeader>() {
+        (ErrorCode::InvalidHeader, Some(e.to_string()))
+    } else if let Some(e) = err.find::<InvalidHeader>() {
+        (ErrorCode::InvalidHeader, Some(e.to_string()))
+    } else if err.find::<LengthRequired>().is_some() {
+        (ErrorCode::LengthRequired, None)
+    } else if err.find::<PayloadTooLarge>().is_some() {
+        (ErrorCode::PayloadTooLarge, None)
+    } else if err.find::<UnsupportedMediaType>().is_some() {
+        (ErrorCode::UnsupportedMediaType, None)
+    } else if err.find::<MethodNotAllowed>().is_some() {
+        (ErrorCode::MethodNotAllowed, None)
+    } else if let Some(e) = err.find::<MissingExtension>() {
+        // Extensions are put in place by the server, so this is a bug rather
+        // than a problem with the request
+        log::error!("Request is missing an extension: {}", e);
+        (ErrorCode::Internal, None)
     } else {
-        code = StatusCode::INTERNAL_SERVER_ERROR;
-        message = "INTERNAL SERVER ERROR";
+        log::error!("Unhandled rejection: {:?}", err);
+        (ErrorCode::Internal, None)
     }
+}
+
+// This function receives a `Rejection` and turns it into the matching entry
+// of the error catalog.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (error_code, detail) = classify(&err);
+    let status = error_code.status();
 
     let json = warp::reply::json(&ErrorMessage {
-        code: code.as_u16(),
-        message: message.into(),
+        code: status.as_u16(),
+        error: error_code.code(),
+        message: error_code.message().to_owned(),
+        detail,
+        request_id: logging::current_request_id(),
     });
 
-    Ok(warp::reply::with_status(json, code))
+    Ok(warp::reply::with_status(json, status))
 }
@@ -0,0 +1,303 @@
+//! Expiry of entries written with a time-to-live. An entry is hidden from
+//! readers as soon as it expires, and a background sweeper later deletes the
+//! entry along with its versions and their blobs.
+
+use crate::{
+    backends,
+    blobs::BlobBucket,
+    history::{HistoryError, VersionStore},
+    index::EntryIndex,
+    metrics,
+    policy::{PolicyError, PolicyStore},
+};
+use bson::doc;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    options::{FindOptions, IndexOptions, ReplaceOptions},
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, sync::Arc, time::Duration};
+
+/// Collection the expiry of each path is kept in.
+const EXPIRIES_COLLECTION: &str = "redact_store_expiries";
+
+/// Expired paths purged per query made by the sweeper.
+const SWEEP_BATCH_SIZE: i64 = 100;
+
+#[derive(Debug)]
+pub enum ExpiryError {
+    /// The expiries or entries collection could not be read or written
+    Database(mongodb::error::Error),
+    /// The versions of an expired path could not be purged
+    History(HistoryError),
+    /// The blob of an expired entry could not be deleted
+    Blob(cloud_storage::Error),
+}
+
+impl fmt::Display for ExpiryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpiryError::Database(e) => write!(f, "Expiries are unavailable: {}", e),
+            ExpiryError::History(e) => write!(f, "{}", e),
+            ExpiryError::Blob(e) => write!(f, "Could not delete expired blob: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ExpiryError {}
+
+impl From<mongodb::error::Error> for ExpiryError {
+    fn from(e: mongodb::error::Error) -> Self {
+        ExpiryError::Database(e)
+    }
+}
+
+/// When the value written to a path stops being readable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Expiry {
+    path: String,
+    expires_at: bson::DateTime,
+    /// Version of the path that was written with this expiry
+    version: i64,
+}
+
+pub struct ExpiryStore {
+    expiries: Collection<Expiry>,
+    index: Arc<EntryIndex>,
+    versions: Arc<VersionStore>,
+    blobs: Arc<BlobBucket>,
+    policies: Arc<PolicyStore>,
+}
+
+impl ExpiryStore {
+    pub async fn new(
+        database: &Database,
+        index: Arc<EntryIndex>,
+        versions: Arc<VersionStore>,
+        blobs: Arc<BlobBucket>,
+        policies: Arc<PolicyStore>,
+    ) -> Result<Self, mongodb::error::Error> {
+        let expiries = database.collection::<Expiry>(EXPIRIES_COLLECTION);
+        expiries
+            .create_indexes(
+                vec![
+                    IndexModel::builder()
+                        .keys(doc! { "path": 1 })
+                        .options(IndexOptions::builder().unique(true).build())
+                        .build(),
+                    IndexModel::builder()
+                        .keys(doc! { "expires_at": 1, "path": 1 })
+                        .build(),
+                ],
+                None,
+            )
+            .await?;
+
+        Ok(ExpiryStore {
+            expiries,
+            index,
+            versions,
+            blobs,
+            policies,
+        })
+    }
+
+    /// Sets the expiry of a path after a version of it was written, or
+    /// clears it when the version was written without one.
+    pub async fn set(
+        &self,
+        path: &str,
+        version: i64,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<(), mongodb::error::Error> {
+        match expires_at {
+            Some(expires_at) => {
+                self.expiries
+                    .replace_one(
+                        doc! { "path": path },
+                        Expiry {
+                            path: path.to_owned(),
+                            expires_at: backends::to_bson(expires_at),
+                            version,
+                        },
+                        ReplaceOptions::builder().upsert(true).build(),
+                    )
+                    .await?;
+            }
+            None => self.clear(path).await?,
+        }
+        Ok(())
+    }
+
+    /// When the entry at a path expires, if it does.
+    pub async fn expires_at(
+        &self,
+        path: &str,
+    ) -> Result<Option<DateTime<Utc>>, mongodb::error::Error> {
+        Ok(self
+            .expiries
+            .find_one(doc! { "path": path }, None)
+            .await?
+            .map(|expiry| backends::to_chrono(expiry.expires_at)))
+    }
+
+    /// Removes the expiry of a path.
+    pub async fn clear(&self, path: &str) -> Result<(), mongodb::error::Error> {
+        self.expiries
+            .delete_one(doc! { "path": path }, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the entry at a path has expired.
+    pub async fn is_expired(&self, path: &str) -> Result<bool, mongodb::error::Error> {
+        let expired = self
+            .expiries
+            .find_one(
+                doc! { "path": path, "expires_at": { "$lte": bson::DateTime::now() } },
+                None,
+            )
+            .await?;
+        Ok(expired.is_some())
+    }
+
+    /// Picks out the paths that have expired from a set of paths.
+    pub async fn expired_among(
+        &self,
+        paths: &[String],
+    ) -> Result<HashSet<String>, mongodb::error::Error> {
+        if paths.is_empty() {
+            return Ok(HashSet::new());
+        }
+        self.expiries
+            .find(
+                doc! { "path": { "$in": paths }, "expires_at": { "$lte": bson::DateTime::now() } },
+                None,
+            )
+            .await?
+            .map_ok(|expiry| expiry.path)
+            .try_collect()
+            .await
+    }
+
+    /// Deletes an expired entry, its versions up to the expired one and
+    /// their blobs, then forgets the expiry. Returns false without deleting
+    /// anything if the path is under a legal hold or a retention rule still
+    /// keeps the expired version.
+    async fn purge(&self, expiry: &Expiry) -> Result<bool, ExpiryError> {
+        // A version that is gone is treated as new, so retention rules keep protecting it
+        let created_at = self
+            .versions
+            .get(&expiry.path, expiry.version)
+            .await
+            .map_err(ExpiryError::History)?
+            .map(|version| backends::to_chrono(version.created_at))
+            .unwrap_or_else(Utc::now);
+        match self.policies.check_delete(&expiry.path, created_at).await {
+            Ok(()) => {}
+            Err(PolicyError::Violation { .. }) => return Ok(false),
+            Err(PolicyError::Database(e)) => return Err(ExpiryError::Database(e)),
+            Err(PolicyError::History(e)) => return Err(ExpiryError::History(e)),
+        }
+
+        // A newer version is being written, so the entry at the path is no longer the expired one
+        let superseded = self
+            .versions
+            .latest(&expiry.path)
+            .await
+            .map_err(ExpiryError::History)?
+            .is_some_and(|latest| latest > expiry.version);
+        if !superseded {
+            self.index.delete(&expiry.path).await?;
+            // Entries written before versions were kept have their blob under their own path
+            self.blobs
+                .delete(&expiry.path)
+                .await
+                .map_err(ExpiryError::Blob)?;
+        }
+        self.versions
+            .purge(&expiry.path, expiry.version)
+            .await
+            .map_err(ExpiryError::History)?;
+
+        // Unless the path was written again with a new expiry in the meantime
+        self.expiries
+            .delete_one(
+                doc! { "path": &expiry.path, "version": expiry.version },
+                None,
+            )
+            .await?;
+        Ok(true)
+    }
+
+    /// Purges every entry that has expired, returning how many were purged.
+    /// Paths that fail to purge, are on hold or are still kept by a retention
+    /// rule are left for the next sweep.
+    async fn sweep(&self) -> Result<u64, mongodb::error::Error> {
+        let now = bson::DateTime::now();
+        let mut purged = 0;
+        // Each batch starts past the last expiry seen, so that paths left in
+        // place do not keep the sweeper from reaching the ones after them
+        let mut last: Option<(bson::DateTime, String)> = None;
+        loop {
+            let filter = match &last {
+                None => doc! { "expires_at": { "$lte": now } },
+                Some((expires_at, path)) => doc! {
+                    "expires_at": { "$lte": now },
+                    "$or": [
+                        { "expires_at": { "$gt": *expires_at } },
+                        { "expires_at": *expires_at, "path": { "$gt": path.as_str() } },
+                    ],
+                },
+            };
+            let expired: Vec<Expiry> = self
+                .expiries
+                .find(
+                    filter,
+                    FindOptions::builder()
+                        .sort(doc! { "expires_at": 1, "path": 1 })
+                        .limit(SWEEP_BATCH_SIZE)
+                        .build(),
+                )
+                .await?
+                .try_collect()
+                .await?;
+
+            for expiry in &expired {
+                match self.purge(expiry).await {
+                    Ok(true) => {
+                        metrics::expired_entry_purged();
+                        purged += 1;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        log::error!("Could not purge expired path {}: {}", expiry.path, e);
+                    }
+                }
+            }
+            match expired.last() {
+                Some(expiry) if expired.len() as i64 == SWEEP_BATCH_SIZE => {
+                    last = Some((expiry.expires_at, expiry.path.clone()));
+                }
+                _ => return Ok(purged),
+            }
+        }
+    }
+
+    /// Purges expired entries on the given interval for as long as the server runs.
+    pub fn spawn_sweeper(self: Arc<Self>, interval: Duration) {
+        tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.sweep().await {
+                    Ok(0) => {}
+                    Ok(purged) => log::info!("Purged {} expired entries", purged),
+                    Err(e) => log::error!("Could not purge expired entries: {}", e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,414 @@
+//! History of the values written to each path. Every write reserves the next
+//! version number of its path before touching the storers, and binary data is
+//! uploaded under a blob path unique to that version so that older versions
+//! stay readable after the path is overwritten.
+
+use crate::{backends, blobs::BlobBucket, policy::PolicyStore};
+use bson::doc;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneOptions, FindOptions, IndexOptions},
+    Collection, Cursor, Database, IndexModel,
+};
+use redact_crypto::{Entry, Type};
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::Arc};
+
+/// Collection the versions of every path are kept in.
+const VERSIONS_COLLECTION: &str = "redact_store_versions";
+
+/// Prefix of the blob paths binary versions are uploaded under.
+const VERSION_BLOB_PREFIX: &str = ".redact-store.versions";
+
+/// Error code MongoDB returns when a unique index is violated.
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Debug)]
+pub enum HistoryError {
+    /// The versions collection could not be read or written
+    Database(mongodb::error::Error),
+    /// The blob of a dropped version could not be deleted
+    Blob(cloud_storage::Error),
+    /// An entry could not be converted to or from its stored form
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Database(e) => write!(f, "Version history is unavailable: {}", e),
+            HistoryError::Blob(e) => write!(f, "Could not delete version blob: {}", e),
+            HistoryError::Serialization(e) => write!(f, "Version entry is malformed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<mongodb::error::Error> for HistoryError {
+    fn from(e: mongodb::error::Error) -> Self {
+        HistoryError::Database(e)
+    }
+}
+
+/// One value written to a path.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Version {
+    pub path: String,
+    pub version: i64,
+    pub created_at: bson::DateTime,
+    pub created_by: Option<String>,
+    /// Index entry as written, unset while the write is still in progress
+    pub entry: Option<String>,
+    /// Path the binary data of this version was uploaded to, if it has any
+    pub blob_path: Option<String>,
+    /// Held by a deleted entry in the trash
+    #[serde(default)]
+    pub trashed: bool,
+}
+
+/// What the versions listing shows about each version.
+#[derive(Debug, Serialize)]
+pub struct VersionSummary {
+    pub version: i64,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+}
+
+impl Version {
+    /// The index entry written for this version, none if its write never finished.
+    pub fn entry(&self) -> Result<Option<Entry<Type>>, HistoryError> {
+        self.entry
+            .as_deref()
+            .map(serde_json::from_str)
+            .transpose()
+            .map_err(HistoryError::Serialization)
+    }
+
+    pub fn summary(&self) -> VersionSummary {
+        VersionSummary {
+            version: self.version,
+            created_at: backends::to_chrono(self.created_at),
+            created_by: self.created_by.clone(),
+        }
+    }
+}
+
+/// Converts an entry into the form it is kept in the history in.
+pub fn snapshot<T: Serialize>(entry: &T) -> Result<String, HistoryError> {
+    serde_json::to_string(entry).map_err(HistoryError::Serialization)
+}
+
+/// Blob path the binary data of a version is uploaded to.
+pub fn blob_path(path: &str, version: i64) -> String {
+    format!("{}{}{}.", VERSION_BLOB_PREFIX, path, version)
+}
+
+/// Whether a path is one binary versions are uploaded under, rather than an entry.
+pub fn is_blob_path(path: &str) -> bool {
+    path.starts_with(VERSION_BLOB_PREFIX)
+}
+
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        &*e.kind,
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY
+    )
+}
+
+/// Only versions whose write finished are visible to readers.
+fn committed(path: &str) -> bson::Document {
+    doc! { "path": path, "entry": { "$type": "string" } }
+}
+
+pub struct VersionStore {
+    versions: Collection<Version>,
+    blobs: Arc<BlobBucket>,
+    policies: Arc<PolicyStore>,
+    /// Versions kept per path, including the current one
+    max_versions: u64,
+}
+
+impl VersionStore {
+    pub async fn new(
+        database: &Database,
+        blobs: Arc<BlobBucket>,
+        policies: Arc<PolicyStore>,
+        max_versions: u64,
+    ) -> Result<Self, HistoryError> {
+        let versions = database.collection::<Version>(VERSIONS_COLLECTION);
+        versions
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "path": 1, "version": -1 })
+                    .options(IndexOptions::builder().unique(true).build())
+                    .build(),
+                None,
+            )
+            .await?;
+
+        Ok(VersionStore {
+            versions,
+            blobs,
+            policies,
+            max_versions,
+        })
+    }
+
+    /// Reserves the next version number of a path for a write about to happen.
+    pub async fn reserve(
+        &self,
+        path: &str,
+        created_by: Option<String>,
+        binary: bool,
+    ) -> Result<Version, HistoryError> {
+        loop {
+            let number = self
+                .latest(path)
+                .await?
+                .map(|latest| latest + 1)
+                .unwrap_or(1);
+            let version = Version {
+                path: path.to_owned(),
+                version: number,
+                created_at: bson::DateTime::now(),
+                created_by: created_by.clone(),
+                entry: None,
+                blob_path: binary.then(|| blob_path(path, number)),
+                trashed: false,
+            };
+
+            match self.versions.insert_one(&version, None).await {
+                Ok(_) => return Ok(version),
+                // A concurrent write to the same path took this number first
+                Err(e) if is_duplicate_key(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Records the index entry written for a reserved version, then drops
+    /// the oldest versions of the path beyond the retained count.
+    pub async fn commit(&self, version: &Version, entry: String) -> Result<(), HistoryError> {
+        self.versions
+            .update_one(
+                doc! { "path": &version.path, "version": version.version },
+                doc! { "$set": { "entry": entry } },
+                None,
+            )
+            .await?;
+        self.prune(&version.path).await
+    }
+
+    /// Releases a version whose write failed.
+    pub async fn abandon(&self, version: &Version) -> Result<(), HistoryError> {
+        self.drop_version(version).await
+    }
+
+    async fn drop_version(&self, version: &Version) -> Result<(), HistoryError> {
+        // The blob goes first so that a failure leaves the version to be retried
+        if let Some(blob_path) = &version.blob_path {
+            self.blobs
+                .delete(blob_path)
+                .await
+                .map_err(HistoryError::Blob)?;
+        }
+        self.versions
+            .delete_one(
+                doc! { "path": &version.path, "version": version.version },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a version along with its blob, whether or not its write
+    /// finished or it is held by the trash.
+    pub async fn erase(&self, version: &Version) -> Result<(), HistoryError> {
+        self.drop_version(version).await
+    }
+
+    async fn prune(&self, path: &str) -> Result<(), HistoryError> {
+        // Nothing under a legal hold may be deleted, so old versions pile up until it is released
+        if self.policies.is_held(path).await? {
+            return Ok(());
+        }
+        let expired: Vec<Version> = self
+            .versions
+            .find(
+                doc! { "path": path, "trashed": { "$ne": true } },
+                FindOptions::builder()
+                    .sort(doc! { "version": -1 })
+                    .skip(self.max_versions)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        for version in expired {
+            self.drop_version(&version).await?;
+        }
+        Ok(())
+    }
+
+    /// Number of the newest version of a path, including one still being written.
+    pub async fn latest(&self, path: &str) -> Result<Option<i64>, HistoryError> {
+        Ok(self
+            .versions
+            .find_one(
+                doc! { "path": path },
+                FindOneOptions::builder()
+                    .sort(doc! { "version": -1 })
+                    .build(),
+            )
+            .await?
+            .map(|latest| latest.version))
+    }
+
+    /// Deletes every version of a path up to and including the given one,
+    /// along with their blobs, except those held by the trash.
+    pub async fn purge(&self, path: &str, up_to: i64) -> Result<(), HistoryError> {
+        let purged: Vec<Version> = self
+            .versions
+            .find(
+                doc! { "path": path, "version": { "$lte": up_to }, "trashed": { "$ne": true } },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?;
+        for version in purged {
+            self.drop_version(&version).await?;
+        }
+        Ok(())
+    }
+
+    /// The newest version of a path whose write finished.
+    pub async fn current(&self, path: &str) -> Result<Option<Version>, HistoryError> {
+        Ok(self
+            .versions
+            .find_one(
+                committed(path),
+                FindOneOptions::builder()
+                    .sort(doc! { "version": -1 })
+                    .build(),
+            )
+            .await?)
+    }
+
+    /// Marks a version as held by the trash, which keeps it and its blob from
+    /// being dropped, or releases it again.
+    pub async fn set_trashed(
+        &self,
+        path: &str,
+        version: i64,
+        trashed: bool,
+    ) -> Result<(), HistoryError> {
+        self.versions
+            .update_one(
+                doc! { "path": path, "version": version },
+                doc! { "$set": { "trashed": trashed } },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a single version of a path along with its blob.
+    pub async fn discard(&self, path: &str, version: i64) -> Result<(), HistoryError> {
+        let discarded = self
+            .versions
+            .find_one(doc! { "path": path, "version": version }, None)
+            .await?;
+        match discarded {
+            Some(discarded) => self.drop_version(&discarded).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Reads a specific version of a path.
+    pub async fn get(&self, path: &str, version: i64) -> Result<Option<Version>, HistoryError> {
+        let mut filter = committed(path);
+        filter.insert("version", version);
+        Ok(self.versions.find_one(filter, None).await?)
+    }
+
+    /// Reads the version of a path that was current at the given time.
+    pub async fn as_of(
+        &self,
+        path: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<Version>, HistoryError> {
+        let mut filter = committed(path);
+        filter.insert("created_at", doc! { "$lte": backends::to_bson(at) });
+        Ok(self
+            .versions
+            .find_one(
+                filter,
+                FindOneOptions::builder()
+                    .sort(doc! { "version": -1 })
+                    .build(),
+            )
+            .await?)
+    }
+
+    /// Lists every retained version of a path, newest first.
+    pub async fn list(&self, path: &str) -> Result<Vec<Version>, HistoryError> {
+        Ok(self
+            .versions
+            .find(
+                committed(path),
+                FindOptions::builder().sort(doc! { "version": -1 }).build(),
+            )
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Every version of a path in any state, or only those written by the
+    /// given identity.
+    pub async fn all_of(
+        &self,
+        path: &str,
+        written_by: Option<&str>,
+    ) -> Result<Vec<Version>, HistoryError> {
+        let mut filter = doc! { "path": path };
+        if let Some(written_by) = written_by {
+            filter.insert("created_by", written_by);
+        }
+        Ok(self
+            .versions
+            .find(filter, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Paths the given identity has written a retained version of.
+    pub async fn paths_written_by(&self, identity: &str) -> Result<Vec<String>, HistoryError> {
+        self.distinct_paths(doc! { "created_by": identity }).await
+    }
+
+    /// Paths under a prefix that have retained versions.
+    pub async fn paths_under(&self, prefix: &str) -> Result<Vec<String>, HistoryError> {
+        self.distinct_paths(doc! { "path": { "$regex": backends::prefix_pattern(prefix) } })
+            .await
+    }
+
+    async fn distinct_paths(&self, filter: bson::Document) -> Result<Vec<String>, HistoryError> {
+        Ok(self
+            .versions
+            .distinct("path", filter, None)
+            .await?
+            .into_iter()
+            .filter_map(|path| path.as_str().map(str::to_owned))
+            .collect())
+    }
+
+    /// Iterates over every version of every path.
+    pub async fn everything(&self) -> Result<Cursor<Version>, HistoryError> {
+        Ok(self.versions.find(None, None).await?)
+    }
+}
@@ -0,0 +1,624 @@
+//! Caller identities extracted from either the mTLS peer certificate or the
+//! XFCC header, and the rules used to authorize them.
+
+use crate::{
+    routes::error::{DeniedRejection, ForbiddenRejection, UnauthorizedRejection},
+    settings::ReadRule,
+    xfcc::XfccElement,
+};
+use std::{fmt, str::FromStr, sync::Arc};
+use tokio_rustls::rustls::Certificate;
+use warp::{path::FullPath, Filter, Rejection};
+use x509_parser::{error::X509Error, extensions::GeneralName, nom};
+
+const SPIFFE_SCHEME: &str = "spiffe://";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SpiffeIdError {
+    /// The URI does not use the spiffe:// scheme
+    InvalidScheme,
+    /// The trust domain is empty or contains characters outside of [a-z0-9.-_]
+    InvalidTrustDomain,
+    /// The path contains an empty, relative or otherwise invalid segment
+    InvalidPath,
+}
+
+impl fmt::Display for SpiffeIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpiffeIdError::InvalidScheme => write!(f, "SPIFFE ID must start with spiffe://"),
+            SpiffeIdError::InvalidTrustDomain => write!(f, "SPIFFE ID trust domain is invalid"),
+            SpiffeIdError::InvalidPath => write!(f, "SPIFFE ID path is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for SpiffeIdError {}
+
+/// A SPIFFE ID as defined by the SPIFFE-ID specification, split into its
+/// trust domain and workload path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SpiffeId {
+    /// Trust domain, e.g. `cluster.local`
+    pub trust_domain: String,
+    /// Workload path including its leading slash, or empty for the trust domain itself
+    pub path: String,
+}
+
+impl FromStr for SpiffeId {
+    type Err = SpiffeIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix(SPIFFE_SCHEME)
+            .ok_or(SpiffeIdError::InvalidScheme)?;
+        let (trust_domain, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+
+        if trust_domain.is_empty()
+            || !trust_domain.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || c == '.' || c == '-' || c == '_'
+            })
+        {
+            return Err(SpiffeIdError::InvalidTrustDomain);
+        }
+
+        if !path.is_empty() {
+            let segments_valid = path[1..].split('/').all(|segment| {
+                !segment.is_empty()
+                    && segment != "."
+                    && segment != ".."
+                    && segment
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+            });
+            if !segments_valid {
+                return Err(SpiffeIdError::InvalidPath);
+            }
+        }
+
+        Ok(SpiffeId {
+            trust_domain: trust_domain.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for SpiffeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}{}", SPIFFE_SCHEME, self.trust_domain, self.path)
+    }
+}
+
+/// The identity of a caller as presented by its client certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// First valid SPIFFE ID found in the URI subject alternative names
+    pub spiffe_id: Option<SpiffeId>,
+    /// Common name of the certificate subject
+    pub common_name: Option<String>,
+}
+
+impl Identity {
+    /// Extracts the identity from a DER-encoded X.509 certificate.
+    pub fn from_certificate(cert: &Certificate) -> Result<Self, nom::Err<X509Error>> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0)?;
+
+        let spiffe_id = cert
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .and_then(|san| {
+                san.value.general_names.iter().find_map(|name| match name {
+                    GeneralName::URI(uri) => uri.parse::<SpiffeId>().ok(),
+                    _ => None,
+                })
+            });
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_owned());
+
+        Ok(Identity {
+            spiffe_id,
+            common_name,
+        })
+    }
+
+    /// Extracts the identity from a single XFCC element, preferring the
+    /// forwarded `URI=` and `Subject=` fields over the forwarded certificate.
+    /// Elements that identify no caller at all yield no identity.
+    pub fn from_xfcc(element: &XfccElement) -> Option<Self> {
+        let from_cert = element
+            .cert
+            .as_ref()
+            .and_then(|cert| Identity::from_certificate(cert).ok())
+            .unwrap_or_default();
+
+        let spiffe_id = element
+            .uri
+            .iter()
+            .find_map(|uri| uri.parse::<SpiffeId>().ok())
+            .or(from_cert.spiffe_id);
+        let common_name = element
+            .subject
+            .as_deref()
+            .and_then(common_name_from_dn)
+            .or(from_cert.common_name);
+
+        if spiffe_id.is_none() && common_name.is_none() {
+            return None;
+        }
+        Some(Identity {
+            spiffe_id,
+            common_name,
+        })
+    }
+}
+
+/// Returns the first common name of an RFC 4514 distinguished name, or
+/// nothing if the name cannot be parsed.
+fn common_name_from_dn(dn: &str) -> Option<String> {
+    parse_dn(dn)?
+        .into_iter()
+        .find(|(attribute_type, _)| {
+            attribute_type.eq_ignore_ascii_case("CN") || attribute_type == "2.5.4.3"
+        })
+        .map(|(_, value)| value)
+}
+
+/// Splits an RFC 4514 distinguished name into its attribute type and value
+/// pairs, unescaping the values. Values in the `#`-prefixed hex form are not
+/// supported.
+fn parse_dn(dn: &str) -> Option<Vec<(String, String)>> {
+    let mut attributes = Vec::new();
+    let mut chars = dn.chars();
+    loop {
+        let mut attribute_type = String::new();
+        loop {
+            match chars.next()? {
+                '=' => break,
+                c => attribute_type.push(c),
+            }
+        }
+        let attribute_type = attribute_type.trim();
+        if attribute_type.is_empty() {
+            return None;
+        }
+
+        let mut value = Vec::new();
+        let mut last = true;
+        while let Some(c) = chars.next() {
+            match c {
+                ',' | '+' | ';' => {
+                    last = false;
+                    break;
+                }
+                '#' if value.is_empty() => return None,
+                '\\' => match chars.next()? {
+                    c @ (' ' | '"' | '#' | '+' | ',' | ';' | '<' | '=' | '>' | '\\') => {
+                        value.push(c as u8)
+                    }
+                    high => {
+                        let low = chars.next()?;
+                        let byte = [high.to_digit(16)?, low.to_digit(16)?];
+                        value.push((byte[0] * 16 + byte[1]) as u8);
+                    }
+                },
+                c => value.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        attributes.push((attribute_type.to_owned(), String::from_utf8(value).ok()?));
+
+        if last {
+            return Some(attributes);
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.spiffe_id, &self.common_name) {
+            (Some(spiffe_id), _) => write!(f, "{}", spiffe_id),
+            (None, Some(cn)) => write!(f, "CN={}", cn),
+            (None, None) => write!(f, "anonymous"),
+        }
+    }
+}
+
+/// An identity named in the config, written either as a SPIFFE ID or as
+/// `CN=<name>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdentityMatcher {
+    SpiffeId(SpiffeId),
+    CommonName(String),
+}
+
+impl IdentityMatcher {
+    /// Whether the caller has the named SPIFFE ID or common name.
+    pub fn matches(&self, identity: &Identity) -> bool {
+        match self {
+            IdentityMatcher::SpiffeId(spiffe_id) => identity.spiffe_id.as_ref() == Some(spiffe_id),
+            IdentityMatcher::CommonName(cn) => identity.common_name.as_ref() == Some(cn),
+        }
+    }
+}
+
+impl FromStr for IdentityMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("CN=") {
+            Some("") => Err(format!("'{}' names an empty common name", s)),
+            Some(cn) => Ok(IdentityMatcher::CommonName(cn.to_owned())),
+            None => s
+                .parse()
+                .map(IdentityMatcher::SpiffeId)
+                .map_err(|e| format!("'{}' is neither CN=<name> nor a valid SPIFFE ID: {}", s, e)),
+        }
+    }
+}
+
+/// Rules deciding which caller identities may access the store.
+#[derive(Debug, Clone, Default)]
+pub struct AuthorizationRules {
+    /// SPIFFE trust domains callers must belong to; any caller is accepted if empty
+    pub trust_domains: Vec<String>,
+    /// Identities allowed to use admin endpoints
+    pub admin_identities: Vec<IdentityMatcher>,
+    /// Prefixes whose paths only the listed identities and admins may read
+    pub read_rules: Vec<ReadRule>,
+}
+
+impl AuthorizationRules {
+    pub fn permits(&self, identity: &Identity) -> bool {
+        if self.trust_domains.is_empty() {
+            return true;
+        }
+
+        identity.spiffe_id.as_ref().is_some_and(|spiffe_id| {
+            self.trust_domains
+                .iter()
+                .any(|trust_domain| trust_domain == &spiffe_id.trust_domain)
+        })
+    }
+
+    pub fn permits_admin(&self, identity: &Identity) -> bool {
+        self.permits(identity)
+            && self
+                .admin_identities
+                .iter()
+                .any(|admin_identity| admin_identity.matches(identity))
+    }
+
+    /// Whether a caller may read a path. The rule with the longest prefix
+    /// covering the path applies, and paths no rule covers can be read by any
+    /// permitted caller.
+    pub fn permits_read(&self, identity: &Identity, path: &str) -> bool {
+        let rule = self
+            .read_rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len());
+        match rule {
+            Some(rule) => {
+                rule.identities
+                    .iter()
+                    .any(|rule_identity| rule_identity.matches(identity))
+                    || self.permits_admin(identity)
+            }
+            None => true,
+        }
+    }
+}
+
+/// Rejects reading a path the caller may not read.
+pub fn check_read(
+    rules: &AuthorizationRules,
+    identity: Option<&Identity>,
+    path: &str,
+) -> Result<(), Rejection> {
+    match identity {
+        Some(identity) if rules.permits_read(identity, path) => Ok(()),
+        Some(identity) => {
+            log::warn!("Caller {} is not allowed to read path {}", identity, path);
+            Err(warp::reject::custom(ForbiddenRejection))
+        }
+        None => Err(warp::reject::custom(UnauthorizedRejection)),
+    }
+}
+
+/// Rejects requests that carry no caller identity or whose identity is not
+/// permitted by the given rules. The rejection names the caller and path so
+/// that the denial can be audited once the request is turned away.
+pub fn authorize(
+    rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    authorize_with(rules, AuthorizationRules::permits)
+}
+
+/// Rejects requests that do not come from one of the configured admin
+/// identities. Admin routes apply it right after matching their full path and
+/// method, so that it never turns away requests meant for other routes.
+pub fn authorize_admin(
+    rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    authorize_with(rules, AuthorizationRules::permits_admin)
+}
+
+fn authorize_with(
+    rules: Arc<AuthorizationRules>,
+    check: fn(&AuthorizationRules, &Identity) -> bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<Identity>()
+        .and(warp::path::full())
+        .and(warp::any().map(move || rules.clone()))
+        .and_then(
+            move |identity: Option<Identity>,
+                  path: FullPath,
+                  rules: Arc<AuthorizationRules>| async move {
+                match identity {
+                    Some(identity) if check(&rules, &identity) => Ok(()),
+                    Some(identity) => {
+                        log::warn!("Caller {} is not an authorized identity", identity);
+                        Err(warp::reject::custom(DeniedRejection {
+                            identity: Some(identity.to_string()),
+                            path: path.as_str().to_owned(),
+                        }))
+                    }
+                    None => Err(warp::reject::custom(DeniedRejection {
+                        identity: None,
+                        path: path.as_str().to_owned(),
+                    })),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_spiffe_ids() {
+        assert_eq!(
+            "spiffe://cluster.local/ns/default/sa/store".parse(),
+            Ok(SpiffeId {
+                trust_domain: "cluster.local".to_owned(),
+                path: "/ns/default/sa/store".to_owned(),
+            })
+        );
+        assert_eq!(
+            "spiffe://cluster.local".parse(),
+            Ok(SpiffeId {
+                trust_domain: "cluster.local".to_owned(),
+                path: "".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn spiffe_ids_round_trip_through_display() {
+        let id = "spiffe://example.org/workload-1/v_2.0";
+        assert_eq!(id.parse::<SpiffeId>().unwrap().to_string(), id);
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert_eq!(
+            "https://example.org/a".parse::<SpiffeId>(),
+            Err(SpiffeIdError::InvalidScheme)
+        );
+        assert_eq!(
+            "SPIFFE://example.org/a".parse::<SpiffeId>(),
+            Err(SpiffeIdError::InvalidScheme)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_trust_domains() {
+        for id in [
+            "spiffe://",
+            "spiffe:///a",
+            "spiffe://Example.org/a",
+            "spiffe://exa mple/a",
+        ] {
+            assert_eq!(
+                id.parse::<SpiffeId>(),
+                Err(SpiffeIdError::InvalidTrustDomain),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_paths() {
+        for id in [
+            "spiffe://example.org/",
+            "spiffe://example.org//a",
+            "spiffe://example.org/a/",
+            "spiffe://example.org/./a",
+            "spiffe://example.org/a/..",
+            "spiffe://example.org/a%20b",
+        ] {
+            assert_eq!(
+                id.parse::<SpiffeId>(),
+                Err(SpiffeIdError::InvalidPath),
+                "{}",
+                id
+            );
+        }
+    }
+
+    #[test]
+    fn reads_the_common_name_from_a_distinguished_name() {
+        assert_eq!(
+            common_name_from_dn("OU=store, CN=client-1,O=example"),
+            Some("client-1".to_owned())
+        );
+        assert_eq!(
+            common_name_from_dn("cn=client-1+UID=4"),
+            Some("client-1".to_owned())
+        );
+        assert_eq!(common_name_from_dn("OU=store"), None);
+    }
+
+    #[test]
+    fn unescapes_distinguished_name_values() {
+        assert_eq!(
+            common_name_from_dn(r"OU=a\,CN=fake,CN=Doe\, John\+1"),
+            Some("Doe, John+1".to_owned())
+        );
+        assert_eq!(
+            common_name_from_dn(r"CN=Lu\C4\8Di\C4\87"),
+            Some("Lučić".to_owned())
+        );
+        assert_eq!(common_name_from_dn(r"CN=\#1\=\\"), Some("#1=\\".to_owned()));
+    }
+
+    #[test]
+    fn rejects_malformed_distinguished_names() {
+        for dn in [r"CN=a\", r"CN=a\q", r"CN=\C4", "CN", "=a", "CN=#0403616263"] {
+            assert_eq!(common_name_from_dn(dn), None, "{}", dn);
+        }
+    }
+
+    #[test]
+    fn xfcc_elements_without_a_caller_yield_no_identity() {
+        assert_eq!(Identity::from_xfcc(&XfccElement::default()), None);
+        assert_eq!(
+            Identity::from_xfcc(&XfccElement {
+                by: Some("spiffe://example.org/proxy".to_owned()),
+                uri: vec!["https://example.org".to_owned()],
+                subject: Some("OU=store".to_owned()),
+                ..XfccElement::default()
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn xfcc_elements_yield_their_forwarded_identity() {
+        assert_eq!(
+            Identity::from_xfcc(&XfccElement {
+                subject: Some(r"CN=client\2C 1,OU=store".to_owned()),
+                uri: vec!["spiffe://example.org/client".to_owned()],
+                ..XfccElement::default()
+            }),
+            Some(Identity {
+                spiffe_id: Some("spiffe://example.org/client".parse().unwrap()),
+                common_name: Some("client, 1".to_owned()),
+            })
+        );
+    }
+
+    fn identity(id: &str) -> Identity {
+        Identity {
+            spiffe_id: Some(id.parse().unwrap()),
+            common_name: None,
+        }
+    }
+
+    fn read_rules() -> AuthorizationRules {
+        AuthorizationRules {
+            trust_domains: vec![],
+            admin_identities: vec!["spiffe://example.org/admin".parse().unwrap()],
+            read_rules: vec![
+                ReadRule {
+                    prefix: ".medical.".to_owned(),
+                    identities: vec!["spiffe://example.org/doctor".parse().unwrap()],
+                },
+                ReadRule {
+                    prefix: ".medical.public.".to_owned(),
+                    identities: vec!["spiffe://example.org/anyone".parse().unwrap()],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn paths_without_a_rule_can_be_read_by_anyone() {
+        let rules = read_rules();
+
+        assert!(rules.permits_read(&identity("spiffe://example.org/anyone"), ".notes.a"));
+        assert!(rules.permits_read(&Identity::default(), ".notes.a"));
+    }
+
+    #[test]
+    fn rules_limit_reads_to_their_identities() {
+        let rules = read_rules();
+
+        assert!(rules.permits_read(&identity("spiffe://example.org/doctor"), ".medical.a"));
+        assert!(!rules.permits_read(&identity("spiffe://example.org/anyone"), ".medical.a"));
+    }
+
+    #[test]
+    fn the_longest_covering_prefix_applies() {
+        let rules = read_rules();
+
+        assert!(rules.permits_read(
+            &identity("spiffe://example.org/anyone"),
+            ".medical.public.a"
+        ));
+        assert!(!rules.permits_read(
+            &identity("spiffe://example.org/doctor"),
+            ".medical.public.a"
+        ));
+    }
+
+    #[test]
+    fn admins_can_read_every_path() {
+        let rules = read_rules();
+        let admin = identity("spiffe://example.org/admin");
+
+        assert!(rules.permits_read(&admin, ".medical.a"));
+        assert!(rules.permits_read(&admin, ".medical.public.a"));
+    }
+
+    #[test]
+    fn admins_outside_the_trust_domains_are_refused() {
+        let rules = AuthorizationRules {
+            trust_domains: vec!["cluster.local".to_owned()],
+            ..read_rules()
+        };
+
+        assert!(!rules.permits_read(&identity("spiffe://example.org/admin"), ".medical.a"));
+    }
+
+    #[test]
+    fn identities_are_matched_on_their_parsed_fields() {
+        let by_cn: IdentityMatcher = "CN=admin".parse().unwrap();
+        let by_spiffe_id: IdentityMatcher = "spiffe://example.org/admin".parse().unwrap();
+
+        let both = Identity {
+            spiffe_id: Some("spiffe://example.org/admin".parse().unwrap()),
+            common_name: Some("admin".to_owned()),
+        };
+        assert!(by_cn.matches(&both));
+        assert!(by_spiffe_id.matches(&both));
+
+        // A common name spelled like a SPIFFE ID or a CN= string is only a common name
+        let lookalike = Identity {
+            spiffe_id: None,
+            common_name: Some("spiffe://example.org/admin".to_owned()),
+        };
+        assert!(!by_spiffe_id.matches(&lookalike));
+        assert!(!by_cn.matches(&Identity {
+            spiffe_id: None,
+            common_name: Some("CN=admin".to_owned()),
+        }));
+    }
+
+    #[test]
+    fn rejects_malformed_identity_matchers() {
+        for matcher in ["", "CN=", "admin", "spiffe://Example.org/admin"] {
+            assert!(matcher.parse::<IdentityMatcher>().is_err(), "{}", matcher);
+        }
+    }
+}
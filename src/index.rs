@@ -0,0 +1,78 @@
+//! Direct access to the documents the indexed storer keeps, for the
+//! operations `MongoStorer` does not offer. Each entry is one document in the
+//! `entries` collection, keyed by its path.
+
+use crate::backends;
+use bson::{doc, Document};
+use mongodb::{error::Error, Collection, Cursor, Database};
+
+/// Collection `MongoStorer` keeps entries in.
+const ENTRIES_COLLECTION: &str = "entries";
+
+pub struct EntryIndex {
+    entries: Collection<Document>,
+}
+
+impl EntryIndex {
+    pub fn new(database: &Database) -> Self {
+        EntryIndex {
+            entries: database.collection(ENTRIES_COLLECTION),
+        }
+    }
+
+    /// Whether there is an entry at a path.
+    pub async fn exists(&self, path: &str) -> Result<bool, Error> {
+        Ok(self
+            .entries
+            .find_one(doc! { "path": path }, None)
+            .await?
+            .is_some())
+    }
+
+    /// Reads the document of the entry at a path.
+    pub async fn get(&self, path: &str) -> Result<Option<Document>, Error> {
+        self.entries.find_one(doc! { "path": path }, None).await
+    }
+
+    /// Puts back a document previously deleted from the index.
+    pub async fn put_back(&self, document: Document) -> Result<(), Error> {
+        self.entries.insert_one(document, None).await?;
+        Ok(())
+    }
+
+    /// Puts the entry at a path back to a document read earlier, or deletes
+    /// it if there was none.
+    pub async fn restore(&self, path: &str, document: Option<Document>) -> Result<(), Error> {
+        self.delete(path).await?;
+        if let Some(document) = document {
+            self.put_back(document).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the entry at a path, returning whether there was one.
+    pub async fn delete(&self, path: &str) -> Result<bool, Error> {
+        let result = self.entries.delete_one(doc! { "path": path }, None).await?;
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Paths of every entry under a prefix.
+    pub async fn paths_under(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        Ok(self
+            .entries
+            .distinct(
+                "path",
+                doc! { "path": { "$regex": backends::prefix_pattern(prefix) } },
+                None,
+            )
+            .await?
+            .into_iter()
+            .filter_map(|path| path.as_str().map(str::to_owned))
+            .collect())
+    }
+
+    /// Iterates over the documents of every entry.
+    pub async fn documents(&self) -> Result<Cursor<Document>, Error> {
+        self.entries.find(None, None).await
+    }
+}
@@ -0,0 +1,256 @@
+//! Loading and generation of the PKCS#8 private keys backing the CA and
+//! server TLS identities, and of the keys the store signs its own records with.
+
+use der::{
+    asn1::{Any, OctetString},
+    Document,
+};
+use pkcs8::{PrivateKeyDocument, PrivateKeyInfo};
+use redact_crypto::{
+    key::sodiumoxide::{
+        SodiumOxideEd25519SecretAsymmetricKey, SodiumOxideEd25519SecretAsymmetricKeyBuilder,
+    },
+    Builder, HasAlgorithmIdentifier, HasByteSource,
+};
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use std::{
+    convert::TryInto,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::Path,
+};
+use x509_parser::{oid_registry::OID_SIG_ED25519, prelude::FromDer, x509::SubjectPublicKeyInfo};
+
+const OID_ED25519: &str = "1.3.101.112";
+const OID_EC_PUBLIC_KEY: &str = "1.2.840.10045.2.1";
+const OID_CURVE_P256: &str = "1.2.840.10045.3.1.7";
+const OID_CURVE_P384: &str = "1.3.132.0.34";
+const OID_RSA_ENCRYPTION: &str = "1.2.840.113549.1.1.1";
+
+/// Key algorithms accepted for the CA and server identities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Ed25519,
+    EcdsaP256,
+    EcdsaP384,
+    Rsa,
+}
+
+impl fmt::Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyAlgorithm::Ed25519 => write!(f, "Ed25519"),
+            KeyAlgorithm::EcdsaP256 => write!(f, "ECDSA P-256"),
+            KeyAlgorithm::EcdsaP384 => write!(f, "ECDSA P-384"),
+            KeyAlgorithm::Rsa => write!(f, "RSA"),
+        }
+    }
+}
+
+impl KeyAlgorithm {
+    /// Determines the key algorithm from the AlgorithmIdentifier of a PKCS#8 key.
+    fn detect(private_key_info: &PrivateKeyInfo) -> io::Result<Self> {
+        let algorithm = &private_key_info.algorithm;
+        match algorithm.oid.to_string().as_str() {
+            OID_ED25519 => Ok(KeyAlgorithm::Ed25519),
+            OID_RSA_ENCRYPTION => Ok(KeyAlgorithm::Rsa),
+            OID_EC_PUBLIC_KEY => {
+                let curve = algorithm.parameters_oid().map_err(|e| {
+                    io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("ECDSA key is missing its named curve: {}", e),
+                    )
+                })?;
+                match curve.to_string().as_str() {
+                    OID_CURVE_P256 => Ok(KeyAlgorithm::EcdsaP256),
+                    OID_CURVE_P384 => Ok(KeyAlgorithm::EcdsaP384),
+                    curve => Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("ECDSA curve {} is not supported", curve),
+                    )),
+                }
+            }
+            oid => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Key algorithm {} is not supported", oid),
+            )),
+        }
+    }
+}
+
+fn invalid_data(e: impl fmt::Display) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// A private key backing one of the store's TLS identities.
+pub enum StoreKey {
+    /// Ed25519 key, usable with redact-crypto's own certificate tooling
+    Ed25519 {
+        key: SodiumOxideEd25519SecretAsymmetricKey,
+        pkcs8_der: Vec<u8>,
+    },
+    /// Any other supported key, kept in its PKCS#8 DER form
+    Pkcs8 {
+        algorithm: KeyAlgorithm,
+        pkcs8_der: Vec<u8>,
+    },
+}
+
+impl StoreKey {
+    /// Parses a PEM-encoded PKCS#8 private key of any supported algorithm.
+    pub fn from_pkcs8_pem(pem: &str) -> io::Result<Self> {
+        let pkd = PrivateKeyDocument::from_pem(pem).map_err(invalid_data)?;
+        let private_key_info = pkd.decode();
+        let pkcs8_der = pkd.as_der().to_vec();
+
+        match KeyAlgorithm::detect(&private_key_info)? {
+            KeyAlgorithm::Ed25519 => {
+                let seed_bytes: OctetString =
+                    TryInto::<Any>::try_into(private_key_info.private_key)
+                        .and_then(|any| any.try_into())
+                        .map_err(invalid_data)?;
+                let builder = SodiumOxideEd25519SecretAsymmetricKeyBuilder {};
+                let key = builder
+                    .build(Some(seed_bytes.as_bytes()))
+                    .map_err(invalid_data)?;
+                Ok(StoreKey::Ed25519 { key, pkcs8_der })
+            }
+            algorithm => Ok(StoreKey::Pkcs8 {
+                algorithm,
+                pkcs8_der,
+            }),
+        }
+    }
+
+    /// Generates a new Ed25519 key, returning it along with its PKCS#8 PEM encoding.
+    fn generate() -> io::Result<(Self, String)> {
+        let key = SodiumOxideEd25519SecretAsymmetricKey::new();
+        let key_bs = key.byte_source();
+        let mut key_bytes = vec![0x04, 0x20];
+        key_bytes.extend_from_slice(&key_bs.get().map_err(invalid_data)?[0..32]);
+        let key_pkcs8 = PrivateKeyInfo::new(key.algorithm_identifier(), &key_bytes);
+        let pem = key_pkcs8
+            .to_pem(pkcs8::LineEnding::LF)
+            .map_err(invalid_data)?;
+        let pkcs8_der = key_pkcs8.to_der().map_err(invalid_data)?.as_der().to_vec();
+
+        Ok((StoreKey::Ed25519 { key, pkcs8_der }, (*pem).to_owned()))
+    }
+
+    pub fn algorithm(&self) -> KeyAlgorithm {
+        match self {
+            StoreKey::Ed25519 { .. } => KeyAlgorithm::Ed25519,
+            StoreKey::Pkcs8 { algorithm, .. } => *algorithm,
+        }
+    }
+
+    pub fn pkcs8_der(&self) -> &[u8] {
+        match self {
+            StoreKey::Ed25519 { pkcs8_der, .. } => pkcs8_der,
+            StoreKey::Pkcs8 { pkcs8_der, .. } => pkcs8_der,
+        }
+    }
+
+    /// Converts the key into an rcgen key pair for issuing certificates with
+    /// algorithms that redact-crypto cannot sign with.
+    pub fn to_key_pair(&self) -> io::Result<rcgen::KeyPair> {
+        rcgen::KeyPair::from_der(self.pkcs8_der()).map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Cannot use {} key for signing: {}", self.algorithm(), e),
+            )
+        })
+    }
+}
+
+/// Loads the PKCS#8 key at the given path, generating and writing a new
+/// Ed25519 key there if it does not exist yet.
+pub fn load_or_generate(path: &Path) -> io::Result<StoreKey> {
+    match fs::read_to_string(path) {
+        Ok(pem) => StoreKey::from_pkcs8_pem(&pem).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Cannot load private key from {}: {}", path.display(), e),
+            )
+        }),
+        Err(e) => match e.kind() {
+            ErrorKind::NotFound => {
+                let (key, pem) = StoreKey::generate()?;
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, pem)?;
+                Ok(key)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+/// Loads the Ed25519 key at the given path for signing the store's own
+/// records, generating it if it does not exist. `purpose` names the key in
+/// errors.
+pub fn load_signing_key(path: &Path, purpose: &str) -> io::Result<Ed25519KeyPair> {
+    ed25519_key_pair(&load_or_generate(path)?, path, purpose)
+}
+
+/// Loads the Ed25519 public key that records signed by the store are checked
+/// against. The file at the given path may hold the public key alone or the
+/// PKCS#8 signing key, and unlike `load_signing_key` a missing file is an
+/// error rather than a reason to generate a new key.
+pub fn load_verifying_key(path: &Path, purpose: &str) -> io::Result<UnparsedPublicKey<Vec<u8>>> {
+    let pem = fs::read_to_string(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => io::Error::new(
+            ErrorKind::NotFound,
+            format!("The {} {} does not exist", purpose, path.display()),
+        ),
+        _ => io::Error::new(
+            e.kind(),
+            format!("Cannot read {} {}: {}", purpose, path.display(), e),
+        ),
+    })?;
+    let invalid_key = |reason: String| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Cannot load {} {}: {}", purpose, path.display(), reason),
+        )
+    };
+
+    let document = pem::parse(&pem).map_err(|e| invalid_key(e.to_string()))?;
+    let public_key = if document.tag() == "PUBLIC KEY" {
+        let (_, info) = SubjectPublicKeyInfo::from_der(document.contents())
+            .map_err(|e| invalid_key(e.to_string()))?;
+        if info.algorithm.algorithm != OID_SIG_ED25519 {
+            return Err(invalid_key("the public key is not Ed25519".to_owned()));
+        }
+        info.subject_public_key.data.to_vec()
+    } else {
+        let key = StoreKey::from_pkcs8_pem(&pem).map_err(|e| invalid_key(e.to_string()))?;
+        ed25519_key_pair(&key, path, purpose)?
+            .public_key()
+            .as_ref()
+            .to_vec()
+    };
+
+    Ok(UnparsedPublicKey::new(&signature::ED25519, public_key))
+}
+
+fn ed25519_key_pair(key: &StoreKey, path: &Path, purpose: &str) -> io::Result<Ed25519KeyPair> {
+    if key.algorithm() != KeyAlgorithm::Ed25519 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "The {} {} must be Ed25519, not {}",
+                purpose,
+                path.display(),
+                key.algorithm()
+            ),
+        ));
+    }
+    Ed25519KeyPair::from_pkcs8_maybe_unchecked(key.pkcs8_der()).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Cannot load {} {}: {}", purpose, path.display(), e),
+        )
+    })
+}
@@ -0,0 +1,165 @@
+//! The socket the server accepts connections on, either a TCP port or a Unix
+//! domain socket shared with a sidecar proxy.
+
+use crate::settings::ServerSettings;
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{self, DirBuilder},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    pin::Pin,
+    process,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds the Unix domain socket if one is configured, otherwise the TCP
+    /// port on the configured address.
+    pub async fn bind(settings: &ServerSettings) -> io::Result<Listener> {
+        match &settings.unix_socket {
+            Some(unix_socket) => {
+                let listener = bind_unix(&unix_socket.path, unix_socket.mode)?;
+                Ok(Listener::Unix(listener, unix_socket.path.clone()))
+            }
+            None => {
+                let socket_addr = SocketAddr::new(settings.bind, settings.port);
+                Ok(Listener::Tcp(TcpListener::bind(&socket_addr).await?))
+            }
+        }
+    }
+
+    /// Waits for the next incoming connection.
+    pub async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => Ok(Connection::Tcp(listener.accept().await?.0)),
+            Listener::Unix(listener, _) => Ok(Connection::Unix(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Binds a Unix domain socket inside a staging directory only this process
+/// may enter, sets its permissions and only then moves it to its path, so
+/// that it is never reachable with looser permissions than configured. The
+/// move replaces any socket file left behind by a previous run.
+fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a socket file path", path.display()),
+        )
+    })?;
+    let mut staging_name = OsString::from(".");
+    staging_name.push(file_name);
+    staging_name.push(format!(".{}", process::id()));
+    let staging_dir = path.with_file_name(staging_name);
+
+    DirBuilder::new().mode(0o700).create(&staging_dir)?;
+    let staged_path = staging_dir.join("socket");
+    let bound = UnixListener::bind(&staged_path).and_then(|listener| {
+        fs::set_permissions(&staged_path, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged_path, path)?;
+        Ok(listener)
+    });
+
+    // Whatever happened, the staging directory is no longer needed
+    let _ = fs::remove_file(&staged_path);
+    let _ = fs::remove_dir(&staging_dir);
+    bound
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "unknown TCP address"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection accepted on either kind of listener.
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    /// Returns where the connection came from.
+    pub fn peer_addr(&self) -> io::Result<PeerAddr> {
+        match self {
+            Connection::Tcp(stream) => Ok(PeerAddr::Tcp(stream.peer_addr()?.ip())),
+            Connection::Unix(_) => Ok(PeerAddr::Unix),
+        }
+    }
+}
+
+/// The remote end of an accepted connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerAddr {
+    Tcp(IpAddr),
+    /// Any process able to open the Unix domain socket
+    Unix,
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(ip) => write!(f, "{}", ip),
+            PeerAddr::Unix => write!(f, "unix socket peer"),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
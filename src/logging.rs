@@ -0,0 +1,136 @@
+//! Log output, either as the original free-text lines or as one JSON object
+//! per line, and the per-request context attached to every line logged while
+//! a request is being handled.
+
+use crate::{identity::Identity, routes, settings::LogFormat};
+use hyper::{
+    header::HeaderValue,
+    http::{Request, Response},
+};
+use serde_json::json;
+use std::{future::Future, io::Write, time::Instant};
+use uuid::Uuid;
+
+/// Header carrying the request ID, honoured on requests and set on every response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID accepted from a caller before a new one is generated.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST: RequestContext;
+}
+
+/// Details of the request currently being handled.
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub request_id: String,
+    pub identity: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub operation: &'static str,
+    pub started: Instant,
+}
+
+impl RequestContext {
+    /// Builds the context for a request, reusing the caller's request ID if
+    /// it sent a usable one. Must be called after the identity has been
+    /// inserted into the request extensions.
+    pub fn new<B>(req: &Request<B>) -> Self {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|request_id| request_id.to_str().ok())
+            .map(|request_id| request_id.trim())
+            .filter(|request_id| !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LEN)
+            .map(|request_id| request_id.to_owned())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        RequestContext {
+            request_id,
+            identity: req
+                .extensions()
+                .get::<Identity>()
+                .map(|identity| identity.to_string()),
+            method: req.method().to_string(),
+            path: req.uri().path().to_owned(),
+            operation: routes::operation(req.method(), req.uri().path(), req.uri().query()),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Returns the ID of the request being handled by the current task, if any.
+pub fn current_request_id() -> Option<String> {
+    REQUEST.try_with(|context| context.request_id.clone()).ok()
+}
+
+/// Handles a request with its context available to every log line, then
+/// returns the request ID in the response and logs the outcome.
+pub async fn scope<B, E>(
+    context: RequestContext,
+    response: impl Future<Output = Result<Response<B>, E>>,
+) -> Result<Response<B>, E> {
+    REQUEST
+        .scope(context.clone(), async move {
+            let mut result = response.await;
+            if let Ok(response) = &mut result {
+                if let Ok(request_id) = HeaderValue::from_str(&context.request_id) {
+                    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+                }
+                log::info!(
+                    target: "access",
+                    "{} {} by {} returned {} in {}ms",
+                    context.method,
+                    context.path,
+                    context.identity.as_deref().unwrap_or("anonymous"),
+                    response.status().as_u16(),
+                    context.started.elapsed().as_millis()
+                );
+            }
+            result
+        })
+        .await
+}
+
+/// Installs the logger in the configured format.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::builder();
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let request_id = current_request_id()
+                .map(|request_id| format!(" [{}]", request_id))
+                .unwrap_or_default();
+            writeln!(
+                buf,
+                "{}:{} {} [{}]{} - {}",
+                record.file().unwrap_or("unknown"),
+                record.line().unwrap_or(0),
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"),
+                record.level(),
+                request_id,
+                record.args()
+            )
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let mut line = json!({
+                "timestamp": chrono::Utc::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "file": record.file(),
+                "line": record.line(),
+                "message": record.args().to_string(),
+            });
+            let _ = REQUEST.try_with(|context| {
+                line["request_id"] = json!(context.request_id);
+                line["identity"] = json!(context.identity);
+                line["method"] = json!(context.method);
+                line["path"] = json!(context.path);
+                line["operation"] = json!(context.operation);
+                line["duration_ms"] = json!(context.started.elapsed().as_millis() as u64);
+            });
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+}
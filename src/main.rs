@@ -1,57 +1,69 @@
+mod audit;
+mod backends;
+mod blobs;
 mod bootstrap;
+mod ca;
+mod cli;
+mod erasure;
 mod error_handler;
+mod expiry;
+mod history;
+mod identity;
+mod index;
+mod keys;
+mod listener;
+mod logging;
+mod metrics;
+mod policy;
+mod renewal;
+mod revocation;
 mod routes;
+mod settings;
+mod telemetry;
+mod tls;
+mod trash;
+mod xfcc;
 
 use crate::error_handler::handle_rejection;
-use base64::{engine::general_purpose as b64_general_purpose, Engine};
-use bootstrap::AllowAnyClient;
-use chrono::{prelude::*, Duration};
-use der::asn1::{Any, OctetString};
-use der::Document;
-use pkcs8::{PrivateKeyDocument, PrivateKeyInfo};
-use redact_config::Configurator;
-use redact_crypto::{
-    key::sodiumoxide::{
-        SodiumOxideEd25519SecretAsymmetricKey, SodiumOxideEd25519SecretAsymmetricKeyBuilder,
-    },
-    storage::gcs::GoogleCloudStorer,
-    Builder, HasAlgorithmIdentifier, HasByteSource, PublicAsymmetricKey,
-};
-use redact_crypto::{storage::NonIndexedTypeStorer, HasPublicKey};
-use redact_crypto::{x509::DistinguishedName, MongoStorer, TypeStorer};
+use audit::AuditLog;
+use backends::{BackendStatus, ReadinessProbe};
+use blobs::BlobBucket;
+use chrono::Duration;
+use clap::Parser;
+use cli::{Cli, Command};
+use erasure::ErasureStore;
+use expiry::ExpiryStore;
+use history::VersionStore;
+use identity::AuthorizationRules;
+use index::EntryIndex;
+use listener::Listener;
+use policy::PolicyStore;
+use renewal::{ReloadableCertResolver, ServerCertRenewer};
+use revocation::{CrlStore, RevocationCheckingVerifier};
+use ring::signature::Ed25519KeyPair;
 use serde::Serialize;
-use std::{
-    convert::TryInto,
-    fs::File,
-    io::{self, ErrorKind, Read, Write},
-    net::{IpAddr, SocketAddr},
-    path::Path,
-    str::FromStr,
-    sync::Arc,
-};
-use tokio::net;
-use tokio_rustls::rustls::{Certificate, PrivateKey};
+use settings::{ConfigProblem, Scope, Settings};
+use std::{fmt, net::SocketAddr, path::Path, process, sync::Arc, time};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use trash::TrashStore;
 use warp::Filter;
+use xfcc::TrustedProxy;
+
+/// Exit status used when the config is invalid, as opposed to a runtime failure.
+const INVALID_CONFIG_EXIT_CODE: i32 = 2;
 
 #[derive(Serialize)]
 struct Healthz {}
 
 #[tokio::main]
 async fn main() {
-    // pretty_env_logger::init();
-    env_logger::builder()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{}:{} {} [{}] - {}",
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                record.level(),
-                record.args()
-            )
-        })
-        .init();
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Serve);
+    let scope = match command {
+        Command::InitTls { .. } => Scope::Tls,
+        Command::VerifyAudit => Scope::Audit,
+        Command::CheckConfig | Command::Serve => Scope::Server,
+    };
 
     // Extract config with a REDACT_ env var prefix
     let config_path = if Path::new("/etc/redact-store/config").is_dir() {
@@ -59,326 +71,529 @@ async fn main() {
     } else {
         "./config"
     };
-    let config = redact_config::new(config_path, "APPCFG").unwrap();
-
-    // Determine port to listen on
-    let port = match config.get_int("server.port") {
-        Ok(port) => {
-            if !(1..=65535).contains(&port) {
-                println!(
-                    "listen port value '{}' is not between 1 and 65535, defaulting to 8080",
-                    port
-                );
-                8080
-            } else {
-                port as u16
+    let settings = redact_config::new(config_path, "APPCFG")
+        .map_err(|e| {
+            vec![ConfigProblem::Unreadable {
+                path: config_path.to_owned(),
+                reason: e.to_string(),
+            }]
+        })
+        .and_then(|config| Settings::load(&config, scope));
+    let settings = match settings {
+        Ok(settings) => settings,
+        Err(problems) => {
+            eprintln!("config is invalid:");
+            for problem in problems {
+                eprintln!("  {}", problem);
             }
+            process::exit(INVALID_CONFIG_EXIT_CODE);
         }
-        Err(e) => {
-            match e {
-                // Suppress debug logging if server.port was simply not set
-                redact_config::ConfigError::NotFound(_) => {
-                    println!("listen port not set in config, defaulting to 8080")
+    };
+    logging::init(settings.logging.format);
+
+    match command {
+        Command::InitTls { inspect } => {
+            if !inspect {
+                if let Err(e) = tls::init(&settings.tls) {
+                    exit_invalid_config("tls", format!("could not generate TLS material: {}", e));
                 }
-                _ => println!("{}", e),
             }
-            8080
+            if let Err(e) = tls::inspect(&settings.tls) {
+                eprintln!("could not inspect TLS material: {}", e);
+                process::exit(1);
+            }
         }
-    };
-
-    let generate_crypto_material = config.get_bool("tls.generate").unwrap();
-    if generate_crypto_material {
-        // Make the storer TLS CA key if it doesn't exist
-        let ca_key_path = config.get_str("tls.ca.key.path").unwrap();
-        let ca_key = match File::open(&ca_key_path) {
-            Ok(mut f) => {
-                let mut pem = String::new();
-                f.read_to_string(&mut pem).unwrap();
-                let pkd = PrivateKeyDocument::from_pem(&pem).unwrap();
-                let seed_bytes: OctetString = TryInto::<Any>::try_into(pkd.decode().private_key)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                // TODO(ajpauwels): Add a match on the AlgorithmIdentifier within the PEM file to
-                //                  determine the proper key type; assuming NaCl Ed25519 for now
-                let builder = SodiumOxideEd25519SecretAsymmetricKeyBuilder {};
-                Ok(builder.build(Some(seed_bytes.as_bytes())).unwrap())
+        Command::CheckConfig => {
+            if !check_backends(&settings).await {
+                process::exit(1);
             }
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => {
-                    let ca_key = SodiumOxideEd25519SecretAsymmetricKey::new();
-                    let ca_key_bs = ca_key.byte_source();
-                    let mut ca_key_bytes = vec![0x04, 0x20];
-                    ca_key_bytes.extend_from_slice(&ca_key_bs.get().unwrap()[0..32]);
-                    let ca_key_pkcs8 =
-                        PrivateKeyInfo::new(ca_key.algorithm_identifier(), &ca_key_bytes);
-                    let path = Path::new(&ca_key_path);
-                    let path_parent = path.parent();
-                    if let Some(path) = path_parent {
-                        std::fs::create_dir_all(path).unwrap();
-                    }
-                    let mut pkcs8_file = File::create(&ca_key_path).unwrap();
-                    pkcs8_file
-                        .write_all(
-                            (*ca_key_pkcs8.to_pem(pkcs8::LineEnding::LF).unwrap()).as_bytes(),
-                        )
-                        .unwrap();
-                    Ok(ca_key)
-                }
-                _ => Err(e),
-            },
+            println!("config is valid and all backends are reachable");
         }
-        .unwrap();
-
-        // Make the storer TLS CA cert if it doesn't exist
-        let ca_cert_o = config.get_str("tls.ca.certificate.o").unwrap();
-        let ca_cert_ou = config.get_str("tls.ca.certificate.ou").unwrap();
-        let ca_cert_cn = config.get_str("tls.ca.certificate.cn").unwrap();
-        let ca_cert_dn = DistinguishedName {
-            o: &ca_cert_o,
-            ou: &ca_cert_ou,
-            cn: &ca_cert_cn,
-        };
-        if let Err(e) = File::open(config.get_str("tls.ca.certificate.path").unwrap()) {
-            match e.kind() {
-                ErrorKind::NotFound => {
-                    let not_before = Utc::now();
-                    let not_after = not_before
-                        + Duration::days(config.get_int("tls.ca.certificate.expires_in").unwrap());
-                    let tls_cert = redact_crypto::cert::setup_cert::<_, PublicAsymmetricKey>(
-                        &ca_key,
-                        None,
-                        &ca_cert_dn,
-                        None,
-                        not_before,
-                        not_after,
-                        true,
-                        None,
-                    )
-                    .unwrap();
-                    let path_str = &config.get_str("tls.ca.certificate.path").unwrap();
-                    let path = Path::new(path_str);
-                    let path_parent = path.parent();
-                    if let Some(path) = path_parent {
-                        std::fs::create_dir_all(path).unwrap();
-                    }
-                    let mut tls_cert_vec: Vec<u8> = vec![];
-                    let mut tls_cert_file = File::create(path).unwrap();
-                    tls_cert_vec
-                        .write_all(b"-----BEGIN CERTIFICATE-----\n")
-                        .unwrap();
-                    b64_general_purpose::STANDARD
-                        .encode(tls_cert)
-                        .as_bytes()
-                        .chunks(64)
-                        .for_each(|chunk| {
-                            tls_cert_vec.write_all(chunk).unwrap();
-                            tls_cert_vec.write_all(b"\n").unwrap();
-                        });
-                    tls_cert_vec
-                        .write_all(b"-----END CERTIFICATE-----\n")
-                        .unwrap();
-                    tls_cert_file.write_all(&tls_cert_vec).unwrap();
-                }
-                _ => Err(e).unwrap(),
+        Command::VerifyAudit => {
+            if !verify_audit(&settings) {
+                process::exit(1);
+            }
+        }
+        Command::Serve => {
+            if let Err(e) = telemetry::init(&settings.tracing) {
+                eprintln!("could not set up trace exporting: {}", e);
+                process::exit(1);
             }
+            serve(&settings).await;
+            telemetry::shutdown();
         }
+    }
+}
 
-        // Make the storer client TLS key if it doesn't exist
-        let storer_key_path = config.get_str("tls.server.key.path").unwrap();
-        let storer_key = match File::open(&storer_key_path) {
-            Ok(mut f) => {
-                let mut pem = String::new();
-                f.read_to_string(&mut pem).unwrap();
-                let pkd = PrivateKeyDocument::from_pem(&pem).unwrap();
-                let seed_bytes: OctetString = TryInto::<Any>::try_into(pkd.decode().private_key)
-                    .unwrap()
-                    .try_into()
-                    .unwrap();
-                // TODO(ajpauwels): Add a match on the AlgorithmIdentifier within the PEM file to
-                //                  determine the proper key type; assuming NaCl Ed25519 for now
-                let builder = SodiumOxideEd25519SecretAsymmetricKeyBuilder {};
-                Ok(builder.build(Some(seed_bytes.as_bytes())).unwrap())
+/// Checks that both storage backends respond, printing each one that does not.
+async fn check_backends(settings: &Settings) -> bool {
+    let (mongo_storer, google_storer) = backends::storers(settings);
+    let probe = ReadinessProbe::new(
+        mongo_storer,
+        google_storer,
+        time::Duration::from_secs(10),
+        time::Duration::ZERO,
+    );
+    let readiness = probe.check_now().await;
+    for (name, status) in &readiness.backends {
+        match status {
+            BackendStatus::Ok => (),
+            BackendStatus::Unreachable { error } => {
+                eprintln!("{}: backend is unreachable: {}", name, error)
+            }
+            BackendStatus::Timeout { after_ms } => {
+                eprintln!("{}: backend did not respond within {}ms", name, after_ms)
             }
-            Err(e) => match e.kind() {
-                ErrorKind::NotFound => {
-                    let storer_key = SodiumOxideEd25519SecretAsymmetricKey::new();
-                    let storer_tls_key_bs = storer_key.byte_source();
-                    let mut storer_tls_key_bytes = vec![0x04, 0x20];
-                    storer_tls_key_bytes
-                        .extend_from_slice(&storer_tls_key_bs.get().unwrap()[0..32]);
-                    let storer_tls_key_pkcs8 = PrivateKeyInfo::new(
-                        storer_key.algorithm_identifier(),
-                        &storer_tls_key_bytes,
-                    );
-                    let path = Path::new(&storer_key_path);
-                    let path_parent = path.parent();
-                    if let Some(path) = path_parent {
-                        std::fs::create_dir_all(path).unwrap();
-                    }
-                    let mut pkcs8_file = File::create(&storer_key_path).unwrap();
-                    pkcs8_file
-                        .write_all(
-                            (*storer_tls_key_pkcs8.to_pem(pkcs8::LineEnding::LF).unwrap())
-                                .as_bytes(),
-                        )
-                        .unwrap();
-                    Ok(storer_key)
-                }
-                _ => Err(e),
-            },
         }
-        .unwrap();
-
-        // Make the storer TLS cert if it doesn't exist
-        if let Err(e) = File::open(config.get_str("tls.server.certificate.path").unwrap()) {
-            match e.kind() {
-                ErrorKind::NotFound => {
-                    let storer_cert_o = config.get_str("tls.server.certificate.o").unwrap();
-                    let storer_cert_ou = config.get_str("tls.server.certificate.ou").unwrap();
-                    let storer_cert_cn = config.get_str("tls.server.certificate.cn").unwrap();
-                    let storer_cert_dn = DistinguishedName {
-                        o: &storer_cert_o,
-                        ou: &storer_cert_ou,
-                        cn: &storer_cert_cn,
-                    };
-                    let not_before = Utc::now();
-                    let not_after = not_before
-                        + Duration::days(
-                            config.get_int("tls.server.certificate.expires_in").unwrap(),
-                        );
-                    let tls_cert = redact_crypto::cert::setup_cert(
-                        &ca_key,
-                        Some(&storer_key.public_key().unwrap()),
-                        &ca_cert_dn,
-                        Some(&storer_cert_dn),
-                        not_before,
-                        not_after,
-                        false,
-                        Some(&["localhost"]),
-                    )
-                    .unwrap();
-                    let path_str = &config.get_str("tls.server.certificate.path").unwrap();
-                    let path = Path::new(path_str);
-                    let path_parent = path.parent();
-                    if let Some(path) = path_parent {
-                        std::fs::create_dir_all(path).unwrap();
-                    }
-                    let mut tls_cert_vec: Vec<u8> = vec![];
-                    let mut tls_cert_file = File::create(path_str).unwrap();
-                    tls_cert_vec
-                        .write_all(b"-----BEGIN CERTIFICATE-----\n")
-                        .unwrap();
-                    b64_general_purpose::STANDARD
-                        .encode(tls_cert)
-                        .as_bytes()
-                        .chunks(64)
-                        .for_each(|chunk| {
-                            tls_cert_vec.write_all(chunk).unwrap();
-                            tls_cert_vec.write_all(b"\n").unwrap();
-                        });
-                    tls_cert_vec
-                        .write_all(b"-----END CERTIFICATE-----\n")
-                        .unwrap();
-                    tls_cert_file.write_all(&tls_cert_vec).unwrap();
-                }
-                _ => Err(e).unwrap(),
+    }
+
+    readiness.ready
+}
+
+/// Checks the audit log chain, printing a summary or where it breaks.
+fn verify_audit(settings: &Settings) -> bool {
+    let audit_settings = match &settings.audit {
+        Some(audit_settings) => audit_settings,
+        None => {
+            eprintln!("audit logging is not enabled, set audit.path");
+            return false;
+        }
+    };
+
+    // The key is only read here, since a newly generated one could never
+    // verify the checkpoints already in the log
+    let verification = keys::load_verifying_key(&audit_settings.key_path, "audit log signing key")
+        .and_then(|public_key| audit::verify(&audit_settings.path, &public_key));
+    match verification {
+        Ok(verification) => {
+            println!(
+                "{} records, {} checkpoints, signed through record {}",
+                verification.records, verification.checkpoints, verification.last_signed_seq
+            );
+            if let Some(e) = &verification.error {
+                eprintln!("audit log is invalid at {}", e);
             }
+            verification.valid
+        }
+        Err(e) => {
+            eprintln!("could not verify the audit log: {}", e);
+            false
         }
     }
+}
+
+/// Exits as for any other invalid config when a file or address the config
+/// points at cannot be used.
+fn exit_invalid_config(key: &str, reason: impl fmt::Display) -> ! {
+    eprintln!("config is invalid:");
+    eprintln!(
+        "  {}",
+        ConfigProblem::Invalid {
+            key: key.to_owned(),
+            reason: reason.to_string(),
+        }
+    );
+    process::exit(INVALID_CONFIG_EXIT_CODE);
+}
 
-    // Extract handle to the database
-    let db_url = config.get_str("db.url").unwrap();
-    let db_name = config.get_str("db.name").unwrap();
-    let mongo_storer = Arc::new(MongoStorer::new(&db_url, &db_name));
+/// Loads a key the store signs its own records with, exiting as for any
+/// other invalid config if it cannot be used.
+fn signing_key(key: &str, path: &Path, purpose: &str) -> Ed25519KeyPair {
+    keys::load_signing_key(path, purpose).unwrap_or_else(|e| exit_invalid_config(key, e))
+}
 
-    let storage_bucket_name = config.get_str("google.storage.bucket.name").unwrap();
-    let google_storer = Arc::new(TypeStorer::NonIndexed(NonIndexedTypeStorer::GoogleCloud(
-        GoogleCloudStorer::new(storage_bucket_name),
-    )));
+async fn serve(settings: &Settings) {
+    let generated_tls = if settings.tls.generate {
+        Some(tls::init(&settings.tls).unwrap_or_else(|e| {
+            exit_invalid_config("tls", format!("could not generate TLS material: {}", e))
+        }))
+    } else {
+        None
+    };
+    let certificate_authority = generated_tls
+        .as_ref()
+        .map(|generated_tls| generated_tls.ca.clone());
 
-    // Build out routes
-    let health_get = warp::path!("healthz")
-        .and(warp::get())
-        .map(|| warp::reply::json(&Healthz {}));
-    let get = warp::get().and(routes::get::get(mongo_storer.clone()));
-    let post = warp::post().and(routes::post::create(
+    // Extract handle to the database and blob storage
+    let (mongo_storer, google_storer) = backends::storers(settings);
+
+    // Version history, expiries, the trash and policies live in their own
+    // collections, next to the indexed storer's
+    let database = backends::database(settings).await.unwrap();
+    let entry_index = Arc::new(EntryIndex::new(&database));
+    let blob_bucket = Arc::new(BlobBucket::new(settings.google.storage_bucket_name.clone()));
+    let policies = Arc::new(PolicyStore::new(&database).await.unwrap());
+    let versions = Arc::new(
+        VersionStore::new(
+            &database,
+            blob_bucket.clone(),
+            policies.clone(),
+            settings.history.max_versions,
+        )
+        .await
+        .unwrap(),
+    );
+    let expiries = Arc::new(
+        ExpiryStore::new(
+            &database,
+            entry_index.clone(),
+            versions.clone(),
+            blob_bucket.clone(),
+            policies.clone(),
+        )
+        .await
+        .unwrap(),
+    );
+    expiries
+        .clone()
+        .spawn_sweeper(time::Duration::from_secs(settings.expiry.sweep_interval));
+    let trash = Arc::new(
+        TrashStore::new(
+            &database,
+            entry_index.clone(),
+            versions.clone(),
+            expiries.clone(),
+            blob_bucket.clone(),
+            policies.clone(),
+            Duration::days(settings.trash.retention),
+        )
+        .await
+        .unwrap(),
+    );
+    trash
+        .clone()
+        .spawn_sweeper(time::Duration::from_secs(settings.trash.sweep_interval));
+
+    // Only accept callers from the listed SPIFFE trust domains, if any are set
+    let authorization_rules = Arc::new(AuthorizationRules {
+        trust_domains: settings.auth.trust_domains.clone(),
+        admin_identities: settings.auth.admin_identities.clone(),
+        read_rules: settings.auth.read_rules.clone(),
+    });
+
+    // Record reads and writes in the signed audit log, if enabled
+    let audit_log = settings.audit.as_ref().map(|audit_settings| {
+        let key = signing_key(
+            "audit.key.path",
+            &audit_settings.key_path,
+            "audit log signing key",
+        );
+        let audit_log = Arc::new(AuditLog::open(audit_settings.path.clone(), key).unwrap());
+        audit_log
+            .clone()
+            .spawn_signer(time::Duration::from_secs(audit_settings.sign_interval));
+        audit_log
+    });
+
+    // Erasure jobs sign their receipts, so they are only available with a key to sign them with
+    let erasures = match &settings.erasure {
+        Some(erasure_settings) => {
+            let key = signing_key(
+                "erasure.key.path",
+                &erasure_settings.key_path,
+                "erasure receipt signing key",
+            );
+            let erasures = Arc::new(
+                ErasureStore::new(
+                    &database,
+                    entry_index.clone(),
+                    versions.clone(),
+                    expiries.clone(),
+                    trash.clone(),
+                    blob_bucket.clone(),
+                    policies.clone(),
+                    audit_log.clone(),
+                    key,
+                )
+                .await
+                .unwrap(),
+            );
+            erasures.clone().resume().await.unwrap();
+            Some(erasures)
+        }
+        None => None,
+    };
+
+    // Readiness pings the backends, while health only reports that the process is up
+    let readiness_probe = Arc::new(ReadinessProbe::new(
         mongo_storer.clone(),
         google_storer.clone(),
+        time::Duration::from_secs(settings.readiness.timeout),
+        time::Duration::from_secs(settings.readiness.cache_ttl),
+    ));
+
+    // Build out routes. Operational routes live under the reserved /-/ prefix so
+    // that they never shadow a data path
+    let health_get = warp::path!("-" / "healthz")
+        .and(warp::get())
+        .map(|| warp::reply::json(&Healthz {}));
+    let ready_get = routes::readyz::readyz(readiness_probe);
+
+    // Metrics are served on the main listener unless a separate admin port is set
+    let metrics_on_main_listener = settings.metrics.admin_port.is_none();
+    let metrics_get = warp::any()
+        .and_then(move || async move {
+            if metrics_on_main_listener {
+                Ok::<_, warp::Rejection>(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+        .and(warp::path("-"))
+        .and(routes::metrics::metrics());
+    if let Some(admin_port) = settings.metrics.admin_port {
+        let admin_addr = SocketAddr::new(settings.metrics.admin_bind, admin_port);
+        println!("serving metrics over plain HTTP on {}", admin_addr);
+        tokio::task::spawn(
+            warp::serve(
+                routes::metrics::metrics()
+                    .recover(handle_rejection)
+                    .with(warp::log::custom(metrics::record_request)),
+            )
+            .run(admin_addr),
+        );
+    }
+
+    let get = warp::get()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::get::get(
+            mongo_storer.clone(),
+            versions.clone(),
+            expiries.clone(),
+            authorization_rules.clone(),
+            settings.listing.max_page_size,
+            audit_log.clone(),
+        ));
+    let batch_get = warp::post()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::batch::get(
+            mongo_storer.clone(),
+            expiries.clone(),
+            authorization_rules.clone(),
+            audit_log.clone(),
+        ));
+    let versions_get = warp::get()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::versions::list(
+            versions.clone(),
+            expiries.clone(),
+            authorization_rules.clone(),
+            audit_log.clone(),
+        ));
+    let post = warp::post()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::post::create(
+            mongo_storer.clone(),
+            google_storer.clone(),
+            versions.clone(),
+            expiries.clone(),
+            entry_index.clone(),
+            policies.clone(),
+            audit_log.clone(),
+        ));
+    let batch_post = warp::post()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::batch::create(
+            mongo_storer.clone(),
+            google_storer.clone(),
+            versions.clone(),
+            expiries.clone(),
+            entry_index.clone(),
+            policies.clone(),
+            audit_log.clone(),
+        ));
+
+    let delete = warp::delete()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::trash::delete(
+            trash.clone(),
+            expiries.clone(),
+            entry_index.clone(),
+            versions.clone(),
+            policies.clone(),
+            audit_log.clone(),
+        ));
+    let trash_get = warp::get()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::trash::list(
+            trash.clone(),
+            authorization_rules.clone(),
+            audit_log.clone(),
+        ));
+    let restore = warp::post()
+        .and(identity::authorize(authorization_rules.clone()))
+        .and(routes::trash::restore(
+            trash.clone(),
+            authorization_rules.clone(),
+            audit_log.clone(),
+        ));
+
+    // Retention rules and legal holds are managed by admins
+    let policies_get = warp::get().and(routes::policies::list(
+        policies.clone(),
+        authorization_rules.clone(),
+    ));
+    let policies_post = warp::post().and(
+        routes::policies::add_rule(
+            policies.clone(),
+            audit_log.clone(),
+            authorization_rules.clone(),
+        )
+        .or(routes::policies::place_hold(
+            policies.clone(),
+            audit_log.clone(),
+            authorization_rules.clone(),
+        )),
+    );
+    let policies_delete = warp::delete().and(
+        routes::policies::remove_rule(
+            policies.clone(),
+            audit_log.clone(),
+            authorization_rules.clone(),
+        )
+        .or(routes::policies::release_hold(
+            policies.clone(),
+            audit_log.clone(),
+            authorization_rules.clone(),
+        )),
+    );
+
+    // Erasing everything tied to an identity or prefix is left to admins
+    let erasures_post = warp::post().and(routes::erasures::start(
+        erasures.clone(),
+        audit_log.clone(),
+        authorization_rules.clone(),
+    ));
+    let erasures_get = warp::get().and(
+        routes::erasures::receipt(erasures.clone(), authorization_rules.clone())
+            .or(routes::erasures::get(erasures, authorization_rules.clone())),
+    );
+
+    let issue_certificate = warp::post().and(routes::certificates::issue(
+        certificate_authority,
+        authorization_rules.clone(),
+    ));
+    let denial_audit_log = audit_log.clone();
+    let verify_audit_log = warp::get().and(routes::audit::verify(
+        audit_log,
+        authorization_rules.clone(),
     ));
 
     let total_route = health_get
+        .or(ready_get)
+        .or(metrics_get)
+        .or(issue_certificate)
+        .or(verify_audit_log)
+        .or(policies_get)
+        .or(policies_post)
+        .or(policies_delete)
+        .or(erasures_post)
+        .or(erasures_get)
+        .or(versions_get)
+        .or(trash_get)
         .or(get)
         .or(post)
-        .with(warp::log("routes"))
-        .recover(handle_rejection);
+        .or(batch_post)
+        .or(batch_get)
+        .or(restore)
+        .or(delete)
+        .recover(move |rejection: warp::Rejection| {
+            let audit_log = denial_audit_log.clone();
+            async move {
+                routes::audit_denial(audit_log.as_ref(), &rejection).await;
+                handle_rejection(rejection).await
+            }
+        })
+        .with(warp::log::custom(metrics::record_request));
+
+    // Load certificate revocation lists, which apply to both mTLS and XFCC callers
+    let crl_store = Arc::new(
+        CrlStore::new(
+            settings.tls.crl.paths.clone(),
+            settings.tls.client.trust_bundle_path.clone(),
+        )
+        .unwrap_or_else(|e| exit_invalid_config("tls.crl.paths", e)),
+    );
+    crl_store
+        .clone()
+        .spawn_reloader(time::Duration::from_secs(settings.tls.crl.reload_interval));
 
-    let addr = IpAddr::from_str("::0").unwrap();
-    let socket_addr: SocketAddr = (addr, port).into();
-    let listener = net::TcpListener::bind(&socket_addr).await.unwrap();
-    println!("starting server listening on [::0]:{}", port);
+    // Build the TLS configuration once, the server certificate is swapped in
+    // place by the renewal task whenever it gets re-issued
+    let tls_config = if !settings.tls.use_xfcc_header {
+        let cert_resolver = Arc::new(ReloadableCertResolver::new(
+            renewal::load_certified_key(
+                &settings.tls.server.certificate.path,
+                &settings.tls.server.key_path,
+            )
+            .unwrap_or_else(|e| exit_invalid_config("tls.server.certificate.path", e)),
+        ));
+
+        if let Some(generated_tls) = generated_tls {
+            ServerCertRenewer {
+                ca: generated_tls.ca,
+                key: generated_tls.server_key,
+                spec: tls::server_certificate_spec(&settings.tls),
+                renew_before: Duration::days(settings.tls.server.renew_before),
+                resolver: cert_resolver.clone(),
+            }
+            .spawn(time::Duration::from_secs(
+                settings.tls.server.renew_check_interval,
+            ));
+        }
+
+        // Client certificates must chain up to the trust bundle, which
+        // defaults to the store's own CA
+        let client_roots = bootstrap::client_roots(&settings.tls.client.trust_bundle_path)
+            .unwrap_or_else(|e| exit_invalid_config("tls.client.trust_bundle.path", e));
+        let mut server_config = tokio_rustls::rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(RevocationCheckingVerifier::new(
+                AllowAnyAuthenticatedClient::new(client_roots),
+                crl_store.clone(),
+            )))
+            .with_cert_resolver(cert_resolver);
+        server_config.alpn_protocols = bootstrap::ALPN_PROTOCOLS
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect();
+        Some(Arc::new(server_config))
+    } else {
+        None
+    };
+
+    let xfcc_trusted_proxies: Arc<[TrustedProxy]> =
+        settings.tls.xfcc_trusted_proxies.clone().into();
+    let listener = Listener::bind(&settings.server).await.unwrap_or_else(|e| {
+        match settings.server.unix_socket {
+            Some(_) => exit_invalid_config("server.unix_socket.path", e),
+            None => exit_invalid_config("server.bind", e),
+        }
+    });
+    let http = bootstrap::http(&settings.server.http);
+    println!("starting server listening on {}", listener);
     loop {
-        let serve_with_xfcc = config.get_bool("tls.use_xfcc_header").unwrap();
-        if !serve_with_xfcc {
-            // Build TLS configuration.
-            let tls_config = {
-                let cert_path = config.get_str("tls.server.certificate.path").unwrap();
-
-                // Select a certificate to use.
-                let file = File::open(&cert_path).unwrap();
-                let mut reader = io::BufReader::new(file);
-                let certs = rustls_pemfile::certs(&mut reader)
-                    .map_err(|_err| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("Cannot load certificate from {}", &cert_path),
-                        )
-                    })
-                    .unwrap()
-                    .into_iter()
-                    .map(Certificate)
-                    .collect();
-                let storer_key_path = config.get_str("tls.server.key.path").unwrap();
-                let file = File::open(&storer_key_path).unwrap();
-                let mut reader = io::BufReader::new(file);
-                let keys = rustls_pemfile::pkcs8_private_keys(&mut reader)
-                    .map_err(|_err| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("Cannot load private key from {}", &storer_key_path),
-                        )
-                    })
-                    .unwrap();
-                let key = PrivateKey(
-                    keys.into_iter()
-                        .next()
-                        .ok_or_else(|| {
-                            io::Error::new(
-                                io::ErrorKind::Other,
-                                format!(
-                                    "No keys found in the private key file {}",
-                                    storer_key_path
-                                ),
-                            )
-                        })
-                        .unwrap(),
-                );
-                //let mut server_config =
-                //tokio_rustls::rustls::ServerConfig::new(Arc::new(AllowAnyClient {}));
-                let server_config = tokio_rustls::rustls::ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_client_cert_verifier(Arc::new(AllowAnyClient {}))
-                    .with_single_cert(certs, key)
-                    .unwrap();
-                Arc::new(server_config)
-            };
-
-            if let Err(e) =
-                bootstrap::serve_mtls(&listener, tls_config.clone(), total_route.clone()).await
+        if let Some(tls_config) = &tls_config {
+            if let Err(e) = bootstrap::serve_mtls(
+                &listener,
+                tls_config.clone(),
+                http.clone(),
+                total_route.clone(),
+            )
+            .await
             {
                 eprintln!("Problem accepting TLS connection: {}", e);
             }
-        } else if let Err(e) = bootstrap::serve_xfcc(&listener, total_route.clone()).await {
+        } else if let Err(e) = bootstrap::serve_xfcc(
+            &listener,
+            http.clone(),
+            total_route.clone(),
+            crl_store.clone(),
+            xfcc_trusted_proxies.clone(),
+        )
+        .await
+        {
             eprintln!(
                 "Problem accepting non-TLS connection using XFCC header: {}",
                 e
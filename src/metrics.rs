@@ -0,0 +1,176 @@
+//! Prometheus metrics describing request handling, storage backend latency
+//! and connection-level failures.
+
+use crate::routes;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::{future::Future, time::Instant};
+use tracing::Instrument;
+use warp::log::Info;
+
+/// Backend label for the indexed (Mongo) storer.
+pub const INDEXED: &str = "indexed";
+/// Backend label for the blob (Google Cloud Storage) storer.
+pub const BLOB: &str = "blob";
+/// Backend label for the version history kept alongside the indexed storer.
+pub const HISTORY: &str = "history";
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+static HTTP_REQUESTS: Lazy<prometheus::Result<IntCounterVec>> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("redact_store_http_requests_total", "HTTP requests handled"),
+        &["route", "status"],
+    ))
+});
+
+static HTTP_REQUEST_DURATION: Lazy<prometheus::Result<HistogramVec>> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "redact_store_http_request_duration_seconds",
+            "Time taken to handle HTTP requests",
+        ),
+        &["route", "status"],
+    ))
+});
+
+static STORER_OPERATION_DURATION: Lazy<prometheus::Result<HistogramVec>> = Lazy::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "redact_store_storer_operation_duration_seconds",
+            "Time taken by storage backend operations",
+        ),
+        &["backend", "operation", "outcome"],
+    ))
+});
+
+static TLS_HANDSHAKE_FAILURES: Lazy<prometheus::Result<IntCounter>> = Lazy::new(|| {
+    register(IntCounter::new(
+        "redact_store_tls_handshake_failures_total",
+        "TLS handshakes that failed, including rejected client certificates",
+    ))
+});
+
+static XFCC_PARSE_FAILURES: Lazy<prometheus::Result<IntCounter>> = Lazy::new(|| {
+    register(IntCounter::new(
+        "redact_store_xfcc_parse_failures_total",
+        "XFCC headers that could not be parsed",
+    ))
+});
+
+static UPLOAD_BYTES: Lazy<prometheus::Result<IntCounterVec>> = Lazy::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "redact_store_upload_bytes_total",
+            "Bytes received in entry uploads",
+        ),
+        &["backend"],
+    ))
+});
+
+static EXPIRED_ENTRIES_PURGED: Lazy<prometheus::Result<IntCounter>> = Lazy::new(|| {
+    register(IntCounter::new(
+        "redact_store_expired_entries_purged_total",
+        "Expired entries deleted by the sweeper",
+    ))
+});
+
+// A metric that fails to register is left out rather than taking the process
+// down; recording to it does nothing and rendering reports the error.
+fn register<M: prometheus::core::Collector + Clone + 'static>(
+    metric: prometheus::Result<M>,
+) -> prometheus::Result<M> {
+    let metric = metric?;
+    REGISTRY.register(Box::new(metric.clone()))?;
+    Ok(metric)
+}
+
+/// Records the count and latency of a handled request, used with `warp::log::custom`.
+pub fn record_request(info: Info) {
+    // The query string is not available here, so lists are counted as gets
+    let route = routes::operation(info.method(), info.path(), None);
+    let status = info.status().as_u16().to_string();
+    if let Ok(requests) = &*HTTP_REQUESTS {
+        requests.with_label_values(&[route, &status]).inc();
+    }
+    if let Ok(duration) = &*HTTP_REQUEST_DURATION {
+        duration
+            .with_label_values(&[route, &status])
+            .observe(info.elapsed().as_secs_f64());
+    }
+}
+
+/// Runs a storage backend operation inside its own tracing span, recording
+/// how long it took and whether it succeeded.
+pub async fn time_storer<T, E>(
+    backend: &str,
+    operation: &str,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let span = tracing::info_span!(
+        "storer",
+        otel.name = %format!("{} {}", backend, operation),
+        backend,
+        operation,
+    );
+    let start = Instant::now();
+    let result = future.instrument(span).await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    if let Ok(duration) = &*STORER_OPERATION_DURATION {
+        duration
+            .with_label_values(&[backend, operation, outcome])
+            .observe(start.elapsed().as_secs_f64());
+    }
+    result
+}
+
+pub fn tls_handshake_failed() {
+    if let Ok(counter) = &*TLS_HANDSHAKE_FAILURES {
+        counter.inc();
+    }
+}
+
+pub fn xfcc_parse_failed() {
+    if let Ok(counter) = &*XFCC_PARSE_FAILURES {
+        counter.inc();
+    }
+}
+
+pub fn expired_entry_purged() {
+    if let Ok(counter) = &*EXPIRED_ENTRIES_PURGED {
+        counter.inc();
+    }
+}
+
+pub fn uploaded(backend: &str, bytes: u64) {
+    if let Ok(upload_bytes) = &*UPLOAD_BYTES {
+        upload_bytes.with_label_values(&[backend]).inc_by(bytes);
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format, failing if
+/// any of them could not be registered or the output could not be encoded.
+pub fn render() -> prometheus::Result<String> {
+    // Touch every metric so that each one is exposed before it is first recorded
+    let registrations = [
+        HTTP_REQUESTS.as_ref().err(),
+        HTTP_REQUEST_DURATION.as_ref().err(),
+        STORER_OPERATION_DURATION.as_ref().err(),
+        TLS_HANDSHAKE_FAILURES.as_ref().err(),
+        XFCC_PARSE_FAILURES.as_ref().err(),
+        UPLOAD_BYTES.as_ref().err(),
+        EXPIRED_ENTRIES_PURGED.as_ref().err(),
+    ];
+    if let Some(e) = registrations.into_iter().flatten().next() {
+        return Err(prometheus::Error::Msg(format!(
+            "a metric could not be registered: {}",
+            e
+        )));
+    }
+
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer)?;
+    String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+}
@@ -0,0 +1,304 @@
+//! Retention rules and legal holds declared by admins on path prefixes.
+//! Retention rules either keep entries from being deleted or overwritten
+//! until they reach a minimum age, or cap how long entries may live. Legal
+//! holds block every delete and overwrite under their prefix, including those
+//! made by the expiry and trash sweepers, until they are released.
+
+use crate::{
+    backends,
+    history::{HistoryError, VersionStore},
+    index::EntryIndex,
+};
+use bson::doc;
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::{options::IndexOptions, Collection, Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+
+/// Collection retention rules are kept in.
+const RETENTION_RULES_COLLECTION: &str = "redact_store_retention_rules";
+
+/// Collection legal holds are kept in.
+const LEGAL_HOLDS_COLLECTION: &str = "redact_store_legal_holds";
+
+#[derive(Debug)]
+pub enum PolicyError {
+    /// The policies or entries could not be read or written
+    Database(mongodb::error::Error),
+    /// The age of the entry at a path could not be read
+    History(HistoryError),
+    /// The request is not allowed by a retention rule or legal hold
+    Violation { reason: String },
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::Database(e) => write!(f, "Policies are unavailable: {}", e),
+            PolicyError::History(e) => write!(f, "{}", e),
+            PolicyError::Violation { reason } => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+impl From<mongodb::error::Error> for PolicyError {
+    fn from(e: mongodb::error::Error) -> Self {
+        PolicyError::Database(e)
+    }
+}
+
+impl From<HistoryError> for PolicyError {
+    fn from(e: HistoryError) -> Self {
+        PolicyError::History(e)
+    }
+}
+
+/// What a retention rule requires of entries under its prefix.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Retention {
+    /// Entries may not be deleted or overwritten until they are this many days old
+    KeepFor { days: i64 },
+    /// Entries expire at the latest this many days after being written
+    DeleteAfter { days: i64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionRule {
+    pub id: String,
+    pub prefix: String,
+    #[serde(flatten)]
+    pub retention: Retention,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegalHold {
+    pub id: String,
+    pub prefix: String,
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+}
+
+fn violation(reason: String) -> PolicyError {
+    PolicyError::Violation { reason }
+}
+
+pub struct PolicyStore {
+    rules: Collection<RetentionRule>,
+    holds: Collection<LegalHold>,
+}
+
+impl PolicyStore {
+    pub async fn new(database: &Database) -> Result<Self, mongodb::error::Error> {
+        let unique_id = || {
+            IndexModel::builder()
+                .keys(doc! { "id": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build()
+        };
+        let rules = database.collection::<RetentionRule>(RETENTION_RULES_COLLECTION);
+        rules.create_index(unique_id(), None).await?;
+        let holds = database.collection::<LegalHold>(LEGAL_HOLDS_COLLECTION);
+        holds.create_index(unique_id(), None).await?;
+
+        Ok(PolicyStore { rules, holds })
+    }
+
+    pub async fn rules(&self) -> Result<Vec<RetentionRule>, mongodb::error::Error> {
+        self.rules.find(None, None).await?.try_collect().await
+    }
+
+    pub async fn holds(&self) -> Result<Vec<LegalHold>, mongodb::error::Error> {
+        self.holds.find(None, None).await?.try_collect().await
+    }
+
+    pub async fn add_rule(
+        &self,
+        prefix: String,
+        retention: Retention,
+        created_by: Option<String>,
+    ) -> Result<RetentionRule, mongodb::error::Error> {
+        let rule = RetentionRule {
+            id: Uuid::new_v4().to_string(),
+            prefix,
+            retention,
+            created_at: Utc::now(),
+            created_by,
+        };
+        self.rules.insert_one(&rule, None).await?;
+        Ok(rule)
+    }
+
+    /// Deletes a retention rule, returning it or none if there is no such rule.
+    pub async fn remove_rule(
+        &self,
+        id: &str,
+    ) -> Result<Option<RetentionRule>, mongodb::error::Error> {
+        self.rules
+            .find_one_and_delete(doc! { "id": id }, None)
+            .await
+    }
+
+    pub async fn place_hold(
+        &self,
+        prefix: String,
+        reason: String,
+        created_by: Option<String>,
+    ) -> Result<LegalHold, mongodb::error::Error> {
+        let hold = LegalHold {
+            id: Uuid::new_v4().to_string(),
+            prefix,
+            reason,
+            created_at: Utc::now(),
+            created_by,
+        };
+        self.holds.insert_one(&hold, None).await?;
+        Ok(hold)
+    }
+
+    /// Releases a legal hold, returning it or none if there is no such hold.
+    pub async fn release_hold(&self, id: &str) -> Result<Option<LegalHold>, mongodb::error::Error> {
+        self.holds
+            .find_one_and_delete(doc! { "id": id }, None)
+            .await
+    }
+
+    /// The legal hold covering a path, if any.
+    pub async fn hold_on(&self, path: &str) -> Result<Option<LegalHold>, mongodb::error::Error> {
+        Ok(self
+            .holds()
+            .await?
+            .into_iter()
+            .find(|hold| path.starts_with(&hold.prefix)))
+    }
+
+    /// Whether a legal hold covers a path.
+    pub async fn is_held(&self, path: &str) -> Result<bool, mongodb::error::Error> {
+        Ok(self.hold_on(path).await?.is_some())
+    }
+
+    /// Rejects removing or replacing the entry at a path if it is on hold or
+    /// younger than a retention rule requires.
+    async fn check_removal(
+        &self,
+        path: &str,
+        created_at: DateTime<Utc>,
+        action: &str,
+    ) -> Result<(), PolicyError> {
+        if let Some(hold) = self.hold_on(path).await? {
+            return Err(violation(format!(
+                "{} cannot be {} while legal hold {} on {} is in place: {}",
+                path, action, hold.id, hold.prefix, hold.reason
+            )));
+        }
+        for rule in self.rules().await? {
+            if let Retention::KeepFor { days } = rule.retention {
+                let kept_until = created_at + Duration::days(days);
+                if path.starts_with(&rule.prefix) && kept_until > Utc::now() {
+                    return Err(violation(format!(
+                        "{} cannot be {} before {} under retention rule {} on {}",
+                        path,
+                        action,
+                        kept_until.to_rfc3339(),
+                        rule.id,
+                        rule.prefix
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a write to a path against the policies, returning the expiry
+    /// the written entry must have. `current` is when the entry being
+    /// overwritten was written, if there is one.
+    pub async fn check_write(
+        &self,
+        path: &str,
+        current: Option<DateTime<Utc>>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<DateTime<Utc>>, PolicyError> {
+        if let Some(created_at) = current {
+            self.check_removal(path, created_at, "overwritten").await?;
+        }
+
+        let rules = self.rules().await?;
+        let mut expires_at = expires_at;
+        for rule in &rules {
+            if let Retention::DeleteAfter { days } = rule.retention {
+                if !path.starts_with(&rule.prefix) {
+                    continue;
+                }
+                let latest = Utc::now() + Duration::days(days);
+                match expires_at {
+                    Some(requested) if requested > latest => {
+                        return Err(violation(format!(
+                            "{} must expire by {} under retention rule {} on {}",
+                            path,
+                            latest.to_rfc3339(),
+                            rule.id,
+                            rule.prefix
+                        )))
+                    }
+                    Some(_) => {}
+                    None => expires_at = Some(latest),
+                }
+            }
+        }
+
+        // An entry may not expire before it may be deleted
+        if let Some(expires_at) = expires_at {
+            for rule in &rules {
+                if let Retention::KeepFor { days } = rule.retention {
+                    let kept_until = Utc::now() + Duration::days(days);
+                    if path.starts_with(&rule.prefix) && expires_at < kept_until {
+                        return Err(violation(format!(
+                            "{} cannot expire before {} under retention rule {} on {}",
+                            path,
+                            kept_until.to_rfc3339(),
+                            rule.id,
+                            rule.prefix
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(expires_at)
+    }
+
+    /// Checks that the entry at a path, written at the given time, may be deleted.
+    pub async fn check_delete(
+        &self,
+        path: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<(), PolicyError> {
+        self.check_removal(path, created_at, "deleted").await
+    }
+}
+
+/// When the entry currently at a path was written, or none if there is no
+/// entry. Entries written before versions were kept are treated as new, so
+/// that retention rules keep protecting them.
+pub async fn written_at(
+    index: &EntryIndex,
+    versions: &VersionStore,
+    path: &str,
+) -> Result<Option<DateTime<Utc>>, PolicyError> {
+    if !index.exists(path).await? {
+        return Ok(None);
+    }
+    Ok(Some(
+        versions
+            .current(path)
+            .await?
+            .map(|current| backends::to_chrono(current.created_at))
+            .unwrap_or_else(Utc::now),
+    ))
+}
@@ -0,0 +1,207 @@
+//! Hot-swappable server certificates and the background task that renews
+//! the generated server certificate before it expires.
+
+use crate::{
+    ca::{self, CertificateAuthority, SubjectAltName},
+    keys::StoreKey,
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use redact_crypto::x509::DistinguishedName;
+use std::{
+    collections::HashSet,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey,
+};
+
+/// Loads a PEM certificate chain and PKCS#8 private key into a key usable by rustls.
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let file = File::open(cert_path)?;
+    let mut reader = io::BufReader::new(file);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)
+        .map_err(|_err| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Cannot load certificate from {}", cert_path.display()),
+            )
+        })?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let file = File::open(key_path)?;
+    let mut reader = io::BufReader::new(file);
+    let keys = rustls_pemfile::pkcs8_private_keys(&mut reader).map_err(|_err| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Cannot load private key from {}", key_path.display()),
+        )
+    })?;
+    let key = PrivateKey(keys.into_iter().next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "No keys found in the private key file {}",
+                key_path.display()
+            ),
+        )
+    })?);
+    let signing_key = sign::any_supported_type(&key).map_err(|_err| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unsupported private key type in {}", key_path.display()),
+        )
+    })?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Returns the expiry time of the first certificate in a PEM file.
+pub fn certificate_expiry(cert_path: &Path) -> io::Result<DateTime<Utc>> {
+    let der = ca::read_certificate(cert_path)?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Cannot parse certificate {}: {}", cert_path.display(), e),
+        )
+    })?;
+
+    Utc.timestamp_opt(cert.validity().not_after.timestamp(), 0)
+        .single()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Certificate {} has an invalid expiry", cert_path.display()),
+            )
+        })
+}
+
+/// Serves whichever certificate was most recently stored in it, allowing the
+/// server certificate to be replaced without rebuilding the TLS config.
+pub struct ReloadableCertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCertResolver {
+    pub fn new(certified_key: CertifiedKey) -> Self {
+        ReloadableCertResolver {
+            current: RwLock::new(Arc::new(certified_key)),
+        }
+    }
+
+    pub fn replace(&self, certified_key: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(certified_key);
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Subject and validity of the generated server certificate.
+#[derive(Debug, Clone)]
+pub struct ServerCertificateSpec {
+    pub o: String,
+    pub ou: String,
+    pub cn: String,
+    /// Validity of each issued certificate in days
+    pub expires_in: i64,
+    pub sans: Vec<SubjectAltName>,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl ServerCertificateSpec {
+    /// Issues a new server certificate for the key with the CA and writes it to disk.
+    pub fn issue(&self, ca: &CertificateAuthority, key: &StoreKey) -> io::Result<()> {
+        let cert = ca
+            .issue(
+                key,
+                &DistinguishedName {
+                    o: &self.o,
+                    ou: &self.ou,
+                    cn: &self.cn,
+                },
+                self.expires_in,
+                &self.sans,
+            )
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Cannot issue server certificate: {}", e),
+                )
+            })?;
+
+        ca::write_certificate(&self.cert_path, &cert)
+    }
+
+    /// Returns true if the certificate on disk carries exactly the configured
+    /// subject alternative names.
+    pub fn sans_match(&self) -> io::Result<bool> {
+        let on_disk: HashSet<SubjectAltName> =
+            ca::certificate_sans(&ca::read_certificate(&self.cert_path)?)?
+                .into_iter()
+                .collect();
+        let configured: HashSet<SubjectAltName> = self.sans.iter().cloned().collect();
+        Ok(on_disk == configured)
+    }
+}
+
+/// Re-issues the server certificate a set amount of time before it expires
+/// and swaps it into the running TLS listener.
+pub struct ServerCertRenewer {
+    pub ca: Arc<CertificateAuthority>,
+    pub key: StoreKey,
+    pub spec: ServerCertificateSpec,
+    pub renew_before: Duration,
+    pub resolver: Arc<ReloadableCertResolver>,
+}
+
+impl ServerCertRenewer {
+    /// Renews the certificate if it is within the renewal window or no longer
+    /// carries the configured subject alternative names, returning whether it
+    /// was renewed.
+    pub fn renew_if_needed(&self) -> io::Result<bool> {
+        let expiry = certificate_expiry(&self.spec.cert_path)?;
+        if Utc::now() + self.renew_before < expiry && self.spec.sans_match()? {
+            return Ok(false);
+        }
+
+        self.spec.issue(&self.ca, &self.key)?;
+        self.resolver.replace(load_certified_key(
+            &self.spec.cert_path,
+            &self.spec.key_path,
+        )?);
+        Ok(true)
+    }
+
+    /// Checks the certificate on the given interval for as long as the server
+    /// runs. Each check reads and writes files, so it runs on the blocking pool.
+    pub fn spawn(self, check_interval: std::time::Duration) {
+        let renewer = Arc::new(self);
+        tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(check_interval);
+            loop {
+                ticker.tick().await;
+                let check = renewer.clone();
+                match tokio::task::spawn_blocking(move || check.renew_if_needed()).await {
+                    Ok(Ok(true)) => log::info!(
+                        "Renewed server certificate at {}",
+                        renewer.spec.cert_path.display()
+                    ),
+                    Ok(Ok(false)) => (),
+                    Ok(Err(e)) => log::error!("Could not renew server certificate: {}", e),
+                    Err(e) => log::error!("Server certificate renewal check failed: {}", e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,275 @@
+//! Certificate revocation checking against CRL files on disk.
+//!
+//! Every CRL must be signed by a CA certificate in the trust bundle and carry a
+//! nextUpdate time. Once a CRL is past its nextUpdate, certificates from its
+//! issuer are treated as revoked until a fresh CRL is loaded.
+
+use crate::bootstrap;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::rustls::{
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, DistinguishedNames, Error as TlsError,
+};
+use x509_parser::{
+    certificate::X509Certificate, pem::Pem, prelude::FromDer,
+    revocation_list::CertificateRevocationList, x509::SubjectPublicKeyInfo,
+};
+
+/// Identifies a revoked certificate by the raw DER bytes of its issuer name
+/// and serial number, as serial numbers are only unique per issuer.
+type RevokedSerial = (Vec<u8>, Vec<u8>);
+
+/// Revoked certificates and the time by which each issuer's CRL must have
+/// been replaced, keyed by the raw DER bytes of the issuer name.
+#[derive(Default)]
+struct Crls {
+    revoked: HashSet<RevokedSerial>,
+    next_updates: HashMap<Vec<u8>, SystemTime>,
+}
+
+/// Set of revoked certificates built from one or more CRL files.
+pub struct CrlStore {
+    paths: Vec<PathBuf>,
+    /// PEM file of the CA certificates CRLs must be signed by
+    issuers_path: PathBuf,
+    crls: RwLock<Crls>,
+}
+
+impl CrlStore {
+    /// Creates a store for the given CRL files and loads them immediately.
+    pub fn new(paths: Vec<PathBuf>, issuers_path: PathBuf) -> io::Result<Self> {
+        let store = CrlStore {
+            paths,
+            issuers_path,
+            crls: RwLock::new(Crls::default()),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Re-reads every CRL file, replacing the loaded CRLs only if all of them
+    /// could be parsed, are signed by a CA in the trust bundle and are not
+    /// past their nextUpdate time.
+    pub fn reload(&self) -> io::Result<()> {
+        if self.paths.is_empty() {
+            return Ok(());
+        }
+
+        let issuers = bootstrap::trust_bundle(&self.issuers_path)?;
+        let now = SystemTime::now();
+        let mut crls = Crls::default();
+        for path in &self.paths {
+            let invalid = |reason: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Cannot load CRL from {}: {}", path.display(), reason),
+                )
+            };
+
+            let bytes = fs::read(path)?;
+            for der in crl_ders(&bytes) {
+                let (_, crl) = CertificateRevocationList::from_der(&der)
+                    .map_err(|e| invalid(e.to_string()))?;
+                verify_crl_signature(&crl, &issuers).map_err(invalid)?;
+                let next_update = crl
+                    .next_update()
+                    .and_then(|next_update| u64::try_from(next_update.timestamp()).ok())
+                    .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                    .ok_or_else(|| invalid("CRL has no nextUpdate time".to_owned()))?;
+                if next_update <= now {
+                    return Err(invalid("CRL is past its nextUpdate time".to_owned()));
+                }
+
+                let issuer = crl.issuer().as_raw().to_vec();
+                crls.revoked.extend(
+                    crl.iter_revoked_certificates()
+                        .map(|entry| (issuer.clone(), entry.raw_serial().to_vec())),
+                );
+                crls.next_updates
+                    .entry(issuer)
+                    .and_modify(|earliest| *earliest = (*earliest).min(next_update))
+                    .or_insert(next_update);
+            }
+        }
+
+        *self.crls.write().unwrap() = crls;
+        Ok(())
+    }
+
+    /// Returns true if the certificate appears in any loaded CRL or its
+    /// issuer's CRL has gone stale. Certificates that cannot be parsed are
+    /// treated as revoked.
+    pub fn is_revoked(&self, cert: &Certificate) -> bool {
+        let cert = match x509_parser::parse_x509_certificate(&cert.0) {
+            Ok((_, cert)) => cert,
+            Err(_) => return true,
+        };
+        let issuer = cert.issuer().as_raw().to_vec();
+        let crls = self.crls.read().unwrap();
+
+        if let Some(next_update) = crls.next_updates.get(&issuer) {
+            if *next_update <= SystemTime::now() {
+                log::warn!(
+                    "CRL for issuer {} is past its nextUpdate time, treating its certificates as revoked",
+                    cert.issuer()
+                );
+                return true;
+            }
+        }
+        crls.revoked.contains(&(issuer, cert.raw_serial().to_vec()))
+    }
+
+    /// Periodically reloads the CRL files in the background. A failed reload
+    /// keeps the previously loaded set in place.
+    pub fn spawn_reloader(self: Arc<Self>, interval: Duration) {
+        tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately and the CRLs were loaded on creation
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if let Err(e) = self.reload() {
+                    log::error!("Could not reload certificate revocation lists: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Splits a CRL file into DER documents, accepting either a single DER CRL or
+/// any number of PEM-encoded `X509 CRL` blocks.
+fn crl_ders(bytes: &[u8]) -> Vec<Vec<u8>> {
+    let pems: Vec<Vec<u8>> = Pem::iter_from_buffer(bytes)
+        .filter_map(|pem| pem.ok())
+        .filter(|pem| pem.label == "X509 CRL")
+        .map(|pem| pem.contents)
+        .collect();
+
+    if pems.is_empty() {
+        vec![bytes.to_vec()]
+    } else {
+        pems
+    }
+}
+
+/// Checks that a CRL is signed by a CA certificate with the CRL's issuer name.
+fn verify_crl_signature(
+    crl: &CertificateRevocationList,
+    issuers: &[Certificate],
+) -> Result<(), String> {
+    let candidates: Vec<X509Certificate> = issuers
+        .iter()
+        .filter_map(|issuer| x509_parser::parse_x509_certificate(&issuer.0).ok())
+        .map(|(_, issuer)| issuer)
+        .filter(|issuer| issuer.subject().as_raw() == crl.issuer().as_raw())
+        .collect();
+    if candidates.is_empty() {
+        return Err(format!(
+            "no CA certificate in the trust bundle issued CRLs for {}",
+            crl.issuer()
+        ));
+    }
+
+    let signature_algorithm = crl.signature_algorithm.algorithm.to_id_string();
+    let signed = candidates.iter().any(|issuer| {
+        let public_key = issuer.public_key();
+        verification_algorithm(&signature_algorithm, public_key).is_some_and(|algorithm| {
+            UnparsedPublicKey::new(algorithm, &public_key.subject_public_key.data)
+                .verify(crl.tbs_cert_list.as_ref(), &crl.signature_value.data)
+                .is_ok()
+        })
+    });
+    if signed {
+        Ok(())
+    } else {
+        Err("CRL signature does not verify against its issuer".to_owned())
+    }
+}
+
+/// Maps a signature algorithm OID and the signer's public key to the matching
+/// verification algorithm.
+fn verification_algorithm(
+    signature_algorithm: &str,
+    public_key: &SubjectPublicKeyInfo,
+) -> Option<&'static dyn VerificationAlgorithm> {
+    let curve = public_key
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|parameters| parameters.as_oid().ok())
+        .map(|oid| oid.to_id_string());
+    let algorithm: &'static dyn VerificationAlgorithm =
+        match (signature_algorithm, curve.as_deref()) {
+            ("1.2.840.113549.1.1.11", _) => &signature::RSA_PKCS1_2048_8192_SHA256,
+            ("1.2.840.113549.1.1.12", _) => &signature::RSA_PKCS1_2048_8192_SHA384,
+            ("1.2.840.113549.1.1.13", _) => &signature::RSA_PKCS1_2048_8192_SHA512,
+            ("1.2.840.10045.4.3.2", Some("1.2.840.10045.3.1.7")) => {
+                &signature::ECDSA_P256_SHA256_ASN1
+            }
+            ("1.2.840.10045.4.3.2", Some("1.3.132.0.34")) => &signature::ECDSA_P384_SHA256_ASN1,
+            ("1.2.840.10045.4.3.3", Some("1.2.840.10045.3.1.7")) => {
+                &signature::ECDSA_P256_SHA384_ASN1
+            }
+            ("1.2.840.10045.4.3.3", Some("1.3.132.0.34")) => &signature::ECDSA_P384_SHA384_ASN1,
+            ("1.3.101.112", _) => &signature::ED25519,
+            _ => return None,
+        };
+    Some(algorithm)
+}
+
+/// Client certificate verifier that rejects revoked certificates after the
+/// wrapped verifier has accepted them.
+pub struct RevocationCheckingVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    crls: Arc<CrlStore>,
+}
+
+impl RevocationCheckingVerifier {
+    pub fn new(inner: Arc<dyn ClientCertVerifier>, crls: Arc<CrlStore>) -> Self {
+        RevocationCheckingVerifier { inner, crls }
+    }
+}
+
+impl ClientCertVerifier for RevocationCheckingVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, TlsError> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+
+        if std::iter::once(end_entity)
+            .chain(intermediates)
+            .any(|cert| self.crls.is_revoked(cert))
+        {
+            log::warn!("Rejecting client certificate chain containing a revoked certificate");
+            return Err(TlsError::InvalidCertificateData(
+                "client certificate has been revoked".to_owned(),
+            ));
+        }
+
+        Ok(verified)
+    }
+}
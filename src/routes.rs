@@ -1,3 +1,158 @@
+pub mod audit;
+pub mod batch;
+pub mod certificates;
+pub mod erasures;
 pub mod error;
 pub mod get;
+pub mod metrics;
+pub mod policies;
 pub mod post;
+pub mod readyz;
+pub mod trash;
+pub mod versions;
+
+use crate::{audit::AuditLog, identity::Identity, logging, policy::PolicyError};
+use error::{
+    AuditErrorRejection, DeniedRejection, ForbiddenRejection, NotFoundRejection,
+    PolicyErrorRejection, PolicyViolationRejection,
+};
+use std::sync::Arc;
+use warp::{http::Method, Rejection};
+
+/// Names the operation a request performs, keeping data paths out of the
+/// name so it can be used as a metric label or log field.
+pub fn operation(method: &Method, path: &str, query: Option<&str>) -> &'static str {
+    match (method, path) {
+        (&Method::GET, "/-/healthz") => "healthz",
+        (&Method::GET, "/-/readyz") => "readyz",
+        (&Method::GET, "/metrics" | "/-/metrics") => "metrics",
+        (&Method::POST, "/admin/certificates") => "issue_certificate",
+        (&Method::GET, "/admin/audit/verify") => "verify_audit",
+        (&Method::POST, "/") => "create",
+        (&Method::POST, "/-/batch") => "batch_create",
+        (&Method::POST, "/-/batch-get") => "batch_get",
+        (&Method::GET, "/admin/policies") => "list_policies",
+        (&Method::POST, "/admin/policies/retention") => "add_retention_rule",
+        (&Method::DELETE, path) if path.starts_with("/admin/policies/retention/") => {
+            "remove_retention_rule"
+        }
+        (&Method::POST, "/admin/policies/holds") => "place_legal_hold",
+        (&Method::DELETE, path) if path.starts_with("/admin/policies/holds/") => {
+            "release_legal_hold"
+        }
+        (&Method::POST, "/admin/erasures") => "start_erasure",
+        (&Method::GET, path)
+            if path.starts_with("/admin/erasures/") && path.ends_with("/receipt") =>
+        {
+            "get_erasure_receipt"
+        }
+        (&Method::GET, path) if path.starts_with("/admin/erasures/") => "get_erasure",
+        (&Method::GET, "/-/trash") => "list_trash",
+        (&Method::POST, path) if path.starts_with("/-/trash/") => "restore",
+        (&Method::DELETE, _) => "delete",
+        (&Method::GET, path) if path.ends_with("/versions") => "list_versions",
+        (&Method::GET, _) => {
+            let has = |name: &str| {
+                query.is_some_and(|query| {
+                    query
+                        .split('&')
+                        .any(|parameter| parameter.split('=').next() == Some(name))
+                })
+            };
+            if has("skip") {
+                "list"
+            } else if has("version") || has("as_of") {
+                "get_version"
+            } else {
+                "get"
+            }
+        }
+        _ => "unmatched",
+    }
+}
+
+/// Rejects a request refused by the policies with a policy violation, and
+/// one the policies could not be checked for as a storage failure.
+pub fn policy_rejection(e: PolicyError) -> Rejection {
+    match e {
+        PolicyError::Violation { reason } => {
+            warp::reject::custom(PolicyViolationRejection { reason })
+        }
+        e => {
+            log::error!("An error occurred while checking policies: {}", e);
+            warp::reject::custom(PolicyErrorRejection(e))
+        }
+    }
+}
+
+/// Records the outcome of an operation in the audit log, if one is
+/// configured. The request fails if its record cannot be written.
+pub async fn audit<T>(
+    audit_log: Option<&Arc<AuditLog>>,
+    identity: Option<&Identity>,
+    operation: &str,
+    path: &str,
+    result: &Result<T, Rejection>,
+) -> Result<(), Rejection> {
+    let audit_log = match audit_log {
+        Some(audit_log) => audit_log,
+        None => return Ok(()),
+    };
+    let outcome = match result {
+        Ok(_) => "success",
+        Err(rejection) if rejection.find::<NotFoundRejection>().is_some() => "not_found",
+        Err(rejection)
+            if rejection.find::<PolicyViolationRejection>().is_some()
+                || rejection.find::<ForbiddenRejection>().is_some() =>
+        {
+            "denied"
+        }
+        Err(_) => "failure",
+    };
+
+    audit_log
+        .record(
+            identity.map(|identity| identity.to_string()),
+            operation,
+            path,
+            outcome,
+            logging::current_request_id(),
+        )
+        .await
+        .map_err(|e| {
+            log::error!(
+                "Could not write audit record for {} of {}: {}",
+                operation,
+                path,
+                e
+            );
+            warp::reject::custom(AuditErrorRejection(e))
+        })
+}
+
+/// Records a caller turned away by `identity::authorize` or
+/// `identity::authorize_admin` in the audit log, if one is configured. The
+/// response is already a denial, so a failed write is only logged.
+pub async fn audit_denial(audit_log: Option<&Arc<AuditLog>>, rejection: &Rejection) {
+    let (audit_log, denied) = match (audit_log, rejection.find::<DeniedRejection>()) {
+        (Some(audit_log), Some(denied)) => (audit_log, denied),
+        _ => return,
+    };
+
+    if let Err(e) = audit_log
+        .record(
+            denied.identity.clone(),
+            "authorize",
+            &denied.path,
+            "denied",
+            logging::current_request_id(),
+        )
+        .await
+    {
+        log::error!(
+            "Could not write audit record for denied request to {}: {}",
+            denied.path,
+            e
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use crate::{
+    audit::AuditLog,
+    identity::{self, AuthorizationRules},
+    routes::error::{AuditErrorRejection, NotFoundRejection},
+};
+use std::{
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+use warp::{Filter, Rejection, Reply};
+
+pub fn verify(
+    audit_log: Option<Arc<AuditLog>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "audit" / "verify")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(move |audit_log: Option<Arc<AuditLog>>| async move {
+            // Verification is only possible when the audit log is enabled
+            let audit_log = audit_log.ok_or_else(|| warp::reject::custom(NotFoundRejection))?;
+
+            let verification = tokio::task::spawn_blocking(move || audit_log.verify())
+                .await
+                .unwrap_or_else(|e| Err(io::Error::new(ErrorKind::Other, e)))
+                .map_err(|e| {
+                    log::error!("An error occurred while verifying the audit log: {}", e);
+                    warp::reject::custom(AuditErrorRejection(e))
+                })?;
+
+            Ok::<_, Rejection>(warp::reply::json(&verification))
+        })
+}
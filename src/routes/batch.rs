@@ -0,0 +1,434 @@
+use crate::{
+    audit::AuditLog,
+    error_handler::ItemError,
+    expiry::ExpiryStore,
+    history::VersionStore,
+    identity::{self, AuthorizationRules, Identity},
+    index::EntryIndex,
+    policy::PolicyStore,
+    routes::{
+        self,
+        error::{BadRequestRejection, ConflictRejection},
+        get,
+        post::{self, Prior, Writer},
+    },
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use redact_crypto::{Entry, IndexedStorer, Storer, Type, TypeStorer};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+/// Entries a single batch write may carry.
+const MAX_BATCH_ENTRIES: usize = 1000;
+
+/// Paths a single batch read may ask for.
+const MAX_BATCH_PATHS: usize = 100;
+
+/// Paths of a batch read looked up at the same time.
+const MAX_CONCURRENT_LOOKUPS: usize = 16;
+
+/// How a batch write treats the failure of one of its entries.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BatchMode {
+    /// Nothing is written unless every entry can be, and entries already
+    /// written are undone when a later one fails
+    #[default]
+    AllOrNothing,
+    /// Every entry is written independently of the others
+    BestEffort,
+}
+
+#[derive(Deserialize)]
+struct BatchCreateRequest {
+    #[serde(default)]
+    mode: BatchMode,
+    entries: Vec<Entry<Type>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum BatchCreateStatus {
+    Created,
+    Failed,
+    /// Written, then undone because another entry failed
+    RolledBack,
+    /// Written, then left in place because undoing it failed
+    RollbackFailed,
+    /// Not attempted because another entry failed
+    Skipped,
+}
+
+#[derive(Serialize)]
+struct BatchCreateResult {
+    path: String,
+    status: BatchCreateStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+#[derive(Serialize)]
+struct BatchCreateResponse {
+    success: bool,
+    results: Vec<BatchCreateResult>,
+}
+
+/// Outcome of one entry, kept as a rejection until it is audited.
+struct Outcome {
+    path: String,
+    status: BatchCreateStatus,
+    version: Option<i64>,
+    result: Result<(), Rejection>,
+}
+
+impl Outcome {
+    fn new(path: String, status: BatchCreateStatus, result: Result<(), Rejection>) -> Self {
+        Outcome {
+            path,
+            status,
+            version: None,
+            result,
+        }
+    }
+}
+
+fn skipped(path: String) -> Outcome {
+    Outcome::new(
+        path,
+        BatchCreateStatus::Skipped,
+        Err(warp::reject::custom(ConflictRejection {
+            reason: "not written because another entry of the batch failed".to_owned(),
+        })),
+    )
+}
+
+/// Length of an entry as if it had been posted on its own, for the upload metrics.
+fn content_length(entry: &Entry<Type>) -> u64 {
+    serde_json::to_vec(entry)
+        .map(|bytes| bytes.len() as u64)
+        .unwrap_or_default()
+}
+
+async fn best_effort<T: Storer>(
+    writer: &Writer<T>,
+    entries: Vec<Entry<Type>>,
+    expires_at: Option<DateTime<Utc>>,
+    created_by: Option<String>,
+) -> Vec<Outcome> {
+    let mut outcomes = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let path = entry.path.clone();
+        let length = content_length(&entry);
+        let written = match writer.check(&path, expires_at).await {
+            Ok(expires_at) => {
+                writer
+                    .write(entry, expires_at, created_by.clone(), length)
+                    .await
+            }
+            Err(rejection) => Err(rejection),
+        };
+        outcomes.push(match written {
+            Ok(version) => Outcome {
+                version: Some(version),
+                ..Outcome::new(path, BatchCreateStatus::Created, Ok(()))
+            },
+            Err(rejection) => Outcome::new(path, BatchCreateStatus::Failed, Err(rejection)),
+        });
+    }
+    outcomes
+}
+
+async fn all_or_nothing<T: Storer>(
+    writer: &Writer<T>,
+    entries: Vec<Entry<Type>>,
+    expires_at: Option<DateTime<Utc>>,
+    created_by: Option<String>,
+) -> Vec<Outcome> {
+    // Every entry is checked against the policies before anything is written
+    let mut checked = Vec::with_capacity(entries.len());
+    let mut refused = false;
+    for entry in &entries {
+        let check = writer.check(&entry.path, expires_at).await;
+        refused |= check.is_err();
+        checked.push(check);
+    }
+    if refused {
+        return entries
+            .into_iter()
+            .zip(checked)
+            .map(|(entry, check)| match check {
+                Ok(_) => skipped(entry.path),
+                Err(rejection) => {
+                    Outcome::new(entry.path, BatchCreateStatus::Failed, Err(rejection))
+                }
+            })
+            .collect();
+    }
+
+    let mut outcomes = Vec::with_capacity(entries.len());
+    let mut written: Vec<(Prior, i64)> = vec![];
+    let mut entries = entries.into_iter().zip(checked);
+    for (entry, check) in entries.by_ref() {
+        let path = entry.path.clone();
+        let length = content_length(&entry);
+        let expires_at = check.unwrap_or(expires_at);
+        let result = match writer.prior(&path).await {
+            Ok(prior) => writer
+                .write(entry, expires_at, created_by.clone(), length)
+                .await
+                .map(|version| (prior, version)),
+            Err(rejection) => Err(rejection),
+        };
+        match result {
+            Ok((prior, version)) => {
+                written.push((prior, version));
+                outcomes.push(Outcome {
+                    version: Some(version),
+                    ..Outcome::new(path, BatchCreateStatus::Created, Ok(()))
+                });
+            }
+            Err(rejection) => {
+                outcomes.push(Outcome::new(
+                    path,
+                    BatchCreateStatus::Failed,
+                    Err(rejection),
+                ));
+                break;
+            }
+        }
+    }
+    if written.len() == outcomes.len() {
+        return outcomes;
+    }
+
+    outcomes.extend(entries.map(|(entry, _)| skipped(entry.path)));
+    // Undone newest first, so that each path goes back to the state it was in before the batch
+    for (index, (prior, version)) in written.iter().enumerate().rev() {
+        let outcome = &mut outcomes[index];
+        match writer.undo(prior, *version).await {
+            Ok(()) => {
+                outcome.status = BatchCreateStatus::RolledBack;
+                outcome.version = None;
+                outcome.result = Err(warp::reject::custom(ConflictRejection {
+                    reason: "undone because another entry of the batch failed".to_owned(),
+                }));
+            }
+            Err(rejection) => {
+                outcome.status = BatchCreateStatus::RollbackFailed;
+                outcome.result = Err(rejection);
+            }
+        }
+    }
+    outcomes
+}
+
+pub fn create<T: Storer>(
+    storer: Arc<T>,
+    blob_storer: Arc<TypeStorer>,
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    index: Arc<EntryIndex>,
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let writer = Arc::new(Writer::new(
+        storer,
+        blob_storer,
+        versions,
+        expiries,
+        index,
+        policies,
+    ));
+    warp::path!("-" / "batch")
+        .and(post::expiry())
+        .and(warp::body::content_length_limit(1024 * 1024 * 250))
+        .and(warp::body::json::<BatchCreateRequest>())
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || writer.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |expires_at: Option<DateTime<Utc>>,
+                  request: BatchCreateRequest,
+                  identity: Option<Identity>,
+                  writer: Arc<Writer<T>>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                // Each path may only be written once per batch, so that undoing it is unambiguous
+                let mut paths = HashSet::new();
+                if request.entries.is_empty()
+                    || request.entries.len() > MAX_BATCH_ENTRIES
+                    || !request
+                        .entries
+                        .iter()
+                        .all(|entry| paths.insert(&entry.path))
+                {
+                    return Err(warp::reject::custom(BadRequestRejection));
+                }
+
+                let created_by = identity.as_ref().map(|identity| identity.to_string());
+                let outcomes = match request.mode {
+                    BatchMode::AllOrNothing => {
+                        all_or_nothing(&writer, request.entries, expires_at, created_by).await
+                    }
+                    BatchMode::BestEffort => {
+                        best_effort(&writer, request.entries, expires_at, created_by).await
+                    }
+                };
+
+                for outcome in &outcomes {
+                    routes::audit(
+                        audit_log.as_ref(),
+                        identity.as_ref(),
+                        "batch_create",
+                        &outcome.path,
+                        &outcome.result,
+                    )
+                    .await?;
+                }
+
+                let success = outcomes
+                    .iter()
+                    .all(|outcome| outcome.status == BatchCreateStatus::Created);
+                let results = outcomes
+                    .into_iter()
+                    .map(|outcome| BatchCreateResult {
+                        // Skipped and undone entries did nothing wrong themselves
+                        error: outcome
+                            .result
+                            .as_ref()
+                            .err()
+                            .filter(|_| {
+                                matches!(
+                                    outcome.status,
+                                    BatchCreateStatus::Failed | BatchCreateStatus::RollbackFailed
+                                )
+                            })
+                            .map(ItemError::from_rejection),
+                        path: outcome.path,
+                        status: outcome.status,
+                        version: outcome.version,
+                    })
+                    .collect();
+                let status = if success {
+                    StatusCode::OK
+                } else {
+                    StatusCode::MULTI_STATUS
+                };
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&BatchCreateResponse { success, results }),
+                    status,
+                ))
+            },
+        )
+}
+
+#[derive(Deserialize)]
+struct BatchGetRequest {
+    paths: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BatchGetResult {
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entry: Option<Entry<Type>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+#[derive(Serialize)]
+struct BatchGetResponse {
+    results: Vec<BatchGetResult>,
+}
+
+pub fn get<T: IndexedStorer>(
+    storer: Arc<T>,
+    expiries: Arc<ExpiryStore>,
+    authorization_rules: Arc<AuthorizationRules>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("-" / "batch-get")
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json::<BatchGetRequest>())
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || storer.clone()))
+        .and(warp::any().map(move || expiries.clone()))
+        .and(warp::any().map(move || authorization_rules.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |request: BatchGetRequest,
+                  identity: Option<Identity>,
+                  storer: Arc<T>,
+                  expiries: Arc<ExpiryStore>,
+                  authorization_rules: Arc<AuthorizationRules>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                if request.paths.is_empty() || request.paths.len() > MAX_BATCH_PATHS {
+                    return Err(warp::reject::custom(BadRequestRejection));
+                }
+
+                // Results come back in the order the paths were asked for
+                let lookups: Vec<(String, Result<Entry<Type>, Rejection>)> =
+                    stream::iter(request.paths)
+                        .map(|path| {
+                            let storer = &storer;
+                            let expiries = &expiries;
+                            let authorization_rules = &authorization_rules;
+                            let identity = identity.as_ref();
+                            async move {
+                                let result = match identity::check_read(
+                                    authorization_rules,
+                                    identity,
+                                    &path,
+                                ) {
+                                    Ok(()) => get::lookup(storer.as_ref(), expiries, &path).await,
+                                    Err(rejection) => Err(rejection),
+                                };
+                                (path, result)
+                            }
+                        })
+                        .buffered(MAX_CONCURRENT_LOOKUPS)
+                        .collect()
+                        .await;
+
+                for (path, result) in &lookups {
+                    routes::audit(
+                        audit_log.as_ref(),
+                        identity.as_ref(),
+                        "batch_get",
+                        path,
+                        result,
+                    )
+                    .await?;
+                }
+
+                let success = lookups.iter().all(|(_, result)| result.is_ok());
+                let results = lookups
+                    .into_iter()
+                    .map(|(path, result)| match result {
+                        Ok(entry) => BatchGetResult {
+                            path,
+                            entry: Some(entry),
+                            error: None,
+                        },
+                        Err(rejection) => BatchGetResult {
+                            path,
+                            entry: None,
+                            error: Some(ItemError::from_rejection(&rejection)),
+                        },
+                    })
+                    .collect();
+                let status = if success {
+                    StatusCode::OK
+                } else {
+                    StatusCode::MULTI_STATUS
+                };
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&BatchGetResponse { results }),
+                    status,
+                ))
+            },
+        )
+}
@@ -0,0 +1,48 @@
+use crate::{
+    ca::{self, CertificateAuthority},
+    identity::{self, AuthorizationRules},
+    routes::error::{CaErrorRejection, NotFoundRejection},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Deserialize)]
+struct IssueCertificateRequest {
+    csr: String,
+    expires_in: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct IssueCertificateResponse {
+    certificate: String,
+}
+
+pub fn issue(
+    ca: Option<Arc<CertificateAuthority>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "certificates")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::body::content_length_limit(1024 * 64))
+        .and(warp::body::json::<IssueCertificateRequest>())
+        .and(warp::any().map(move || ca.clone()))
+        .and_then(
+            move |request: IssueCertificateRequest,
+                  ca: Option<Arc<CertificateAuthority>>| async move {
+                // Issuance is only possible when the store generated and holds its own CA
+                let ca = ca.ok_or_else(|| warp::reject::custom(NotFoundRejection))?;
+
+                let certificate = ca
+                    .sign_csr(request.csr.as_bytes(), request.expires_in)
+                    .map_err(|e| {
+                        log::error!("An error occurred while signing a client certificate: {}", e);
+                        warp::reject::custom(CaErrorRejection(e))
+                    })?;
+
+                Ok::<_, Rejection>(warp::reply::json(&IssueCertificateResponse {
+                    certificate: ca::certificate_pem(&certificate),
+                }))
+            },
+        )
+}
@@ -0,0 +1,198 @@
+use crate::{
+    audit::AuditLog,
+    erasure::{ErasureScope, ErasureStatus, ErasureStore},
+    identity::{self, AuthorizationRules, Identity},
+    routes::{
+        self,
+        error::{
+            BadRequestRejection, ConflictRejection, DatabaseErrorRejection, NotFoundRejection,
+        },
+    },
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+#[derive(Deserialize)]
+struct ErasureRequest {
+    identity: Option<String>,
+    prefix: Option<String>,
+}
+
+impl ErasureRequest {
+    /// Exactly one of an identity or a prefix must be given.
+    fn scope(self) -> Option<ErasureScope> {
+        match (self.identity, self.prefix) {
+            (Some(identity), None) if !identity.trim().is_empty() => {
+                Some(ErasureScope::Identity { identity })
+            }
+            // Prefixes are data paths, which always start with a period
+            (None, Some(prefix)) if prefix.starts_with('.') => {
+                Some(ErasureScope::Prefix { prefix })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn database_rejection(e: mongodb::error::Error) -> Rejection {
+    log::error!("An error occurred while tracking an erasure job: {}", e);
+    warp::reject::custom(DatabaseErrorRejection(e))
+}
+
+/// Erasure needs a key to sign receipts with, without one it is not available.
+fn available(
+    erasures: Option<Arc<ErasureStore>>,
+) -> impl Filter<Extract = (Arc<ErasureStore>,), Error = Rejection> + Clone {
+    warp::any().map(move || erasures.clone()).and_then(
+        |erasures: Option<Arc<ErasureStore>>| async move {
+            erasures.ok_or_else(|| warp::reject::custom(NotFoundRejection))
+        },
+    )
+}
+
+pub fn start(
+    erasures: Option<Arc<ErasureStore>>,
+    audit_log: Option<Arc<AuditLog>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "erasures")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<ErasureRequest>())
+        .and(warp::ext::optional::<Identity>())
+        .and(available(erasures))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |request: ErasureRequest,
+                  identity: Option<Identity>,
+                  erasures: Arc<ErasureStore>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let scope = request.scope();
+                let target = scope
+                    .as_ref()
+                    .map(|scope| scope.target().to_owned())
+                    .unwrap_or_else(|| "admin/erasures".to_owned());
+                let result = match scope {
+                    Some(scope) => erasures
+                        .start(
+                            scope,
+                            identity.as_ref().map(|identity| identity.to_string()),
+                        )
+                        .await
+                        .map_err(database_rejection),
+                    None => Err(warp::reject::custom(BadRequestRejection)),
+                };
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "start_erasure",
+                    &target,
+                    &result,
+                )
+                .await?;
+                result.map(|job| {
+                    warp::reply::with_status(warp::reply::json(&job), StatusCode::ACCEPTED)
+                })
+            },
+        )
+}
+
+pub fn get(
+    erasures: Option<Arc<ErasureStore>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "erasures" / String)
+        .and(identity::authorize_admin(authorization_rules))
+        .and(available(erasures))
+        .and_then(move |id: String, erasures: Arc<ErasureStore>| async move {
+            match erasures.get(&id).await.map_err(database_rejection)? {
+                Some(job) => Ok::<_, Rejection>(warp::reply::json(&job)),
+                None => Err(warp::reject::custom(NotFoundRejection)),
+            }
+        })
+}
+
+pub fn receipt(
+    erasures: Option<Arc<ErasureStore>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "erasures" / String / "receipt")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(available(erasures))
+        .and_then(move |id: String, erasures: Arc<ErasureStore>| async move {
+            let job = erasures
+                .get(&id)
+                .await
+                .map_err(database_rejection)?
+                .ok_or_else(|| warp::reject::custom(NotFoundRejection))?;
+            match (job.status, job.receipt) {
+                (ErasureStatus::Completed, Some(receipt)) => {
+                    Ok::<_, Rejection>(warp::reply::json(&receipt))
+                }
+                (ErasureStatus::Failed, _) => Err(warp::reject::custom(ConflictRejection {
+                    reason: format!(
+                        "erasure job {} failed: {}",
+                        id,
+                        job.error.unwrap_or_default()
+                    ),
+                })),
+                _ => Err(warp::reject::custom(ConflictRejection {
+                    reason: format!("erasure job {} has not completed yet", id),
+                })),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(body: &str) -> Option<ErasureScope> {
+        serde_json::from_str::<ErasureRequest>(body)
+            .unwrap()
+            .scope()
+    }
+
+    #[test]
+    fn scopes_an_identity() {
+        assert_eq!(
+            scope(r#"{"identity": "spiffe://example.org/client"}"#),
+            Some(ErasureScope::Identity {
+                identity: "spiffe://example.org/client".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn scopes_a_prefix() {
+        assert_eq!(
+            scope(r#"{"prefix": ".medical."}"#),
+            Some(ErasureScope::Prefix {
+                prefix: ".medical.".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn requires_exactly_one_target() {
+        assert_eq!(scope("{}"), None);
+        assert_eq!(
+            scope(r#"{"identity": "CN=client", "prefix": ".medical."}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_blank_identities() {
+        assert_eq!(scope(r#"{"identity": ""}"#), None);
+        assert_eq!(scope(r#"{"identity": "  "}"#), None);
+    }
+
+    #[test]
+    fn rejects_prefixes_that_are_not_data_paths() {
+        assert_eq!(scope(r#"{"prefix": ""}"#), None);
+        assert_eq!(scope(r#"{"prefix": "medical."}"#), None);
+    }
+}
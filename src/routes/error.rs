@@ -1,3 +1,4 @@
+use crate::{ca::CaError, history::HistoryError, policy::PolicyError, trash::TrashError};
 use redact_crypto::CryptoError;
 use warp::reject::Reject;
 use x509_parser::{error::X509Error, nom};
@@ -17,3 +18,58 @@ impl Reject for X509ErrorRejection {}
 #[derive(Debug)]
 pub struct NotFoundRejection;
 impl Reject for NotFoundRejection {}
+
+#[derive(Debug)]
+pub struct UnauthorizedRejection;
+impl Reject for UnauthorizedRejection {}
+
+#[derive(Debug)]
+pub struct ForbiddenRejection;
+impl Reject for ForbiddenRejection {}
+
+#[derive(Debug)]
+pub struct DeniedRejection {
+    pub identity: Option<String>,
+    pub path: String,
+}
+impl Reject for DeniedRejection {}
+
+#[derive(Debug)]
+pub struct ConflictRejection {
+    pub reason: String,
+}
+impl Reject for ConflictRejection {}
+
+#[derive(Debug)]
+pub struct CaErrorRejection(pub CaError);
+impl Reject for CaErrorRejection {}
+
+#[derive(Debug)]
+pub struct AuditErrorRejection(pub std::io::Error);
+impl Reject for AuditErrorRejection {}
+
+#[derive(Debug)]
+pub struct HistoryErrorRejection(pub HistoryError);
+impl Reject for HistoryErrorRejection {}
+
+#[derive(Debug)]
+pub struct DatabaseErrorRejection(pub mongodb::error::Error);
+impl Reject for DatabaseErrorRejection {}
+
+#[derive(Debug)]
+pub struct TrashErrorRejection(pub TrashError);
+impl Reject for TrashErrorRejection {}
+
+#[derive(Debug)]
+pub struct PolicyViolationRejection {
+    pub reason: String,
+}
+impl Reject for PolicyViolationRejection {}
+
+#[derive(Debug)]
+pub struct PolicyErrorRejection(pub PolicyError);
+impl Reject for PolicyErrorRejection {}
+
+#[derive(Debug)]
+pub struct MetricsErrorRejection(pub prometheus::Error);
+impl Reject for MetricsErrorRejection {}
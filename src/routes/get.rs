@@ -1,105 +1,515 @@
-use crate::routes::error::{
-    BadRequestRejection, CryptoErrorRejection, NotFoundRejection, X509ErrorRejection,
+use crate::{
+    audit::AuditLog,
+    expiry::ExpiryStore,
+    history::VersionStore,
+    identity::{self, AuthorizationRules, Identity},
+    metrics,
+    routes::{
+        self,
+        error::{
+            BadRequestRejection, CryptoErrorRejection, DatabaseErrorRejection,
+            HistoryErrorRejection, NotFoundRejection, UnauthorizedRejection, X509ErrorRejection,
+        },
+    },
 };
-use redact_crypto::{CryptoError, IndexedStorer, Type};
+use base64::{engine::general_purpose as b64_general_purpose, Engine};
+use chrono::{DateTime, Utc};
+use redact_crypto::{CryptoError, Entry, IndexedStorer, Type};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio_rustls::rustls::Certificate;
-use warp::{Filter, Rejection, Reply};
+use warp::{
+    reply::{Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+/// Entries in a page of a listing when no page size is given.
+const DEFAULT_PAGE_SIZE: i64 = 10;
 
 #[derive(Serialize, Deserialize)]
 struct GetQueryParams {
     skip: Option<u64>,
     page_size: Option<i64>,
+    /// Continues a listing from the `next_cursor` of its previous page
+    cursor: Option<String>,
+    version: Option<i64>,
+    as_of: Option<DateTime<Utc>>,
+}
+
+impl GetQueryParams {
+    /// Whether a page of entries under the path is asked for. Only `skip`
+    /// asks for one, as it always has; `page_size` and `cursor` shape it.
+    fn lists(&self) -> bool {
+        self.skip.is_some()
+    }
+
+    /// Rejects contradictory or out of range parameters.
+    fn validate(&self, max_page_size: i64) -> Result<(), Rejection> {
+        // Listing, reading a version and reading as of a time are exclusive
+        let modes = [self.lists(), self.version.is_some(), self.as_of.is_some()];
+        let invalid = modes.iter().filter(|mode| **mode).count() > 1
+            || (self.cursor.is_some() && !self.lists())
+            || self.version.is_some_and(|version| version < 1)
+            || self
+                .page_size
+                .is_some_and(|page_size| !(1..=max_page_size).contains(&page_size))
+            || self
+                .cursor
+                .as_deref()
+                .is_some_and(|cursor| ListCursor::decode(cursor).is_none());
+        if invalid {
+            Err(warp::reject::custom(BadRequestRejection))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Position in a listing, handed to callers as an opaque token. A cursor
+/// holds the last path returned along with how far into the storer's listing
+/// it was, which is only a hint: the listing continues after that path even
+/// if entries written or deleted since have moved it.
+#[derive(Serialize, Deserialize)]
+struct ListCursor {
+    after: String,
+    offset: u64,
+}
+
+impl ListCursor {
+    fn encode(&self) -> String {
+        // Serializing a struct of one string cannot fail
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        b64_general_purpose::URL_SAFE_NO_PAD.encode(json)
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = b64_general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
 }
 
 #[derive(Serialize)]
 struct GetCollectionResponse<T: Serialize> {
     results: Vec<T>,
+    /// Cursor of the next page, absent on the last one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+}
+
+/// Resolves any reference in an entry to the data it points at.
+async fn resolve(data_path: &str, data: Entry<Type>) -> Result<Entry<Type>, Rejection> {
+    match metrics::time_storer(metrics::BLOB, "dereference", data.dereference()).await {
+        Ok(data) => Ok(data),
+        Err(e) => {
+            if let CryptoError::NotFound { .. } = e {
+                Err(warp::reject::custom(NotFoundRejection))
+            } else {
+                log::error!(
+                    "An error occurred while dereferencing the entry at path {}: {}",
+                    data_path,
+                    e
+                );
+                Err(warp::reject::custom(CryptoErrorRejection(e)))
+            }
+        }
+    }
+}
+
+async fn dereferenced(data_path: &str, data: Entry<Type>) -> Result<WithStatus<Json>, Rejection> {
+    let data = resolve(data_path, data).await?;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&data),
+        warp::http::StatusCode::OK,
+    ))
+}
+
+/// Reads the current entry at a path, dereferenced, treating an expired
+/// entry as missing.
+pub async fn lookup<T: IndexedStorer>(
+    storer: &T,
+    expiries: &ExpiryStore,
+    data_path: &str,
+) -> Result<Entry<Type>, Rejection> {
+    let expired = expiries.is_expired(data_path).await.map_err(|e| {
+        log::error!(
+            "An error occurred while checking the expiry of the entry at path {}: {}",
+            data_path,
+            e
+        );
+        warp::reject::custom(DatabaseErrorRejection(e))
+    })?;
+    if expired {
+        return Err(warp::reject::custom(NotFoundRejection));
+    }
+
+    match metrics::time_storer(metrics::INDEXED, "get", storer.get::<Type>(data_path)).await {
+        Ok(data) => resolve(data_path, data).await,
+        Err(e) => {
+            if let CryptoError::NotFound { .. } = e {
+                Err(warp::reject::custom(NotFoundRejection))
+            } else {
+                log::error!(
+                    "An error occurred while retrieving the entry at path {}: {}",
+                    data_path,
+                    e
+                );
+                Err(warp::reject::custom(CryptoErrorRejection(e)))
+            }
+        }
+    }
+}
+
+/// Entries on either side of a cursor's offset that are searched for its
+/// path when a listing continues.
+const RESUME_WINDOW: u64 = 16;
+
+/// Most entries still to be skipped that are read along with a page.
+const SKIP_BATCH: u64 = 1000;
+
+/// Lists entries under a path through the storer, treating a listing that
+/// finds nothing as an empty one.
+async fn list<T: IndexedStorer>(
+    storer: &T,
+    data_path: &str,
+    skip: u64,
+    limit: i64,
+) -> Result<Vec<Entry<Type>>, Rejection> {
+    match metrics::time_storer(
+        metrics::INDEXED,
+        "list",
+        storer.list::<Type>(data_path, skip, limit),
+    )
+    .await
+    {
+        Ok(entries) => Ok(entries),
+        Err(CryptoError::NotFound { .. }) => Ok(vec![]),
+        Err(e) => {
+            log::error!(
+                "An error occurred while retrieving the entries list at path {}: {}",
+                data_path,
+                e
+            );
+            Err(warp::reject::custom(CryptoErrorRejection(e)))
+        }
+    }
+}
+
+/// Finds the offset a listing continues from after a cursor, by looking for
+/// the cursor's path around the offset it was handed out at. When the path
+/// is gone, the listing carries on from that offset.
+async fn resume<T: IndexedStorer>(
+    storer: &T,
+    data_path: &str,
+    cursor: &ListCursor,
+) -> Result<u64, Rejection> {
+    let start = cursor.offset.saturating_sub(RESUME_WINDOW);
+    let window = list(storer, data_path, start, 2 * RESUME_WINDOW as i64).await?;
+    Ok(window
+        .iter()
+        .position(|entry| entry.path == cursor.after)
+        .map_or(cursor.offset, |position| start + position as u64 + 1))
+}
+
+/// Reads a page of the entries under a path that the caller may read,
+/// along with the cursor of the next page if there is one. Expired entries
+/// and entries under a stricter read rule are left out before `skip` is
+/// counted, and more are read in their place until the page is full.
+#[allow(clippy::too_many_arguments)]
+async fn page<T: IndexedStorer>(
+    storer: &T,
+    expiries: &ExpiryStore,
+    authorization_rules: &AuthorizationRules,
+    identity: Option<&Identity>,
+    data_path: &str,
+    mut skip: u64,
+    cursor: Option<&ListCursor>,
+    page_size: i64,
+) -> Result<(Vec<Entry<Type>>, Option<String>), Rejection> {
+    let mut offset = match cursor {
+        Some(cursor) => resume(storer, data_path, cursor).await?,
+        None => 0,
+    };
+
+    let mut results: Vec<Entry<Type>> = vec![];
+    let mut more = true;
+    while more && (results.len() as i64) < page_size {
+        // Entries left to skip are read along with the page, since they can
+        // only be told apart once the hidden ones are left out
+        let wanted = page_size - results.len() as i64 + skip.min(SKIP_BATCH) as i64;
+        // One entry past what is wanted tells whether there is more to read
+        let mut entries = list(storer, data_path, offset, wanted + 1).await?;
+        more = entries.len() as i64 > wanted;
+        entries.truncate(wanted as usize);
+        offset += entries.len() as u64;
+
+        // Expired entries stay in the storer until the sweeper purges them
+        let paths: Vec<String> = entries.iter().map(|entry| entry.path.clone()).collect();
+        let expired = expiries.expired_among(&paths).await.map_err(|e| {
+            log::error!(
+                "An error occurred while checking the expiry of entries at path {}: {}",
+                data_path,
+                e
+            );
+            warp::reject::custom(DatabaseErrorRejection(e))
+        })?;
+        let visible = entries
+            .into_iter()
+            .filter(|entry| !expired.contains(&entry.path))
+            .filter(|entry| {
+                identity
+                    .is_some_and(|identity| authorization_rules.permits_read(identity, &entry.path))
+            });
+        for entry in visible {
+            if skip > 0 {
+                skip -= 1;
+            } else {
+                results.push(entry);
+            }
+        }
+    }
+
+    // Every entry read for a full page was returned, so the offset is just
+    // past the last one, which the caller may read
+    let next_cursor = if more {
+        results.last().map(|entry| {
+            ListCursor {
+                after: entry.path.clone(),
+                offset,
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+    Ok((results, next_cursor))
 }
 
 pub fn get<T: IndexedStorer>(
     storer: Arc<T>,
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    authorization_rules: Arc<AuthorizationRules>,
+    max_page_size: i64,
+    audit_log: Option<Arc<AuditLog>>,
 ) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!(String)
         .map(|data_path| data_path)
         .and(
-            warp::query::<GetQueryParams>().and_then(|query: GetQueryParams| async move {
-                if let Some(page_size) = query.page_size {
-                    if page_size > 100 {
-                        Err(warp::reject::custom(BadRequestRejection))
-                    } else {
-                        Ok(query)
-                    }
-                } else {
-                    Ok(query)
-                }
+            warp::query::<GetQueryParams>().and_then(move |query: GetQueryParams| async move {
+                query.validate(max_page_size)?;
+                Ok::<_, Rejection>(query)
             }),
         )
-        .and(warp::ext::get::<Certificate>())
+        .and(
+            // Callers identified through XFCC may have forwarded no certificate
+            warp::ext::optional::<Certificate>().and_then(|cert: Option<Certificate>| async move {
+                cert.ok_or_else(|| warp::reject::custom(UnauthorizedRejection))
+            }),
+        )
+        .and(warp::ext::optional::<Identity>())
         .and(warp::any().map(move || storer.clone()))
+        .and(warp::any().map(move || versions.clone()))
+        .and(warp::any().map(move || expiries.clone()))
+        .and(warp::any().map(move || authorization_rules.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
         .and_then(
             move |data_path: String,
             query: GetQueryParams,
             client_cert: Certificate,
-            storer: Arc<T>| async move {
-                let x509_result = x509_parser::parse_x509_certificate(&client_cert.0);
-                let _ = match x509_result {
-                    Ok((_, cert)) => Ok(cert),
-                    Err(e) => Err(warp::reject::custom(X509ErrorRejection(e))),
-                }?;
-
-                if let Some(skip) = query.skip {
-                    let page_size = if let Some(page_size) = query.page_size {
-                        page_size
-                    } else {
-                        10
-                    };
+            identity: Option<Identity>,
+            storer: Arc<T>,
+            versions: Arc<VersionStore>,
+            expiries: Arc<ExpiryStore>,
+            authorization_rules: Arc<AuthorizationRules>,
+            audit_log: Option<Arc<AuditLog>>| async move {
+                let operation = if query.lists() {
+                    "list"
+                } else if query.version.is_some() || query.as_of.is_some() {
+                    "get_version"
+                } else {
+                    "get"
+                };
+                let result = async {
+                    let x509_result = x509_parser::parse_x509_certificate(&client_cert.0);
+                    let _ = match x509_result {
+                        Ok((_, cert)) => Ok(cert),
+                        Err(e) => Err(warp::reject::custom(X509ErrorRejection(e))),
+                    }?;
+                    identity::check_read(&authorization_rules, identity.as_ref(), &data_path)?;
 
-                    match storer.list::<Type>(&data_path, skip, page_size).await {
-                        Ok(results) => Ok::<_, Rejection>(warp::reply::with_status(
-                            warp::reply::json(&GetCollectionResponse { results }),
+                    if query.lists() {
+                        let page_size = query.page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+                        let cursor = query.cursor.as_deref().and_then(ListCursor::decode);
+                        // A cursor only continues the listing it came from
+                        if cursor
+                            .as_ref()
+                            .is_some_and(|cursor| !cursor.after.starts_with(&data_path))
+                        {
+                            return Err(warp::reject::custom(BadRequestRejection));
+                        }
+
+                        let (results, next_cursor) = page(
+                            storer.as_ref(),
+                            &expiries,
+                            &authorization_rules,
+                            identity.as_ref(),
+                            &data_path,
+                            query.skip.unwrap_or_default(),
+                            cursor.as_ref(),
+                            page_size,
+                        )
+                        .await?;
+
+                        Ok::<_, Rejection>(warp::reply::with_status(
+                            warp::reply::json(&GetCollectionResponse { results, next_cursor }),
                             warp::http::StatusCode::OK,
-                        )),
-                        Err(e) => {
-                            if let CryptoError::NotFound { .. } = e {
-                                Err(warp::reject::custom(NotFoundRejection))
-                            } else {
-                                log::error!("An error occurred while retrieving the entries list at path {}: {}", data_path, e);
-                                Err(warp::reject::custom(CryptoErrorRejection(e)))
-                            }
+                        ))
+                    } else if query.version.is_some() || query.as_of.is_some() {
+                        let expired = expiries.is_expired(&data_path).await.map_err(|e| {
+                            log::error!("An error occurred while checking the expiry of the entry at path {}: {}", data_path, e);
+                            warp::reject::custom(DatabaseErrorRejection(e))
+                        })?;
+                        if expired {
+                            return Err(warp::reject::custom(NotFoundRejection));
                         }
-                    }
-                } else {
-                    match storer.get::<Type>(&data_path).await {
-                        Ok(data) => {
-                            let dereferenced_data = data.dereference().await;
-
-                            match dereferenced_data {
-                                Ok(data) => Ok::<_, Rejection>(warp::reply::with_status(
-                                    warp::reply::json(&data),
-                                    warp::http::StatusCode::OK,
-                                )),
-                                Err(e) => {
-                                    if let CryptoError::NotFound { .. } = e {
-                                        Err(warp::reject::custom(NotFoundRejection))
-                                    } else {
-                                        log::error!("An error occurred while dereferencing the entry at path {}: {}", data_path, e);
-                                        Err(warp::reject::custom(CryptoErrorRejection(e)))
-                                    }
-                                }
+
+                        let version = match (query.version, query.as_of) {
+                            (Some(version), _) => {
+                                metrics::time_storer(metrics::HISTORY, "get", versions.get(&data_path, version)).await
                             }
-                        }
-                        Err(e) => {
-                            if let CryptoError::NotFound { .. } = e {
-                                Err(warp::reject::custom(NotFoundRejection))
-                            } else {
-                                log::error!("An error occurred while retrieving the entry at path {}: {}", data_path, e);
-                                Err(warp::reject::custom(CryptoErrorRejection(e)))
+                            (None, Some(as_of)) => {
+                                metrics::time_storer(metrics::HISTORY, "as_of", versions.as_of(&data_path, as_of)).await
                             }
+                            (None, None) => Ok(None),
+                        }
+                        .and_then(|version| version.map(|version| version.entry()).transpose())
+                        .map_err(|e| {
+                            log::error!("An error occurred while retrieving a version of the entry at path {}: {}", data_path, e);
+                            warp::reject::custom(HistoryErrorRejection(e))
+                        })?;
+
+                        match version.flatten() {
+                            Some(data) => dereferenced(&data_path, data).await,
+                            None => Err(warp::reject::custom(NotFoundRejection)),
                         }
+                    } else {
+                        let data = lookup(storer.as_ref(), &expiries, &data_path).await?;
+                        Ok(warp::reply::with_status(
+                            warp::reply::json(&data),
+                            warp::http::StatusCode::OK,
+                        ))
                     }
                 }
+                .await;
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    operation,
+                    &data_path,
+                    &result,
+                )
+                .await?;
+                result
             },
         )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_PAGE_SIZE: i64 = 100;
+
+    async fn query(query: &str) -> GetQueryParams {
+        warp::test::request()
+            .path(&format!("/?{}", query))
+            .filter(&warp::query::<GetQueryParams>())
+            .await
+            .unwrap()
+    }
+
+    async fn is_valid(params: &str) -> bool {
+        query(params).await.validate(MAX_PAGE_SIZE).is_ok()
+    }
+
+    fn cursor(after: &str) -> String {
+        ListCursor {
+            after: after.to_owned(),
+            offset: 3,
+        }
+        .encode()
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        let decoded = ListCursor::decode(&cursor(".notes.a")).unwrap();
+
+        assert_eq!(decoded.after, ".notes.a");
+        assert_eq!(decoded.offset, 3);
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        assert!(ListCursor::decode("not base64!").is_none());
+        assert!(ListCursor::decode(&b64_general_purpose::URL_SAFE_NO_PAD.encode("{}")).is_none());
+    }
+
+    #[tokio::test]
+    async fn accepts_each_mode_on_its_own() {
+        assert!(is_valid("").await);
+        assert!(is_valid("skip=10&page_size=20").await);
+        assert!(
+            is_valid(&format!(
+                "skip=0&cursor={}&page_size=20",
+                cursor(".notes.a")
+            ))
+            .await
+        );
+        assert!(is_valid("version=1").await);
+        assert!(is_valid("as_of=2023-01-01T00:00:00Z").await);
+    }
+
+    #[tokio::test]
+    async fn lists_only_when_skip_is_given() {
+        assert!(!query("").await.lists());
+        assert!(!query("version=2").await.lists());
+        assert!(!query("page_size=5").await.lists());
+        assert!(query("skip=0").await.lists());
+        assert!(query(&format!("skip=0&cursor={}", cursor(".notes.a")))
+            .await
+            .lists());
+    }
+
+    #[tokio::test]
+    async fn ignores_a_page_size_outside_listings() {
+        assert!(is_valid("page_size=5").await);
+        assert!(is_valid("page_size=5&version=1").await);
+    }
+
+    #[tokio::test]
+    async fn rejects_combined_modes() {
+        assert!(!is_valid("skip=0&version=1").await);
+        assert!(!is_valid("skip=5&as_of=2023-01-01T00:00:00Z").await);
+        assert!(!is_valid("version=1&as_of=2023-01-01T00:00:00Z").await);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_cursor_outside_listings() {
+        assert!(!is_valid(&format!("cursor={}", cursor(".notes.a"))).await);
+        assert!(is_valid(&format!("skip=5&cursor={}", cursor(".notes.a"))).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_out_of_range_values() {
+        assert!(!is_valid("version=0").await);
+        assert!(!is_valid("page_size=0").await);
+        assert!(!is_valid(&format!("page_size={}", MAX_PAGE_SIZE + 1)).await);
+        assert!(is_valid(&format!("page_size={}", MAX_PAGE_SIZE)).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_cursors() {
+        assert!(!is_valid("cursor=nonsense").await);
+    }
+}
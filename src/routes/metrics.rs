@@ -0,0 +1,15 @@
+use crate::{metrics, routes::error::MetricsErrorRejection};
+use warp::{Filter, Rejection, Reply};
+
+pub fn metrics() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("metrics").and(warp::get()).and_then(|| async {
+        match metrics::render() {
+            Ok(body) => Ok(warp::reply::with_header(
+                body,
+                "content-type",
+                "text/plain; version=0.0.4",
+            )),
+            Err(e) => Err(warp::reject::custom(MetricsErrorRejection(e))),
+        }
+    })
+}
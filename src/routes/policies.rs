@@ -0,0 +1,223 @@
+use crate::{
+    audit::AuditLog,
+    identity::{self, AuthorizationRules, Identity},
+    policy::{LegalHold, PolicyStore, Retention, RetentionRule},
+    routes::{
+        self,
+        error::{BadRequestRejection, DatabaseErrorRejection, NotFoundRejection},
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Deserialize)]
+struct RetentionRuleRequest {
+    prefix: String,
+    #[serde(flatten)]
+    retention: Retention,
+}
+
+#[derive(Deserialize)]
+struct LegalHoldRequest {
+    prefix: String,
+    reason: String,
+}
+
+#[derive(Serialize)]
+struct PoliciesResponse {
+    retention_rules: Vec<RetentionRule>,
+    legal_holds: Vec<LegalHold>,
+}
+
+/// Prefixes are data paths, which always start with a period.
+fn valid_prefix(prefix: &str) -> bool {
+    prefix.starts_with('.')
+}
+
+fn database_rejection(e: mongodb::error::Error) -> Rejection {
+    log::error!("An error occurred while updating policies: {}", e);
+    warp::reject::custom(DatabaseErrorRejection(e))
+}
+
+pub fn list(
+    policies: Arc<PolicyStore>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "policies")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::any().map(move || policies.clone()))
+        .and_then(move |policies: Arc<PolicyStore>| async move {
+            let retention_rules = policies.rules().await.map_err(database_rejection)?;
+            let legal_holds = policies.holds().await.map_err(database_rejection)?;
+            Ok::<_, Rejection>(warp::reply::json(&PoliciesResponse {
+                retention_rules,
+                legal_holds,
+            }))
+        })
+}
+
+pub fn add_rule(
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "policies" / "retention")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<RetentionRuleRequest>())
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || policies.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |request: RetentionRuleRequest,
+                  identity: Option<Identity>,
+                  policies: Arc<PolicyStore>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let days = match request.retention {
+                    Retention::KeepFor { days } | Retention::DeleteAfter { days } => days,
+                };
+                let result = if !valid_prefix(&request.prefix) || days < 1 {
+                    Err(warp::reject::custom(BadRequestRejection))
+                } else {
+                    policies
+                        .add_rule(
+                            request.prefix.clone(),
+                            request.retention,
+                            identity.as_ref().map(|identity| identity.to_string()),
+                        )
+                        .await
+                        .map_err(database_rejection)
+                };
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "add_retention_rule",
+                    &request.prefix,
+                    &result,
+                )
+                .await?;
+                result.map(|rule| warp::reply::json(&rule))
+            },
+        )
+}
+
+pub fn remove_rule(
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "policies" / "retention" / String)
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || policies.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |id: String,
+                  identity: Option<Identity>,
+                  policies: Arc<PolicyStore>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let result = match policies.remove_rule(&id).await {
+                    Ok(Some(rule)) => Ok(rule),
+                    Ok(None) => Err(warp::reject::custom(NotFoundRejection)),
+                    Err(e) => Err(database_rejection(e)),
+                };
+
+                let prefix = result
+                    .as_ref()
+                    .map(|rule| rule.prefix.clone())
+                    .unwrap_or_else(|_| format!("admin/policies/retention/{}", id));
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "remove_retention_rule",
+                    &prefix,
+                    &result,
+                )
+                .await?;
+                result.map(|rule| warp::reply::json(&rule))
+            },
+        )
+}
+
+pub fn place_hold(
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "policies" / "holds")
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json::<LegalHoldRequest>())
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || policies.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |request: LegalHoldRequest,
+                  identity: Option<Identity>,
+                  policies: Arc<PolicyStore>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let result = if !valid_prefix(&request.prefix) || request.reason.trim().is_empty() {
+                    Err(warp::reject::custom(BadRequestRejection))
+                } else {
+                    policies
+                        .place_hold(
+                            request.prefix.clone(),
+                            request.reason,
+                            identity.as_ref().map(|identity| identity.to_string()),
+                        )
+                        .await
+                        .map_err(database_rejection)
+                };
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "place_legal_hold",
+                    &request.prefix,
+                    &result,
+                )
+                .await?;
+                result.map(|hold| warp::reply::json(&hold))
+            },
+        )
+}
+
+pub fn release_hold(
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+    authorization_rules: Arc<AuthorizationRules>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "policies" / "holds" / String)
+        .and(identity::authorize_admin(authorization_rules))
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || policies.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |id: String,
+                  identity: Option<Identity>,
+                  policies: Arc<PolicyStore>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let result = match policies.release_hold(&id).await {
+                    Ok(Some(hold)) => Ok(hold),
+                    Ok(None) => Err(warp::reject::custom(NotFoundRejection)),
+                    Err(e) => Err(database_rejection(e)),
+                };
+
+                let prefix = result
+                    .as_ref()
+                    .map(|hold| hold.prefix.clone())
+                    .unwrap_or_else(|_| format!("admin/policies/holds/{}", id));
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "release_legal_hold",
+                    &prefix,
+                    &result,
+                )
+                .await?;
+                result.map(|hold| warp::reply::json(&hold))
+            },
+        )
+}
@@ -1,78 +1,473 @@
-use crate::routes::error::CryptoErrorRejection;
+use crate::{
+    audit::AuditLog,
+    expiry::ExpiryStore,
+    history::{self, VersionStore},
+    identity::Identity,
+    index::EntryIndex,
+    metrics,
+    policy::{self, PolicyStore},
+    routes::{
+        self,
+        error::{
+            BadRequestRejection, CryptoErrorRejection, DatabaseErrorRejection,
+            HistoryErrorRejection,
+        },
+    },
+};
+use bson::Document;
+use chrono::{DateTime, Duration, Utc};
 use redact_crypto::{Data, DataBuilder, Entry, State, Storer, Type, TypeBuilder, TypeStorer};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use warp::{Filter, Rejection, Reply};
 
+#[derive(Deserialize)]
+struct CreateQueryParams {
+    /// Seconds after which the entry expires
+    ttl: Option<i64>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl CreateQueryParams {
+    /// Works out when the entry expires, if it was given a TTL or expiry.
+    fn expires_at(&self) -> Result<Option<DateTime<Utc>>, Rejection> {
+        match (self.ttl, self.expires_at) {
+            (Some(_), Some(_)) => Err(warp::reject::custom(BadRequestRejection)),
+            (Some(ttl), None) if ttl < 1 => Err(warp::reject::custom(BadRequestRejection)),
+            // Plain duration arithmetic panics on overflow, so huge TTLs are refused instead
+            (Some(ttl), None) => ttl
+                .checked_mul(1000)
+                .and_then(|millis| Utc::now().checked_add_signed(Duration::milliseconds(millis)))
+                .map(Some)
+                .ok_or_else(|| warp::reject::custom(BadRequestRejection)),
+            (None, Some(expires_at)) if expires_at <= Utc::now() => {
+                Err(warp::reject::custom(BadRequestRejection))
+            }
+            (None, expires_at) => Ok(expires_at),
+        }
+    }
+}
+
+/// Extracts when written entries expire from the `ttl` or `expires_at` query parameter.
+pub fn expiry() -> impl Filter<Extract = (Option<DateTime<Utc>>,), Error = Rejection> + Clone {
+    warp::query::<CreateQueryParams>()
+        .and_then(|query: CreateQueryParams| async move { query.expires_at() })
+}
+
 #[derive(Serialize)]
 struct CreateResponse {
     success: bool,
     msg: String,
 }
 
-pub fn create<T: Storer>(
+/// The state of a path before a write, kept to undo the write.
+pub struct Prior {
+    path: String,
+    entry: Option<Document>,
+    version: Option<i64>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Writes entries as new versions of their paths, routing binary data to
+/// the blob storer and everything else to the indexed storer.
+pub struct Writer<T: Storer> {
     storer: Arc<T>,
     blob_storer: Arc<TypeStorer>,
-) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
-    warp::path::end()
-        .and(warp::body::content_length_limit(1024 * 1024 * 250))
-        .and(warp::body::json::<Entry<Type>>())
-        .and(warp::any().map(move || storer.clone()))
-        .and(warp::any().map(move || blob_storer.clone()))
-        .and_then(move |entry: Entry<Type>, storer: Arc<T>, blob_storer: Arc<TypeStorer>| async move {
-            let entry_path = entry.path.clone();
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    index: Arc<EntryIndex>,
+    policies: Arc<PolicyStore>,
+}
+
+impl<T: Storer> Writer<T> {
+    pub fn new(
+        storer: Arc<T>,
+        blob_storer: Arc<TypeStorer>,
+        versions: Arc<VersionStore>,
+        expiries: Arc<ExpiryStore>,
+        index: Arc<EntryIndex>,
+        policies: Arc<PolicyStore>,
+    ) -> Self {
+        Writer {
+            storer,
+            blob_storer,
+            versions,
+            expiries,
+            index,
+            policies,
+        }
+    }
+
+    /// Checks a write to a path against the reserved paths, retention rules
+    /// and legal holds, which may refuse it or shorten its expiry. Returns the expiry the
+    /// entry must be written with.
+    pub async fn check(
+        &self,
+        path: &str,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Option<DateTime<Utc>>, Rejection> {
+        check_path(path)?;
+        let current = policy::written_at(&self.index, &self.versions, path)
+            .await
+            .map_err(routes::policy_rejection)?;
+        self.policies
+            .check_write(path, current, expires_at)
+            .await
+            .map_err(routes::policy_rejection)
+    }
+
+    /// Writes a checked entry as a new version of its path, returning the
+    /// version number.
+    pub async fn write(
+        &self,
+        entry: Entry<Type>,
+        expires_at: Option<DateTime<Utc>>,
+        created_by: Option<String>,
+        content_length: u64,
+    ) -> Result<i64, Rejection> {
+        let entry_path = entry.path.clone();
+        let binary = matches!(entry.builder, TypeBuilder::Data(DataBuilder::Binary(_)));
+        let version = metrics::time_storer(
+            metrics::HISTORY,
+            "reserve",
+            self.versions.reserve(&entry_path, created_by, binary),
+        )
+        .await
+        .map_err(|e| {
+            log::error!(
+                "An error occurred while reserving a version of path {}: {}",
+                entry_path,
+                e
+            );
+            warp::reject::custom(HistoryErrorRejection(e))
+        })?;
 
+        let written = async {
             match entry.builder {
-                TypeBuilder::Data(d) => {
-                    match d {
-                        DataBuilder::Binary(_) => {
-                            let ref_entry: Entry<Data> = Entry::new(entry.path.clone(), entry.builder, State::Referenced {
-                                path: entry.path.clone(),
-                                storer: (*blob_storer).clone()
-                            });
-
-                            // TODO: orchestration
-                            blob_storer
-                                .create(entry)
-                                .await
-                                .map_err(|e| {
-                                    log::error!("An error occurred while uploading binary data to blob storage at path {}: {}", entry_path, e);
-                                    warp::reject::custom(CryptoErrorRejection(e))
-                                })?;
-
-                            storer
-                                .create(ref_entry)
-                                .await
-                                .map_err(|e| {
-                                    log::error!("An error occurred while creating binary data reference {}: {}", entry_path, e);
-                                    warp::reject::custom(CryptoErrorRejection(e))
-                                })?;
-                        }
-                        _ => {
-                            storer
-                                .create(entry)
-                                .await
-                                .map_err(|e| {
-                                    log::error!("An error occurred while creating data entry at path {}: {}", entry_path, e);
-                                    warp::reject::custom(CryptoErrorRejection(e))
-                                })?;
-                        }
-                    }
-                },
+                TypeBuilder::Data(DataBuilder::Binary(_)) => {
+                    // Each version's data gets its own blob so that overwrites keep the old one
+                    let blob_path = version
+                        .blob_path
+                        .clone()
+                        .unwrap_or_else(|| entry.path.clone());
+                    let ref_entry: Entry<Data> = Entry::new(
+                        entry.path.clone(),
+                        entry.builder,
+                        State::Referenced {
+                            path: blob_path.clone(),
+                            storer: (*self.blob_storer).clone(),
+                        },
+                    );
+                    let snapshot = history::snapshot(&ref_entry)
+                        .map_err(|e| warp::reject::custom(HistoryErrorRejection(e)))?;
+                    let mut blob_entry = entry;
+                    blob_entry.path = blob_path;
+
+                    // TODO: orchestration
+                    metrics::uploaded(metrics::BLOB, content_length);
+                    metrics::time_storer(metrics::BLOB, "create", self.blob_storer.create(blob_entry))
+                        .await
+                        .map_err(|e| {
+                            log::error!("An error occurred while uploading binary data to blob storage at path {}: {}", entry_path, e);
+                            warp::reject::custom(CryptoErrorRejection(e))
+                        })?;
+
+                    metrics::time_storer(metrics::INDEXED, "create", self.storer.create(ref_entry))
+                        .await
+                        .map_err(|e| {
+                            log::error!("An error occurred while creating binary data reference {}: {}", entry_path, e);
+                            warp::reject::custom(CryptoErrorRejection(e))
+                        })?;
+                    Ok::<_, Rejection>(snapshot)
+                }
                 _ => {
-                    storer
-                        .create(entry)
+                    let snapshot = history::snapshot(&entry)
+                        .map_err(|e| warp::reject::custom(HistoryErrorRejection(e)))?;
+                    metrics::uploaded(metrics::INDEXED, content_length);
+                    metrics::time_storer(metrics::INDEXED, "create", self.storer.create(entry))
                         .await
                         .map_err(|e| {
-                            log::error!("An error occurred while creating entry at path {}: {}", entry_path, e);
+                            log::error!(
+                                "An error occurred while creating entry at path {}: {}",
+                                entry_path,
+                                e
+                            );
                             warp::reject::custom(CryptoErrorRejection(e))
                         })?;
+                    Ok(snapshot)
+                }
+            }
+        }
+        .await;
+
+        match written {
+            Ok(snapshot) => metrics::time_storer(
+                metrics::HISTORY,
+                "commit",
+                self.versions.commit(&version, snapshot),
+            )
+            .await
+            .map_err(|e| {
+                log::error!(
+                    "An error occurred while recording version {} of path {}: {}",
+                    version.version,
+                    entry_path,
+                    e
+                );
+                warp::reject::custom(HistoryErrorRejection(e))
+            })?,
+            Err(rejection) => {
+                if let Err(e) = self.versions.abandon(&version).await {
+                    log::error!(
+                        "An error occurred while releasing version {} of path {}: {}",
+                        version.version,
+                        entry_path,
+                        e
+                    );
                 }
-	    }
+                return Err(rejection);
+            }
+        }
+
+        self.expiries
+            .set(&entry_path, version.version, expires_at)
+            .await
+            .map_err(|e| {
+                log::error!(
+                    "An error occurred while setting the expiry of path {}: {}",
+                    entry_path,
+                    e
+                );
+                warp::reject::custom(DatabaseErrorRejection(e))
+            })?;
+        Ok(version.version)
+    }
 
-            Ok::<_, Rejection>(warp::reply::json(&CreateResponse {
-                success: true,
-                msg: "inserted".to_owned(),
-            }))
+    /// Reads the state of a path before writing to it.
+    pub async fn prior(&self, path: &str) -> Result<Prior, Rejection> {
+        let database_rejection = |e| {
+            log::error!(
+                "An error occurred while reading the entry at path {}: {}",
+                path,
+                e
+            );
+            warp::reject::custom(DatabaseErrorRejection(e))
+        };
+        let entry = self.index.get(path).await.map_err(database_rejection)?;
+        let expires_at = self
+            .expiries
+            .expires_at(path)
+            .await
+            .map_err(database_rejection)?;
+        let version = self
+            .versions
+            .current(path)
+            .await
+            .map_err(|e| warp::reject::custom(HistoryErrorRejection(e)))?
+            .map(|current| current.version);
+        Ok(Prior {
+            path: path.to_owned(),
+            entry,
+            version,
+            expires_at,
         })
+    }
+
+    /// Undoes the write of a version, putting its path back to the state it
+    /// was in before. The previous value is only readable again while its
+    /// version is retained, which `history.max_versions` of 1 prevents.
+    pub async fn undo(&self, prior: &Prior, version: i64) -> Result<(), Rejection> {
+        let database_rejection = |e| {
+            log::error!(
+                "An error occurred while undoing the write of path {}: {}",
+                prior.path,
+                e
+            );
+            warp::reject::custom(DatabaseErrorRejection(e))
+        };
+        // The entry goes back first so that it never refers to the discarded blob
+        self.index
+            .restore(&prior.path, prior.entry.clone())
+            .await
+            .map_err(database_rejection)?;
+        match prior.version {
+            Some(previous) => self
+                .expiries
+                .set(&prior.path, previous, prior.expires_at)
+                .await
+                .map_err(database_rejection)?,
+            None => self
+                .expiries
+                .clear(&prior.path)
+                .await
+                .map_err(database_rejection)?,
+        }
+        self.versions
+            .discard(&prior.path, version)
+            .await
+            .map_err(|e| warp::reject::custom(HistoryErrorRejection(e)))
+    }
+}
+
+pub fn create<T: Storer>(
+    storer: Arc<T>,
+    blob_storer: Arc<TypeStorer>,
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    index: Arc<EntryIndex>,
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let writer = Arc::new(Writer::new(
+        storer,
+        blob_storer,
+        versions,
+        expiries,
+        index,
+        policies,
+    ));
+    warp::path::end()
+        .and(expiry())
+        .and(warp::body::content_length_limit(1024 * 1024 * 250))
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::body::json::<Entry<Type>>())
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || writer.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |expires_at: Option<DateTime<Utc>>,
+                  content_length: Option<u64>,
+                  entry: Entry<Type>,
+                  identity: Option<Identity>,
+                  writer: Arc<Writer<T>>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let entry_path = entry.path.clone();
+
+                let result = async {
+                    let expires_at = writer.check(&entry_path, expires_at).await?;
+                    writer
+                        .write(
+                            entry,
+                            expires_at,
+                            identity.as_ref().map(|identity| identity.to_string()),
+                            content_length.unwrap_or_default(),
+                        )
+                        .await?;
+                    Ok::<_, Rejection>(warp::reply::json(&CreateResponse {
+                        success: true,
+                        msg: "inserted".to_owned(),
+                    }))
+                }
+                .await;
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "create",
+                    &entry_path,
+                    &result,
+                )
+                .await?;
+                result
+            },
+        )
+}
+
+/// Refuses writes to paths the store keeps its own data under, which would
+/// otherwise overwrite the binary data of past versions.
+fn check_path(path: &str) -> Result<(), Rejection> {
+    if history::is_blob_path(path) {
+        Err(warp::reject::custom(BadRequestRejection))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(ttl: Option<i64>, expires_at: Option<DateTime<Utc>>) -> CreateQueryParams {
+        CreateQueryParams { ttl, expires_at }
+    }
+
+    fn is_bad_request(result: Result<Option<DateTime<Utc>>, Rejection>) -> bool {
+        result.is_err_and(|rejection| rejection.find::<BadRequestRejection>().is_some())
+    }
+
+    #[test]
+    fn entries_without_a_ttl_or_expiry_do_not_expire() {
+        assert_eq!(params(None, None).expires_at().unwrap(), None);
+    }
+
+    #[test]
+    fn ttl_counts_from_now() {
+        let before = Utc::now();
+        let expires_at = params(Some(60), None).expires_at().unwrap().unwrap();
+
+        assert!(expires_at >= before + Duration::seconds(60));
+        assert!(expires_at <= Utc::now() + Duration::seconds(60));
+    }
+
+    #[test]
+    fn expiries_in_the_future_are_kept() {
+        let expires_at = Utc::now() + Duration::days(1);
+
+        assert_eq!(
+            params(None, Some(expires_at)).expires_at().unwrap(),
+            Some(expires_at)
+        );
+    }
+
+    #[test]
+    fn rejects_ttl_and_expiry_together() {
+        let expires_at = Utc::now() + Duration::days(1);
+
+        assert!(is_bad_request(
+            params(Some(60), Some(expires_at)).expires_at()
+        ));
+    }
+
+    #[test]
+    fn rejects_non_positive_ttls() {
+        assert!(is_bad_request(params(Some(0), None).expires_at()));
+        assert!(is_bad_request(params(Some(-1), None).expires_at()));
+    }
+
+    #[test]
+    fn rejects_ttls_past_the_latest_timestamp() {
+        assert!(is_bad_request(params(Some(i64::MAX), None).expires_at()));
+        assert!(is_bad_request(
+            params(Some(i64::MAX / 1000), None).expires_at()
+        ));
+    }
+
+    #[test]
+    fn rejects_expiries_in_the_past() {
+        let expires_at = Utc::now() - Duration::seconds(1);
+
+        assert!(is_bad_request(params(None, Some(expires_at)).expires_at()));
+    }
+
+    #[tokio::test]
+    async fn expiry_is_read_from_the_query() {
+        let expires_at = warp::test::request()
+            .path("/?expires_at=2999-01-01T00:00:00Z")
+            .filter(&expiry())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            expires_at,
+            Some("2999-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_writes_to_version_blob_paths() {
+        let rejection = check_path(&history::blob_path(".a.", 1)).unwrap_err();
+
+        assert!(rejection.find::<BadRequestRejection>().is_some());
+        assert!(check_path(".a.").is_ok());
+    }
 }
@@ -0,0 +1,24 @@
+use crate::backends::ReadinessProbe;
+use std::{convert::Infallible, sync::Arc};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+pub fn readyz(
+    probe: Arc<ReadinessProbe>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("-" / "readyz")
+        .and(warp::get())
+        .and(warp::any().map(move || probe.clone()))
+        .and_then(move |probe: Arc<ReadinessProbe>| async move {
+            let readiness = probe.check().await;
+            let status = if readiness.ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+
+            Ok::<_, Infallible>(warp::reply::with_status(
+                warp::reply::json(readiness.as_ref()),
+                status,
+            ))
+        })
+}
@@ -0,0 +1,244 @@
+use crate::{
+    audit::AuditLog,
+    expiry::ExpiryStore,
+    history::VersionStore,
+    identity::{self, AuthorizationRules, Identity},
+    index::EntryIndex,
+    policy::{self, PolicyStore},
+    routes::{
+        self,
+        error::{
+            BadRequestRejection, ConflictRejection, DatabaseErrorRejection, NotFoundRejection,
+            TrashErrorRejection,
+        },
+    },
+    trash::{TrashError, TrashStore, TrashSummary},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Deserialize)]
+struct TrashQueryParams {
+    /// Only list items whose path starts with this prefix
+    path: Option<String>,
+    skip: Option<u64>,
+    page_size: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct TrashCollectionResponse<T: Serialize> {
+    results: Vec<T>,
+}
+
+/// Moves the entry at a path into the trash.
+pub fn delete(
+    trash: Arc<TrashStore>,
+    expiries: Arc<ExpiryStore>,
+    index: Arc<EntryIndex>,
+    versions: Arc<VersionStore>,
+    policies: Arc<PolicyStore>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!(String)
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || trash.clone()))
+        .and(warp::any().map(move || expiries.clone()))
+        .and(warp::any().map(move || index.clone()))
+        .and(warp::any().map(move || versions.clone()))
+        .and(warp::any().map(move || policies.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |data_path: String,
+                  identity: Option<Identity>,
+                  trash: Arc<TrashStore>,
+                  expiries: Arc<ExpiryStore>,
+                  index: Arc<EntryIndex>,
+                  versions: Arc<VersionStore>,
+                  policies: Arc<PolicyStore>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let result = async {
+                    let expired = expiries.is_expired(&data_path).await.map_err(|e| {
+                        log::error!(
+                            "An error occurred while checking the expiry of path {}: {}",
+                            data_path,
+                            e
+                        );
+                        warp::reject::custom(DatabaseErrorRejection(e))
+                    })?;
+                    if expired {
+                        return Err(warp::reject::custom(NotFoundRejection));
+                    }
+
+                    let written_at = policy::written_at(&index, &versions, &data_path)
+                        .await
+                        .map_err(routes::policy_rejection)?
+                        .ok_or_else(|| warp::reject::custom(NotFoundRejection))?;
+                    policies
+                        .check_delete(&data_path, written_at)
+                        .await
+                        .map_err(routes::policy_rejection)?;
+
+                    let deleted_by = identity.as_ref().map(|identity| identity.to_string());
+                    match trash.delete(&data_path, deleted_by).await {
+                        Ok(Some(item)) => Ok::<_, Rejection>(warp::reply::json(&item)),
+                        Ok(None) => Err(warp::reject::custom(NotFoundRejection)),
+                        Err(e) => {
+                            log::error!(
+                                "An error occurred while moving the entry at path {} to the trash: {}",
+                                data_path,
+                                e
+                            );
+                            Err(warp::reject::custom(TrashErrorRejection(e)))
+                        }
+                    }
+                }
+                .await;
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "delete",
+                    &data_path,
+                    &result,
+                )
+                .await?;
+                result
+            },
+        )
+}
+
+/// Lists the items in the trash, leaving out those the caller may not read.
+pub fn list(
+    trash: Arc<TrashStore>,
+    authorization_rules: Arc<AuthorizationRules>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("-" / "trash")
+        .and(
+            warp::query::<TrashQueryParams>().and_then(|query: TrashQueryParams| async move {
+                match query.page_size {
+                    Some(page_size) if !(1..=100).contains(&page_size) => {
+                        Err(warp::reject::custom(BadRequestRejection))
+                    }
+                    _ => Ok(query),
+                }
+            }),
+        )
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || trash.clone()))
+        .and(warp::any().map(move || authorization_rules.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |query: TrashQueryParams,
+                  identity: Option<Identity>,
+                  trash: Arc<TrashStore>,
+                  authorization_rules: Arc<AuthorizationRules>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let prefix = query.path.unwrap_or_default();
+                let result = async {
+                    identity::check_read(&authorization_rules, identity.as_ref(), &prefix)?;
+                    let results = trash
+                        .list(
+                            &prefix,
+                            query.skip.unwrap_or_default(),
+                            query.page_size.unwrap_or(10),
+                        )
+                        .await
+                        .map_err(|e| {
+                            log::error!("An error occurred while listing the trash: {}", e);
+                            warp::reject::custom(TrashErrorRejection(e))
+                        })?;
+                    // Items under a stricter read rule than the listed prefix are left out
+                    let results: Vec<TrashSummary> = results
+                        .into_iter()
+                        .filter(|item| {
+                            identity.as_ref().is_some_and(|identity| {
+                                authorization_rules.permits_read(identity, &item.path)
+                            })
+                        })
+                        .collect();
+                    Ok::<_, Rejection>(warp::reply::json(&TrashCollectionResponse { results }))
+                }
+                .await;
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "list_trash",
+                    &prefix,
+                    &result,
+                )
+                .await?;
+                result
+            },
+        )
+}
+
+/// Puts an item in the trash back at its path, if the caller may read it.
+pub fn restore(
+    trash: Arc<TrashStore>,
+    authorization_rules: Arc<AuthorizationRules>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("-" / "trash" / String / "restore")
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || trash.clone()))
+        .and(warp::any().map(move || authorization_rules.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |id: String,
+                  identity: Option<Identity>,
+                  trash: Arc<TrashStore>,
+                  authorization_rules: Arc<AuthorizationRules>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let trash_rejection = |e: TrashError| match e {
+                    TrashError::Conflict { reason } => {
+                        warp::reject::custom(ConflictRejection { reason })
+                    }
+                    e => {
+                        log::error!(
+                            "An error occurred while restoring trashed entry {}: {}",
+                            id,
+                            e
+                        );
+                        warp::reject::custom(TrashErrorRejection(e))
+                    }
+                };
+                // The item is looked up first to check its path against the read rules
+                let (path, result) = match trash.get(&id).await {
+                    Ok(Some(item)) => {
+                        let result = match identity::check_read(
+                            &authorization_rules,
+                            identity.as_ref(),
+                            &item.path,
+                        ) {
+                            Ok(()) => match trash.restore(&id).await {
+                                Ok(Some(item)) => Ok(item),
+                                Ok(None) => Err(warp::reject::custom(NotFoundRejection)),
+                                Err(e) => Err(trash_rejection(e)),
+                            },
+                            Err(rejection) => Err(rejection),
+                        };
+                        (item.path, result)
+                    }
+                    Ok(None) => (
+                        format!("trash/{}", id),
+                        Err(warp::reject::custom(NotFoundRejection)),
+                    ),
+                    Err(e) => (format!("trash/{}", id), Err(trash_rejection(e))),
+                };
+
+                // Audit records are kept by path, which is only known once the item is found
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "restore",
+                    &path,
+                    &result,
+                )
+                .await?;
+                result.map(|item| warp::reply::json(&item))
+            },
+        )
+}
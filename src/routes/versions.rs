@@ -0,0 +1,88 @@
+use crate::{
+    audit::AuditLog,
+    expiry::ExpiryStore,
+    history::{VersionStore, VersionSummary},
+    identity::{self, AuthorizationRules, Identity},
+    metrics,
+    routes::{
+        self,
+        error::{DatabaseErrorRejection, HistoryErrorRejection, NotFoundRejection},
+    },
+};
+use serde::Serialize;
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Serialize)]
+struct VersionsResponse {
+    path: String,
+    versions: Vec<VersionSummary>,
+}
+
+pub fn list(
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    authorization_rules: Arc<AuthorizationRules>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!(String / "versions")
+        .and(warp::ext::optional::<Identity>())
+        .and(warp::any().map(move || versions.clone()))
+        .and(warp::any().map(move || expiries.clone()))
+        .and(warp::any().map(move || authorization_rules.clone()))
+        .and(warp::any().map(move || audit_log.clone()))
+        .and_then(
+            move |data_path: String,
+                  identity: Option<Identity>,
+                  versions: Arc<VersionStore>,
+                  expiries: Arc<ExpiryStore>,
+                  authorization_rules: Arc<AuthorizationRules>,
+                  audit_log: Option<Arc<AuditLog>>| async move {
+                let result = async {
+                    identity::check_read(&authorization_rules, identity.as_ref(), &data_path)?;
+                    let expired = expiries.is_expired(&data_path).await.map_err(|e| {
+                        log::error!(
+                            "An error occurred while checking the expiry of path {}: {}",
+                            data_path,
+                            e
+                        );
+                        warp::reject::custom(DatabaseErrorRejection(e))
+                    })?;
+                    if expired {
+                        return Err(warp::reject::custom(NotFoundRejection));
+                    }
+
+                    let history =
+                        metrics::time_storer(metrics::HISTORY, "list", versions.list(&data_path))
+                            .await
+                            .map_err(|e| {
+                                log::error!(
+                                    "An error occurred while listing the versions of path {}: {}",
+                                    data_path,
+                                    e
+                                );
+                                warp::reject::custom(HistoryErrorRejection(e))
+                            })?;
+                    if history.is_empty() {
+                        return Err(warp::reject::custom(NotFoundRejection));
+                    }
+
+                    Ok::<_, Rejection>(warp::reply::json(&VersionsResponse {
+                        path: data_path.clone(),
+                        versions: history.iter().map(|version| version.summary()).collect(),
+                    }))
+                }
+                .await;
+
+                routes::audit(
+                    audit_log.as_ref(),
+                    identity.as_ref(),
+                    "list_versions",
+                    &data_path,
+                    &result,
+                )
+                .await?;
+                result
+            },
+        )
+}
@@ -0,0 +1,794 @@
+//! The store's configuration, read into typed settings and validated as a
+//! whole at startup so that every problem can be reported at once.
+
+use crate::{ca::SubjectAltName, identity::IdentityMatcher, xfcc::TrustedProxy};
+use redact_config::{ConfigError, Configurator};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv6Addr},
+    path::PathBuf,
+};
+
+/// A single problem found while reading or validating the config.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigProblem {
+    /// The config directory or environment could not be read at all
+    Unreadable { path: String, reason: String },
+    /// A required key is not set
+    Missing { key: String },
+    /// A key is set but its value has the wrong type
+    WrongType { key: String, reason: String },
+    /// A key has a value of the right type that is not acceptable
+    Invalid { key: String, reason: String },
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigProblem::Unreadable { path, reason } => {
+                write!(f, "config could not be read from {}: {}", path, reason)
+            }
+            ConfigProblem::Missing { key } => write!(f, "{}: required key is not set", key),
+            ConfigProblem::WrongType { key, reason } => {
+                write!(f, "{}: value has the wrong type: {}", key, reason)
+            }
+            ConfigProblem::Invalid { key, reason } => write!(f, "{}: {}", key, reason),
+        }
+    }
+}
+
+impl ConfigProblem {
+    /// The key the problem is about, if it is about a single key.
+    fn key(&self) -> Option<&str> {
+        match self {
+            ConfigProblem::Unreadable { .. } => None,
+            ConfigProblem::Missing { key }
+            | ConfigProblem::WrongType { key, .. }
+            | ConfigProblem::Invalid { key, .. } => Some(key),
+        }
+    }
+}
+
+/// The settings a command uses. Problems with keys outside of them are not
+/// reported, so that a command is not refused over settings it never reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// The `tls.*` settings, for generating and inspecting TLS material
+    Tls,
+    /// The `audit.*` settings, for verifying the audit log
+    Audit,
+    /// Every setting, for running the server and checking its config
+    Server,
+}
+
+impl Scope {
+    fn covers(&self, key: &str) -> bool {
+        let prefixes: &[&str] = match self {
+            Scope::Tls => &["tls.", "logging."],
+            Scope::Audit => &["audit.", "logging."],
+            Scope::Server => return true,
+        };
+        prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+/// Subject, validity and location of a generated certificate.
+#[derive(Debug, Clone)]
+pub struct CertificateSettings {
+    pub o: String,
+    pub ou: String,
+    pub cn: String,
+    /// Validity in days
+    pub expires_in: i64,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaSettings {
+    pub certificate: CertificateSettings,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerTlsSettings {
+    pub certificate: CertificateSettings,
+    /// Days before expiry at which a generated certificate is re-issued
+    pub renew_before: i64,
+    /// Seconds between checks of whether the certificate needs renewing
+    pub renew_check_interval: u64,
+    pub sans: Vec<SubjectAltName>,
+    /// Add the pod IP and hostname to the SANs
+    pub detect_sans: bool,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientCertificateSettings {
+    pub o: String,
+    pub ou: String,
+    pub cn_suffixes: Vec<String>,
+    pub expires_in: i64,
+    pub max_expires_in: i64,
+    /// PEM file of the CA certificates client certificate chains must lead to
+    pub trust_bundle_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct CrlSettings {
+    pub paths: Vec<PathBuf>,
+    /// Seconds between reloads
+    pub reload_interval: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub generate: bool,
+    pub use_xfcc_header: bool,
+    /// Peers whose XFCC header is honoured
+    pub xfcc_trusted_proxies: Vec<TrustedProxy>,
+    pub crl: CrlSettings,
+    pub ca: CaSettings,
+    pub server: ServerTlsSettings,
+    pub client: ClientCertificateSettings,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerSettings {
+    pub port: u16,
+    /// Address the TCP listener binds to
+    pub bind: IpAddr,
+    /// Listen on this Unix domain socket instead of a TCP port when set
+    pub unix_socket: Option<UnixSocketSettings>,
+    pub http: HttpSettings,
+}
+
+/// Connection-level HTTP tuning shared by the mTLS and XFCC listeners.
+#[derive(Debug, Clone)]
+pub struct HttpSettings {
+    /// Keep HTTP/1.1 connections open between requests
+    pub keep_alive: bool,
+    /// Seconds between HTTP/2 keep-alive pings, none when zero
+    pub keep_alive_interval: u64,
+    /// Seconds a client has to send the full request headers
+    pub header_read_timeout: u64,
+    /// Streams a single HTTP/2 connection may have open at once
+    pub max_concurrent_streams: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct UnixSocketSettings {
+    pub path: PathBuf,
+    /// Permission bits applied to the socket file
+    pub mode: u32,
+}
+
+/// Identities allowed to read the paths under a prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadRule {
+    pub prefix: String,
+    pub identities: Vec<IdentityMatcher>,
+}
+
+impl ReadRule {
+    /// Parses a rule written as `<prefix>=<identity>|<identity>...`.
+    fn parse(rule: &str) -> Result<Self, String> {
+        let (prefix, identities) = rule
+            .split_once('=')
+            .ok_or_else(|| format!("rule '{}' must be written as <prefix>=<identities>", rule))?;
+        let prefix = prefix.trim();
+        if !prefix.starts_with('.') {
+            return Err(format!("prefix '{}' must start with a period", prefix));
+        }
+        let identities = identities
+            .split('|')
+            .map(str::trim)
+            .filter(|identity| !identity.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<IdentityMatcher>, String>>()?;
+        if identities.is_empty() {
+            return Err(format!(
+                "rule for '{}' must list at least one identity",
+                prefix
+            ));
+        }
+        Ok(ReadRule {
+            prefix: prefix.to_owned(),
+            identities,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuthSettings {
+    pub trust_domains: Vec<String>,
+    pub admin_identities: Vec<IdentityMatcher>,
+    pub read_rules: Vec<ReadRule>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DbSettings {
+    pub url: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct GoogleSettings {
+    pub storage_bucket_name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReadinessSettings {
+    /// Seconds to wait for each backend to respond
+    pub timeout: u64,
+    /// Seconds a readiness result is reused for
+    pub cache_ttl: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct HistorySettings {
+    /// Versions kept per path, including the current one
+    pub max_versions: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpirySettings {
+    /// Seconds between sweeps purging expired entries
+    pub sweep_interval: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrashSettings {
+    /// Days deleted entries can be restored for
+    pub retention: i64,
+    /// Seconds between sweeps purging entries past their retention
+    pub sweep_interval: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListingSettings {
+    /// Most entries a single page of a listing may hold
+    pub max_page_size: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsSettings {
+    /// Serve metrics over plain HTTP on this port instead of the main listener
+    pub admin_port: Option<u16>,
+    /// Address the metrics port binds to
+    pub admin_bind: IpAddr,
+}
+
+#[derive(Debug, Clone)]
+pub struct TracingSettings {
+    /// OTLP gRPC endpoint spans are exported to, none disables exporting
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+}
+
+/// Format of each log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct LoggingSettings {
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditSettings {
+    /// Append-only file the audit records are written to
+    pub path: PathBuf,
+    /// Ed25519 key the chain is signed with
+    pub key_path: PathBuf,
+    /// Seconds between checkpoint signatures
+    pub sign_interval: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ErasureSettings {
+    /// Ed25519 key erasure receipts are signed with
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub tls: TlsSettings,
+    pub auth: AuthSettings,
+    pub db: DbSettings,
+    pub google: GoogleSettings,
+    pub readiness: ReadinessSettings,
+    pub history: HistorySettings,
+    pub expiry: ExpirySettings,
+    pub trash: TrashSettings,
+    pub listing: ListingSettings,
+    pub metrics: MetricsSettings,
+    pub tracing: TracingSettings,
+    pub logging: LoggingSettings,
+    /// Audit logging of reads and writes, disabled when not set
+    pub audit: Option<AuditSettings>,
+    /// Erasure jobs, disabled when there is no key to sign their receipts with
+    pub erasure: Option<ErasureSettings>,
+}
+
+/// Reads typed values out of the config, recording a problem instead of
+/// failing whenever a value is missing or malformed.
+struct Reader<'a, C: Configurator> {
+    config: &'a C,
+    scope: Scope,
+    problems: Vec<ConfigProblem>,
+}
+
+impl<'a, C: Configurator> Reader<'a, C> {
+    fn push(&mut self, problem: ConfigProblem) {
+        let in_scope = match problem.key() {
+            Some(key) => self.scope.covers(key),
+            None => true,
+        };
+        if in_scope {
+            self.problems.push(problem);
+        }
+    }
+
+    fn record<T>(
+        &mut self,
+        key: &str,
+        required: bool,
+        result: Result<T, ConfigError>,
+    ) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(ConfigError::NotFound(_)) => {
+                if required {
+                    self.push(ConfigProblem::Missing {
+                        key: key.to_owned(),
+                    });
+                }
+                None
+            }
+            Err(e) => {
+                self.push(ConfigProblem::WrongType {
+                    key: key.to_owned(),
+                    reason: e.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    fn invalid(&mut self, key: &str, reason: impl Into<String>) {
+        self.push(ConfigProblem::Invalid {
+            key: key.to_owned(),
+            reason: reason.into(),
+        });
+    }
+
+    fn str(&mut self, key: &str, required: bool) -> Option<String> {
+        let result = self.config.get_str(key);
+        self.record(key, required, result)
+    }
+
+    fn int(&mut self, key: &str, required: bool) -> Option<i64> {
+        let result = self.config.get_int(key);
+        self.record(key, required, result)
+    }
+
+    fn bool(&mut self, key: &str, required: bool) -> Option<bool> {
+        let result = self.config.get_bool(key);
+        self.record(key, required, result)
+    }
+
+    /// Reads an optional comma-separated list, skipping empty items.
+    fn list(&mut self, key: &str) -> Vec<String> {
+        self.str(key, false)
+            .map(|list| {
+                list.split(',')
+                    .map(|item| item.trim().to_owned())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Reads an integer that must be at least one, such as a number of days.
+    fn positive_int(&mut self, key: &str, required: bool, default: i64) -> i64 {
+        match self.int(key, required) {
+            Some(value) if value < 1 => {
+                self.invalid(key, format!("must be at least 1, got {}", value));
+                default
+            }
+            Some(value) => value,
+            None => default,
+        }
+    }
+
+    /// Reads an optional TCP port number.
+    fn port(&mut self, key: &str) -> Option<u16> {
+        match self.int(key, false) {
+            Some(port) if (1..=65535).contains(&port) => Some(port as u16),
+            Some(port) => {
+                self.invalid(key, format!("must be between 1 and 65535, got {}", port));
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Reads the IP address a listener binds to, every address by default.
+    fn bind_addr(&mut self, key: &str) -> IpAddr {
+        match self.str(key, false) {
+            Some(bind) => match bind.trim().parse() {
+                Ok(bind) => bind,
+                Err(e) => {
+                    self.invalid(key, format!("'{}' is not an IP address: {}", bind, e));
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+                }
+            },
+            None => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+
+    /// Reads a string that must be set to a non-empty value.
+    fn non_empty_str(&mut self, key: &str) -> String {
+        match self.str(key, true) {
+            Some(value) if value.trim().is_empty() => {
+                self.invalid(key, "must not be empty");
+                value
+            }
+            value => value.unwrap_or_default(),
+        }
+    }
+
+    /// Reads the subject and validity of a certificate, which are required
+    /// when it gets generated, and its path, which may be required on its own.
+    fn certificate(
+        &mut self,
+        prefix: &str,
+        required: bool,
+        path_required: bool,
+    ) -> CertificateSettings {
+        CertificateSettings {
+            o: self
+                .str(&format!("{}.o", prefix), required)
+                .unwrap_or_default(),
+            ou: self
+                .str(&format!("{}.ou", prefix), required)
+                .unwrap_or_default(),
+            cn: self
+                .str(&format!("{}.cn", prefix), required)
+                .unwrap_or_default(),
+            expires_in: self.positive_int(&format!("{}.expires_in", prefix), required, 365),
+            path: self
+                .str(&format!("{}.path", prefix), path_required)
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads and validates the config, returning every problem found with
+    /// the settings in scope. Settings outside of it fall back to defaults.
+    pub fn load(config: &impl Configurator, scope: Scope) -> Result<Settings, Vec<ConfigProblem>> {
+        let mut r = Reader {
+            config,
+            scope,
+            problems: vec![],
+        };
+
+        let port = r.port("server.port").unwrap_or(8080);
+        let bind = r.bind_addr("server.bind");
+        let unix_socket = r
+            .str("server.unix_socket.path", false)
+            .filter(|path| !path.trim().is_empty())
+            .map(|path| {
+                let mode = r
+                    .str("server.unix_socket.mode", false)
+                    .unwrap_or_else(|| "660".to_owned());
+                let mode = match u32::from_str_radix(mode.trim(), 8) {
+                    Ok(mode) if mode <= 0o777 => mode,
+                    _ => {
+                        r.invalid(
+                            "server.unix_socket.mode",
+                            format!("'{}' is not an octal permission mode such as 660", mode),
+                        );
+                        0o660
+                    }
+                };
+                UnixSocketSettings {
+                    path: PathBuf::from(path),
+                    mode,
+                }
+            });
+
+        let http = HttpSettings {
+            keep_alive: r.bool("server.http.keep_alive", false).unwrap_or(true),
+            keep_alive_interval: match r.int("server.http.keep_alive_interval", false) {
+                Some(seconds) if seconds < 0 => {
+                    r.invalid(
+                        "server.http.keep_alive_interval",
+                        format!("must not be negative, got {}", seconds),
+                    );
+                    0
+                }
+                Some(seconds) => seconds as u64,
+                None => 0,
+            },
+            header_read_timeout: r.positive_int("server.http.header_read_timeout", false, 30)
+                as u64,
+            max_concurrent_streams: match r.int("server.http.max_concurrent_streams", false) {
+                Some(streams) if (1..=u32::MAX as i64).contains(&streams) => streams as u32,
+                Some(streams) => {
+                    r.invalid(
+                        "server.http.max_concurrent_streams",
+                        format!("must be between 1 and {}, got {}", u32::MAX, streams),
+                    );
+                    100
+                }
+                None => 100,
+            },
+        };
+
+        let generate = r.bool("tls.generate", true).unwrap_or_default();
+        let use_xfcc_header = r.bool("tls.use_xfcc_header", true).unwrap_or_default();
+        // The server certificate and key are needed to generate them or to serve mTLS
+        let server_tls_required = generate || !use_xfcc_header;
+
+        let mut xfcc_trusted_proxies = vec![];
+        for proxy in r.list("tls.xfcc.trusted_proxies") {
+            match proxy.parse() {
+                Ok(proxy) => xfcc_trusted_proxies.push(proxy),
+                Err(reason) => r.invalid("tls.xfcc.trusted_proxies", reason),
+            }
+        }
+        if use_xfcc_header && xfcc_trusted_proxies.is_empty() {
+            r.invalid(
+                "tls.xfcc.trusted_proxies",
+                "must list the proxies allowed to set the XFCC header when tls.use_xfcc_header is true",
+            );
+        }
+
+        let crl = CrlSettings {
+            paths: r
+                .list("tls.crl.paths")
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            reload_interval: r.positive_int("tls.crl.reload_interval", false, 300) as u64,
+        };
+
+        let ca = CaSettings {
+            certificate: r.certificate("tls.ca.certificate", generate, generate),
+            key_path: r
+                .str("tls.ca.key.path", generate)
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+        };
+
+        let mut sans = vec![];
+        let configured_sans = r
+            .str("tls.server.certificate.sans", false)
+            .unwrap_or_else(|| "localhost".to_owned());
+        for san in configured_sans
+            .split(',')
+            .map(str::trim)
+            .filter(|san| !san.is_empty())
+        {
+            match san.parse() {
+                Ok(san) => sans.push(san),
+                Err(reason) => r.invalid("tls.server.certificate.sans", reason),
+            }
+        }
+        let server = ServerTlsSettings {
+            certificate: r.certificate("tls.server.certificate", generate, server_tls_required),
+            renew_before: r.positive_int("tls.server.certificate.renew_before", false, 30),
+            renew_check_interval: r.positive_int(
+                "tls.server.certificate.renew_check_interval",
+                false,
+                60 * 60,
+            ) as u64,
+            sans,
+            detect_sans: r
+                .bool("tls.server.certificate.detect_sans", false)
+                .unwrap_or_default(),
+            key_path: r
+                .str("tls.server.key.path", server_tls_required)
+                .map(PathBuf::from)
+                .unwrap_or_default(),
+        };
+
+        let client = ClientCertificateSettings {
+            o: r.str("tls.client.certificate.o", generate)
+                .unwrap_or_default(),
+            ou: r
+                .str("tls.client.certificate.ou", generate)
+                .unwrap_or_default(),
+            cn_suffixes: r.list("tls.client.certificate.cn_suffixes"),
+            expires_in: r.positive_int("tls.client.certificate.expires_in", generate, 30),
+            max_expires_in: r.positive_int("tls.client.certificate.max_expires_in", generate, 90),
+            trust_bundle_path: r
+                .str("tls.client.trust_bundle.path", false)
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or_else(|| ca.certificate.path.clone()),
+        };
+        if (!use_xfcc_header || !crl.paths.is_empty())
+            && client.trust_bundle_path.as_os_str().is_empty()
+        {
+            r.invalid(
+                "tls.client.trust_bundle.path",
+                "must be set to verify client certificates and CRLs when tls.ca.certificate.path is not",
+            );
+        }
+        if client.expires_in > client.max_expires_in {
+            r.invalid(
+                "tls.client.certificate.expires_in",
+                format!(
+                    "must not exceed tls.client.certificate.max_expires_in ({})",
+                    client.max_expires_in
+                ),
+            );
+        }
+
+        let mut read_rules = vec![];
+        for rule in r.list("auth.read_rules") {
+            match ReadRule::parse(&rule) {
+                Ok(rule) => read_rules.push(rule),
+                Err(reason) => r.invalid("auth.read_rules", reason),
+            }
+        }
+        let mut admin_identities = vec![];
+        for identity in r.list("auth.admin_identities") {
+            match identity.parse() {
+                Ok(identity) => admin_identities.push(identity),
+                Err(reason) => r.invalid("auth.admin_identities", reason),
+            }
+        }
+        let auth = AuthSettings {
+            trust_domains: r.list("auth.trust_domains"),
+            admin_identities,
+            read_rules,
+        };
+
+        let db = DbSettings {
+            url: r.non_empty_str("db.url"),
+            name: r.non_empty_str("db.name"),
+        };
+
+        let google = GoogleSettings {
+            storage_bucket_name: r.non_empty_str("google.storage.bucket.name"),
+        };
+
+        let readiness = ReadinessSettings {
+            timeout: r.positive_int("readiness.timeout", false, 2) as u64,
+            cache_ttl: match r.int("readiness.cache_ttl", false) {
+                Some(seconds) if seconds < 0 => {
+                    r.invalid(
+                        "readiness.cache_ttl",
+                        format!("must not be negative, got {}", seconds),
+                    );
+                    5
+                }
+                Some(seconds) => seconds as u64,
+                None => 5,
+            },
+        };
+
+        let history = HistorySettings {
+            max_versions: r.positive_int("history.max_versions", false, 10) as u64,
+        };
+
+        let expiry = ExpirySettings {
+            sweep_interval: r.positive_int("expiry.sweep_interval", false, 60) as u64,
+        };
+
+        let trash = TrashSettings {
+            retention: r.positive_int("trash.retention", false, 30),
+            sweep_interval: r.positive_int("trash.sweep_interval", false, 3600) as u64,
+        };
+
+        let listing = ListingSettings {
+            max_page_size: r.positive_int("listing.max_page_size", false, 100),
+        };
+
+        let metrics = MetricsSettings {
+            admin_port: r.port("metrics.admin_port"),
+            admin_bind: r.bind_addr("metrics.admin_bind"),
+        };
+        if unix_socket.is_none() && metrics.admin_port == Some(port) {
+            r.invalid(
+                "metrics.admin_port",
+                "must differ from server.port".to_owned(),
+            );
+        }
+
+        let tracing = TracingSettings {
+            otlp_endpoint: r
+                .str("tracing.otlp.endpoint", false)
+                .filter(|endpoint| !endpoint.trim().is_empty()),
+            service_name: r
+                .str("tracing.service_name", false)
+                .unwrap_or_else(|| "redact-store".to_owned()),
+        };
+
+        let logging = LoggingSettings {
+            format: match r.str("logging.format", false).as_deref().map(str::trim) {
+                None | Some("text") => LogFormat::Text,
+                Some("json") => LogFormat::Json,
+                Some(format) => {
+                    r.invalid(
+                        "logging.format",
+                        format!("must be 'text' or 'json', got '{}'", format),
+                    );
+                    LogFormat::Text
+                }
+            },
+        };
+
+        let audit = match r
+            .str("audit.path", false)
+            .filter(|path| !path.trim().is_empty())
+        {
+            Some(path) => {
+                let key_path = match r.str("audit.key.path", false) {
+                    Some(key_path) if !key_path.is_empty() => PathBuf::from(key_path),
+                    _ => {
+                        r.invalid("audit.key.path", "must be set when audit.path is set");
+                        PathBuf::new()
+                    }
+                };
+                Some(AuditSettings {
+                    path: PathBuf::from(path),
+                    key_path,
+                    sign_interval: r.positive_int("audit.sign_interval", false, 300) as u64,
+                })
+            }
+            None => None,
+        };
+
+        let erasure = r
+            .str("erasure.key.path", false)
+            .filter(|key_path| !key_path.is_empty())
+            .map(|key_path| ErasureSettings {
+                key_path: PathBuf::from(key_path),
+            });
+
+        if r.problems.is_empty() {
+            Ok(Settings {
+                server: ServerSettings {
+                    port,
+                    bind,
+                    unix_socket,
+                    http,
+                },
+                tls: TlsSettings {
+                    generate,
+                    use_xfcc_header,
+                    xfcc_trusted_proxies,
+                    crl,
+                    ca,
+                    server,
+                    client,
+                },
+                auth,
+                db,
+                google,
+                readiness,
+                history,
+                expiry,
+                trash,
+                listing,
+                metrics,
+                tracing,
+                logging,
+                audit,
+                erasure,
+            })
+        } else {
+            Err(r.problems)
+        }
+    }
+}
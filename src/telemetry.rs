@@ -0,0 +1,81 @@
+//! Distributed tracing: per-request spans continuing any W3C trace context
+//! sent by the caller, exported over OTLP when a collector is configured.
+
+use crate::settings::TracingSettings;
+use hyper::{http::HeaderMap, Request};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// Reads trace context out of incoming request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderExtractor<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Installs the OTLP exporter if a collector endpoint is configured. Spans
+/// are still created without one, they are simply not recorded anywhere.
+pub fn init(settings: &TracingSettings) -> Result<(), TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let endpoint = match &settings.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(()),
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                settings.service_name.clone(),
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| TraceError::Other(Box::new(e)))
+}
+
+/// Flushes any spans that have not been exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Creates the span covering a whole request, as a child of the caller's
+/// span if the request carries a `traceparent` header.
+pub fn request_span<B>(req: &Request<B>) -> Span {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), req.uri().path()),
+        otel.kind = "server",
+        http.method = %req.method(),
+        http.target = %req.uri().path(),
+        identity = tracing::field::Empty,
+    );
+    span.set_parent(parent);
+    span
+}
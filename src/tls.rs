@@ -0,0 +1,173 @@
+//! Generation and inspection of the store's CA and server TLS material.
+
+use crate::{
+    ca::{self, CertificateAuthority, IssuancePolicy, SubjectAltName},
+    keys::{self, StoreKey},
+    renewal::{self, ServerCertificateSpec},
+    settings::{ServerTlsSettings, TlsSettings},
+};
+use redact_crypto::x509::DistinguishedName;
+use std::{
+    fs::File,
+    io::{self, ErrorKind},
+    sync::Arc,
+};
+
+/// The CA and server key held in memory after generating TLS material.
+pub struct GeneratedTls {
+    pub ca: Arc<CertificateAuthority>,
+    pub server_key: StoreKey,
+}
+
+/// Creates the CA key and certificate and the server key and certificate
+/// wherever they are missing, and re-issues the server certificate if its
+/// SANs no longer match the config.
+pub fn init(settings: &TlsSettings) -> io::Result<GeneratedTls> {
+    // Make the storer TLS CA key if it doesn't exist
+    let ca_key = keys::load_or_generate(&settings.ca.key_path)?;
+
+    // Make the storer TLS CA cert if it doesn't exist
+    let ca_cert_settings = &settings.ca.certificate;
+    let ca_cert_dn = DistinguishedName {
+        o: &ca_cert_settings.o,
+        ou: &ca_cert_settings.ou,
+        cn: &ca_cert_settings.cn,
+    };
+    if let Err(e) = File::open(&ca_cert_settings.path) {
+        match e.kind() {
+            ErrorKind::NotFound => {
+                let tls_cert = CertificateAuthority::generate_certificate(
+                    &ca_key,
+                    &ca_cert_dn,
+                    ca_cert_settings.expires_in,
+                )
+                .map_err(|e| io::Error::new(ErrorKind::Other, e.to_string()))?;
+                ca::write_certificate(&ca_cert_settings.path, &tls_cert)?;
+            }
+            _ => return Err(e),
+        }
+    }
+    let ca_cert = ca::read_certificate(&ca_cert_settings.path)?;
+
+    // Keep the CA around so it can issue client and server certificates
+    let server_cert_spec = server_certificate_spec(settings);
+    let issuance_policy = IssuancePolicy {
+        o: settings.client.o.clone(),
+        ou: settings.client.ou.clone(),
+        cn_suffixes: settings.client.cn_suffixes.clone(),
+        reserved_names: server_cert_spec
+            .sans
+            .iter()
+            .filter_map(|san| match san {
+                SubjectAltName::Dns(dns_name) => Some(dns_name.clone()),
+                SubjectAltName::Ip(_) => None,
+            })
+            .collect(),
+        default_expires_in: settings.client.expires_in,
+        max_expires_in: settings.client.max_expires_in,
+    };
+    let ca = Arc::new(CertificateAuthority::new(
+        ca_key,
+        ca_cert,
+        ca_cert_settings.o.clone(),
+        ca_cert_settings.ou.clone(),
+        ca_cert_settings.cn.clone(),
+        issuance_policy,
+    ));
+
+    // Make the storer client TLS key if it doesn't exist
+    let server_key = keys::load_or_generate(&settings.server.key_path)?;
+
+    // Make the storer TLS cert if it doesn't exist or its SANs changed
+    match File::open(&server_cert_spec.cert_path) {
+        Ok(_) => {
+            if !server_cert_spec.sans_match()? {
+                log::info!("Server certificate SANs differ from config, regenerating");
+                server_cert_spec.issue(&ca, &server_key)?;
+            }
+        }
+        Err(e) => match e.kind() {
+            ErrorKind::NotFound => server_cert_spec.issue(&ca, &server_key)?,
+            _ => return Err(e),
+        },
+    }
+
+    Ok(GeneratedTls { ca, server_key })
+}
+
+/// Prints the algorithm of each key and the subject, expiry and SANs of each
+/// certificate, failing if any of them is missing or unreadable.
+pub fn inspect(settings: &TlsSettings) -> io::Result<()> {
+    for (name, key_path) in [
+        ("CA key", &settings.ca.key_path),
+        ("server key", &settings.server.key_path),
+    ] {
+        let key = StoreKey::from_pkcs8_pem(&std::fs::read_to_string(key_path)?)?;
+        println!("{} {}: {}", name, key_path.display(), key.algorithm());
+    }
+
+    for (name, cert_path) in [
+        ("CA certificate", &settings.ca.certificate.path),
+        ("server certificate", &settings.server.certificate.path),
+    ] {
+        let der = ca::read_certificate(cert_path)?;
+        let (_, cert) = x509_parser::parse_x509_certificate(&der)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let sans: Vec<String> = ca::certificate_sans(&der)?
+            .iter()
+            .map(|san| san.to_string())
+            .collect();
+        println!(
+            "{} {}: subject '{}', expires {}, SANs [{}]",
+            name,
+            cert_path.display(),
+            cert.subject(),
+            renewal::certificate_expiry(cert_path)?.to_rfc3339(),
+            sans.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Builds the subject and validity of the generated server certificate from config.
+pub fn server_certificate_spec(settings: &TlsSettings) -> ServerCertificateSpec {
+    let certificate = &settings.server.certificate;
+    ServerCertificateSpec {
+        o: certificate.o.clone(),
+        ou: certificate.ou.clone(),
+        cn: certificate.cn.clone(),
+        expires_in: certificate.expires_in,
+        sans: server_certificate_sans(&settings.server),
+        cert_path: certificate.path.clone(),
+        key_path: settings.server.key_path.clone(),
+    }
+}
+
+/// Collects the configured server certificate SANs, adding the pod IP and
+/// hostname when detection is enabled.
+fn server_certificate_sans(settings: &ServerTlsSettings) -> Vec<SubjectAltName> {
+    let mut sans = settings.sans.clone();
+
+    if settings.detect_sans {
+        let pod_ip = std::env::var("POD_IP")
+            .ok()
+            .and_then(|pod_ip| pod_ip.trim().parse().ok())
+            .map(SubjectAltName::Ip);
+        let hostname = std::env::var("HOSTNAME")
+            .ok()
+            .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+            .map(|hostname| hostname.trim().to_owned())
+            .filter(|hostname| !hostname.is_empty())
+            .map(SubjectAltName::Dns);
+        sans.extend(pod_ip.into_iter().chain(hostname));
+    }
+
+    let mut deduplicated = Vec::with_capacity(sans.len());
+    for san in sans {
+        if !deduplicated.contains(&san) {
+            deduplicated.push(san);
+        }
+    }
+    deduplicated
+}
@@ -0,0 +1,388 @@
+//! Deleted entries, kept for a retention period during which they can be
+//! restored. Deleting moves the index entry into the trash and holds its
+//! version, so that its blob outlives both the delete and later overwrites of
+//! the path. Items are purged for good once their retention has passed.
+
+use crate::{
+    backends,
+    blobs::BlobBucket,
+    expiry::ExpiryStore,
+    history::{HistoryError, VersionStore},
+    index::EntryIndex,
+    policy::PolicyStore,
+};
+use bson::{doc, Document};
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use mongodb::{
+    options::{FindOptions, IndexOptions},
+    Collection, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt, sync::Arc};
+use uuid::Uuid;
+
+/// Collection deleted entries are kept in.
+const TRASH_COLLECTION: &str = "redact_store_trash";
+
+#[derive(Debug)]
+pub enum TrashError {
+    /// The trash or the entries collection could not be read or written
+    Database(mongodb::error::Error),
+    /// The version held by an item could not be updated or purged
+    History(HistoryError),
+    /// The blob of a purged item could not be deleted
+    Blob(cloud_storage::Error),
+    /// The item cannot be restored over the current state of its path
+    Conflict { reason: String },
+}
+
+impl fmt::Display for TrashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrashError::Database(e) => write!(f, "Trash is unavailable: {}", e),
+            TrashError::History(e) => write!(f, "{}", e),
+            TrashError::Blob(e) => write!(f, "Could not delete trashed blob: {}", e),
+            TrashError::Conflict { reason } => write!(f, "Cannot restore: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TrashError {}
+
+impl From<mongodb::error::Error> for TrashError {
+    fn from(e: mongodb::error::Error) -> Self {
+        TrashError::Database(e)
+    }
+}
+
+impl From<HistoryError> for TrashError {
+    fn from(e: HistoryError) -> Self {
+        TrashError::History(e)
+    }
+}
+
+/// An entry that was deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TrashItem {
+    id: String,
+    path: String,
+    /// Index document as it was when deleted
+    entry: Document,
+    /// Version of the path the entry was written as, if it has one
+    version: Option<i64>,
+    deleted_at: bson::DateTime,
+    deleted_by: Option<String>,
+    purge_after: bson::DateTime,
+    /// Expiry the entry had when it was deleted
+    expires_at: Option<bson::DateTime>,
+}
+
+/// What the trash listing and deletes show about an item.
+#[derive(Debug, Serialize)]
+pub struct TrashSummary {
+    pub id: String,
+    pub path: String,
+    pub version: Option<i64>,
+    pub deleted_at: DateTime<Utc>,
+    pub deleted_by: Option<String>,
+    pub purge_after: DateTime<Utc>,
+}
+
+impl TrashItem {
+    fn summary(&self) -> TrashSummary {
+        TrashSummary {
+            id: self.id.clone(),
+            path: self.path.clone(),
+            version: self.version,
+            deleted_at: backends::to_chrono(self.deleted_at),
+            deleted_by: self.deleted_by.clone(),
+            purge_after: backends::to_chrono(self.purge_after),
+        }
+    }
+}
+
+pub struct TrashStore {
+    items: Collection<TrashItem>,
+    index: Arc<EntryIndex>,
+    versions: Arc<VersionStore>,
+    expiries: Arc<ExpiryStore>,
+    blobs: Arc<BlobBucket>,
+    policies: Arc<PolicyStore>,
+    retention: Duration,
+}
+
+impl TrashStore {
+    pub async fn new(
+        database: &Database,
+        index: Arc<EntryIndex>,
+        versions: Arc<VersionStore>,
+        expiries: Arc<ExpiryStore>,
+        blobs: Arc<BlobBucket>,
+        policies: Arc<PolicyStore>,
+        retention: Duration,
+    ) -> Result<Self, mongodb::error::Error> {
+        let items = database.collection::<TrashItem>(TRASH_COLLECTION);
+        items
+            .create_indexes(
+                vec![
+                    IndexModel::builder()
+                        .keys(doc! { "id": 1 })
+                        .options(IndexOptions::builder().unique(true).build())
+                        .build(),
+                    IndexModel::builder()
+                        .keys(doc! { "path": 1, "deleted_at": -1 })
+                        .build(),
+                    IndexModel::builder()
+                        .keys(doc! { "purge_after": 1 })
+                        .build(),
+                ],
+                None,
+            )
+            .await?;
+
+        Ok(TrashStore {
+            items,
+            index,
+            versions,
+            expiries,
+            blobs,
+            policies,
+            retention,
+        })
+    }
+
+    /// Moves the entry at a path into the trash, returning the new item or
+    /// none if there is no entry at the path.
+    pub async fn delete(
+        &self,
+        path: &str,
+        deleted_by: Option<String>,
+    ) -> Result<Option<TrashSummary>, TrashError> {
+        let entry = match self.index.get(path).await? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let version = self
+            .versions
+            .current(path)
+            .await?
+            .map(|current| current.version);
+        if let Some(version) = version {
+            self.versions.set_trashed(path, version, true).await?;
+        }
+
+        // An entry that would have expired sooner is not kept past its expiry
+        let expires_at = self.expiries.expires_at(path).await?;
+        let now = Utc::now();
+        let purge_after = match expires_at {
+            Some(expires_at) if expires_at < now + self.retention => expires_at,
+            _ => now + self.retention,
+        };
+
+        let item = TrashItem {
+            id: Uuid::new_v4().to_string(),
+            path: path.to_owned(),
+            entry,
+            version,
+            deleted_at: backends::to_bson(now),
+            deleted_by,
+            purge_after: backends::to_bson(purge_after),
+            expires_at: expires_at.map(backends::to_bson),
+        };
+        // The item goes in first so that a failure never loses the entry
+        self.items.insert_one(&item, None).await?;
+        self.index.delete(path).await?;
+        self.expiries.clear(path).await?;
+        Ok(Some(item.summary()))
+    }
+
+    /// Lists the items in the trash whose path starts with a prefix, most
+    /// recently deleted first.
+    pub async fn list(
+        &self,
+        prefix: &str,
+        skip: u64,
+        page_size: i64,
+    ) -> Result<Vec<TrashSummary>, TrashError> {
+        let items: Vec<TrashItem> = self
+            .items
+            .find(
+                doc! { "path": { "$regex": backends::prefix_pattern(prefix) } },
+                FindOptions::builder()
+                    .sort(doc! { "deleted_at": -1 })
+                    .skip(skip)
+                    .limit(page_size)
+                    .build(),
+            )
+            .await?
+            .try_collect()
+            .await?;
+        Ok(items.iter().map(TrashItem::summary).collect())
+    }
+
+    /// Reads an item, none if there is no such item.
+    pub async fn get(&self, id: &str) -> Result<Option<TrashSummary>, TrashError> {
+        Ok(self
+            .items
+            .find_one(doc! { "id": id }, None)
+            .await?
+            .map(|item| item.summary()))
+    }
+
+    /// Puts an item back at its path, returning it or none if there is no
+    /// such item. Fails if the path has been written to since the delete.
+    pub async fn restore(&self, id: &str) -> Result<Option<TrashSummary>, TrashError> {
+        let item = match self.items.find_one(doc! { "id": id }, None).await? {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        if self.index.exists(&item.path).await? {
+            return Err(TrashError::Conflict {
+                reason: format!(
+                    "an entry has been written at {} since it was deleted",
+                    item.path
+                ),
+            });
+        }
+        if let Some(expires_at) = item.expires_at {
+            if backends::to_chrono(expires_at) <= Utc::now() {
+                return Err(TrashError::Conflict {
+                    reason: format!("the entry at {} expired while in the trash", item.path),
+                });
+            }
+        }
+
+        self.index.put_back(item.entry.clone()).await?;
+        if let Some(version) = item.version {
+            self.versions
+                .set_trashed(&item.path, version, false)
+                .await?;
+            self.expiries
+                .set(
+                    &item.path,
+                    version,
+                    item.expires_at.map(backends::to_chrono),
+                )
+                .await?;
+        }
+        self.items.delete_one(doc! { "id": id }, None).await?;
+        Ok(Some(item.summary()))
+    }
+
+    /// Paths under a prefix that have items in the trash.
+    pub async fn paths_under(&self, prefix: &str) -> Result<Vec<String>, TrashError> {
+        Ok(self
+            .items
+            .distinct(
+                "path",
+                doc! { "path": { "$regex": backends::prefix_pattern(prefix) } },
+                None,
+            )
+            .await?
+            .into_iter()
+            .filter_map(|path| path.as_str().map(str::to_owned))
+            .collect())
+    }
+
+    /// Deletes the items of a path, or only those holding one of the given
+    /// versions, returning the index documents they kept. Their versions and
+    /// blobs are left to the caller.
+    pub async fn erase(
+        &self,
+        path: &str,
+        versions: Option<&HashSet<i64>>,
+    ) -> Result<Vec<Document>, TrashError> {
+        let items: Vec<TrashItem> = self
+            .items
+            .find(doc! { "path": path }, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut entries = vec![];
+        for item in items {
+            let erased = match (versions, item.version) {
+                (None, _) => true,
+                (Some(versions), Some(version)) => versions.contains(&version),
+                (Some(_), None) => false,
+            };
+            if erased {
+                self.items.delete_one(doc! { "id": &item.id }, None).await?;
+                entries.push(item.entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Deletes an item along with its version and blob. Returns false
+    /// without deleting anything if the path is under a legal hold.
+    async fn purge(&self, item: &TrashItem) -> Result<bool, TrashError> {
+        if self.policies.is_held(&item.path).await? {
+            return Ok(false);
+        }
+
+        if let Some(version) = item.version {
+            self.versions
+                .set_trashed(&item.path, version, false)
+                .await?;
+            self.versions.discard(&item.path, version).await?;
+        }
+        // Entries written before versions were kept have their blob under their own path
+        if !self.index.exists(&item.path).await? {
+            self.blobs
+                .delete(&item.path)
+                .await
+                .map_err(TrashError::Blob)?;
+        }
+        self.items.delete_one(doc! { "id": &item.id }, None).await?;
+        Ok(true)
+    }
+
+    /// Purges every item whose retention has passed, returning how many
+    /// were purged. Items that fail to purge or are on hold are left for the
+    /// next sweep.
+    async fn sweep(&self) -> Result<u64, mongodb::error::Error> {
+        let due: Vec<TrashItem> = self
+            .items
+            .find(
+                doc! { "purge_after": { "$lte": bson::DateTime::now() } },
+                None,
+            )
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut purged = 0;
+        for item in &due {
+            match self.purge(item).await {
+                Ok(true) => purged += 1,
+                Ok(false) => {}
+                Err(e) => log::error!(
+                    "Could not purge trashed entry {} at path {}: {}",
+                    item.id,
+                    item.path,
+                    e
+                ),
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Purges items past their retention on the given interval for as long
+    /// as the server runs.
+    pub fn spawn_sweeper(self: Arc<Self>, interval: std::time::Duration) {
+        tokio::task::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match self.sweep().await {
+                    Ok(0) => {}
+                    Ok(purged) => log::info!("Purged {} entries from the trash", purged),
+                    Err(e) => log::error!("Could not purge the trash: {}", e),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,466 @@
+//! Parser for the `x-forwarded-client-cert` (XFCC) header as emitted by Envoy.
+//!
+//! The header is a comma-separated list of elements, one per proxy hop. Each
+//! element is a semicolon-separated list of `Key=Value` pairs, where values may
+//! be double-quoted in order to contain `,`, `;` or `=`, and a backslash escapes
+//! the character following it inside a quoted value. The supported keys are
+//! `By`, `Hash`, `Cert`, `Chain`, `Subject`, `URI` and `DNS`; `URI` and `DNS`
+//! may appear more than once in a single element.
+
+use crate::listener::PeerAddr;
+use std::{fmt, net::IpAddr, str::FromStr};
+use tokio_rustls::rustls::Certificate;
+
+/// Name of the header carrying forwarded client certificate details.
+pub const XFCC_HEADER: &str = "x-forwarded-client-cert";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum XfccError {
+    /// The header contained no elements at all
+    Empty,
+    /// A pair did not contain an `=` separating key and value
+    MissingSeparator { pair: String },
+    /// A quoted value was never closed, or had trailing characters after the closing quote
+    UnterminatedQuote { key: String },
+    /// A key that the XFCC grammar does not define
+    UnknownKey { key: String },
+    /// A key that may only appear once was repeated within an element
+    DuplicateKey { key: String },
+    /// The `Cert` or `Chain` value was not a valid URL-encoded PEM document
+    InvalidPem { key: String },
+}
+
+impl fmt::Display for XfccError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            XfccError::Empty => write!(f, "XFCC header is empty"),
+            XfccError::MissingSeparator { pair } => {
+                write!(f, "XFCC pair '{}' is missing a '=' separator", pair)
+            }
+            XfccError::UnterminatedQuote { key } => {
+                write!(f, "XFCC value for key '{}' has an unterminated quote", key)
+            }
+            XfccError::UnknownKey { key } => write!(f, "XFCC key '{}' is not recognized", key),
+            XfccError::DuplicateKey { key } => {
+                write!(f, "XFCC key '{}' appears more than once in an element", key)
+            }
+            XfccError::InvalidPem { key } => {
+                write!(f, "XFCC value for key '{}' is not a valid PEM document", key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for XfccError {}
+
+/// Identity information forwarded by a single proxy hop.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XfccElement {
+    /// Subject alternative name (URI) of the proxy's own certificate
+    pub by: Option<String>,
+    /// Hex-encoded SHA-256 digest of the client certificate
+    pub hash: Option<String>,
+    /// DER-encoded client certificate
+    pub cert: Option<Certificate>,
+    /// DER-encoded client certificate chain, leaf first
+    pub chain: Vec<Certificate>,
+    /// Subject distinguished name of the client certificate
+    pub subject: Option<String>,
+    /// URI subject alternative names of the client certificate
+    pub uri: Vec<String>,
+    /// DNS subject alternative names of the client certificate
+    pub dns: Vec<String>,
+}
+
+/// A fully parsed XFCC header, in the order the elements appeared.
+///
+/// Each proxy appends the details of its own downstream peer, so the last
+/// element describes the hop closest to this server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Xfcc {
+    pub elements: Vec<XfccElement>,
+}
+
+impl Xfcc {
+    /// Returns the element describing the caller directly connected to the
+    /// nearest proxy.
+    pub fn peer(&self) -> Option<&XfccElement> {
+        self.elements.last()
+    }
+}
+
+/// A peer allowed to set the XFCC header. The header is ignored on
+/// connections from any other peer, as anyone able to reach the listener could
+/// otherwise claim any identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustedProxy {
+    /// Any peer connecting over the Unix domain socket, written as `unix`
+    UnixSocket,
+    /// Peers within a network written as `<address>/<prefix length>`, or a
+    /// single address
+    Network { addr: IpAddr, prefix_len: u8 },
+}
+
+impl TrustedProxy {
+    pub fn trusts(&self, peer: PeerAddr) -> bool {
+        match (self, peer) {
+            (TrustedProxy::UnixSocket, PeerAddr::Unix) => true,
+            (TrustedProxy::Network { addr, prefix_len }, PeerAddr::Tcp(peer)) => {
+                match (addr, canonical(peer)) {
+                    (IpAddr::V4(addr), IpAddr::V4(peer)) => {
+                        let mask = u32::MAX.checked_shl(32 - *prefix_len as u32).unwrap_or(0);
+                        u32::from(*addr) & mask == u32::from(peer) & mask
+                    }
+                    (IpAddr::V6(addr), IpAddr::V6(peer)) => {
+                        let mask = u128::MAX.checked_shl(128 - *prefix_len as u32).unwrap_or(0);
+                        u128::from(*addr) & mask == u128::from(peer) & mask
+                    }
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(TrustedProxy::UnixSocket);
+        }
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map(canonical)
+            .map_err(|e| format!("'{}' is not an IP address or network: {}", s, e))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| {
+                    format!(
+                        "'{}' must have a prefix length between 0 and {}",
+                        s, max_prefix_len
+                    )
+                })?,
+            None => max_prefix_len,
+        };
+
+        Ok(TrustedProxy::Network { addr, prefix_len })
+    }
+}
+
+/// Treats IPv4-mapped IPv6 addresses, as seen on a dual-stack listener, as
+/// the IPv4 addresses they carry.
+fn canonical(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        addr => addr,
+    }
+}
+
+impl FromStr for Xfcc {
+    type Err = XfccError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let elements = split_unquoted(s, ',')
+            .into_iter()
+            .map(str::trim)
+            .filter(|element| !element.is_empty())
+            .map(parse_element)
+            .collect::<Result<Vec<XfccElement>, XfccError>>()?;
+
+        if elements.is_empty() {
+            Err(XfccError::Empty)
+        } else {
+            Ok(Xfcc { elements })
+        }
+    }
+}
+
+/// Splits the input on the delimiter, ignoring any delimiters that are inside
+/// a quoted section or escaped by a backslash within one.
+fn split_unquoted(input: &str, delimiter: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if in_quotes && c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if !in_quotes && c == delimiter {
+            parts.push(&input[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&input[start..]);
+
+    parts
+}
+
+/// Removes surrounding double quotes from a value and resolves backslash
+/// escapes. Unquoted values are returned as-is.
+fn unquote(key: &str, value: &str) -> Result<String, XfccError> {
+    if !value.starts_with('"') {
+        return Ok(value.to_owned());
+    }
+
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value[1..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => unquoted.push(escaped),
+                None => break,
+            },
+            '"' => {
+                return if chars.next().is_none() {
+                    Ok(unquoted)
+                } else {
+                    Err(XfccError::UnterminatedQuote {
+                        key: key.to_owned(),
+                    })
+                };
+            }
+            _ => unquoted.push(c),
+        }
+    }
+
+    Err(XfccError::UnterminatedQuote {
+        key: key.to_owned(),
+    })
+}
+
+/// URL-decodes a `Cert` or `Chain` value and parses every PEM block in it.
+fn decode_pem(key: &str, value: &str) -> Result<Vec<Certificate>, XfccError> {
+    let invalid_pem = || XfccError::InvalidPem {
+        key: key.to_owned(),
+    };
+    let decoded = urlencoding::decode(value).map_err(|_| invalid_pem())?;
+    let pems = pem::parse_many(decoded.as_bytes()).map_err(|_| invalid_pem())?;
+    if pems.is_empty() {
+        return Err(invalid_pem());
+    }
+
+    Ok(pems
+        .into_iter()
+        .map(|pem| Certificate(pem.into_contents()))
+        .collect())
+}
+
+fn set_once<T>(slot: &mut Option<T>, key: &str, value: T) -> Result<(), XfccError> {
+    if slot.is_some() {
+        Err(XfccError::DuplicateKey {
+            key: key.to_owned(),
+        })
+    } else {
+        *slot = Some(value);
+        Ok(())
+    }
+}
+
+fn parse_element(element: &str) -> Result<XfccElement, XfccError> {
+    let mut parsed = XfccElement::default();
+    let mut chain_seen = false;
+
+    for pair in split_unquoted(element, ';')
+        .into_iter()
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+    {
+        let (key, raw_value) = pair
+            .split_once('=')
+            .ok_or_else(|| XfccError::MissingSeparator {
+                pair: pair.to_owned(),
+            })?;
+        let key = key.trim();
+        let value = unquote(key, raw_value.trim())?;
+
+        // Keys are case-insensitive in practice, Envoy itself emits them
+        // capitalized as in the spec
+        match key.to_ascii_lowercase().as_str() {
+            "by" => set_once(&mut parsed.by, key, value)?,
+            "hash" => set_once(&mut parsed.hash, key, value)?,
+            "subject" => set_once(&mut parsed.subject, key, value)?,
+            "uri" => parsed.uri.push(value),
+            "dns" => parsed.dns.push(value),
+            "cert" => {
+                // Only the leaf is meaningful here, the rest belongs in Chain
+                let cert = decode_pem(key, &value)?.swap_remove(0);
+                set_once(&mut parsed.cert, key, cert)?
+            }
+            "chain" => {
+                if chain_seen {
+                    return Err(XfccError::DuplicateKey {
+                        key: key.to_owned(),
+                    });
+                }
+                chain_seen = true;
+                parsed.chain = decode_pem(key, &value)?;
+            }
+            _ => {
+                return Err(XfccError::UnknownKey {
+                    key: key.to_owned(),
+                })
+            }
+        }
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT: &str = "-----BEGIN%20CERTIFICATE-----%0AAQID%0A-----END%20CERTIFICATE-----%0A";
+
+    #[test]
+    fn parses_every_key() {
+        let header = format!(
+            "By=spiffe://example.org/proxy;Hash=abc123;Cert=\"{}\";Subject=\"CN=client,O=pauwels\";\
+             URI=spiffe://example.org/a;URI=spiffe://example.org/b;DNS=client.example.org",
+            CERT
+        );
+        let xfcc: Xfcc = header.parse().unwrap();
+
+        assert_eq!(
+            xfcc.elements,
+            vec![XfccElement {
+                by: Some("spiffe://example.org/proxy".to_owned()),
+                hash: Some("abc123".to_owned()),
+                cert: Some(Certificate(vec![1, 2, 3])),
+                chain: vec![],
+                subject: Some("CN=client,O=pauwels".to_owned()),
+                uri: vec![
+                    "spiffe://example.org/a".to_owned(),
+                    "spiffe://example.org/b".to_owned()
+                ],
+                dns: vec!["client.example.org".to_owned()],
+            }]
+        );
+    }
+
+    #[test]
+    fn trusted_proxies_match_their_networks() {
+        let loopback: TrustedProxy = "127.0.0.0/8".parse().unwrap();
+        assert!(loopback.trusts(PeerAddr::Tcp("127.0.0.6".parse().unwrap())));
+        assert!(loopback.trusts(PeerAddr::Tcp("::ffff:127.0.0.1".parse().unwrap())));
+        assert!(!loopback.trusts(PeerAddr::Tcp("10.0.0.1".parse().unwrap())));
+        assert!(!loopback.trusts(PeerAddr::Unix));
+
+        let single: TrustedProxy = "fd00::1".parse().unwrap();
+        assert!(single.trusts(PeerAddr::Tcp("fd00::1".parse().unwrap())));
+        assert!(!single.trusts(PeerAddr::Tcp("fd00::2".parse().unwrap())));
+
+        let any: TrustedProxy = "0.0.0.0/0".parse().unwrap();
+        assert!(any.trusts(PeerAddr::Tcp("192.0.2.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn only_unix_trusts_unix_socket_peers() {
+        let unix: TrustedProxy = "unix".parse().unwrap();
+        assert!(unix.trusts(PeerAddr::Unix));
+        assert!(!unix.trusts(PeerAddr::Tcp("127.0.0.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn rejects_malformed_trusted_proxies() {
+        for proxy in ["", "localhost", "10.0.0.0/33", "fd00::/129", "10.0.0.0/x"] {
+            assert!(proxy.parse::<TrustedProxy>().is_err(), "{}", proxy);
+        }
+    }
+
+    #[test]
+    fn peer_is_the_last_element() {
+        let xfcc: Xfcc = "Hash=first,Hash=second".parse().unwrap();
+
+        assert_eq!(xfcc.elements.len(), 2);
+        assert_eq!(xfcc.peer().unwrap().hash.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn quoted_values_keep_delimiters_and_resolve_escapes() {
+        let xfcc: Xfcc = r#"Subject="CN=a\"b;c,d";Hash=h"#.parse().unwrap();
+
+        let peer = xfcc.peer().unwrap();
+        assert_eq!(peer.subject.as_deref(), Some(r#"CN=a"b;c,d"#));
+        assert_eq!(peer.hash.as_deref(), Some("h"));
+    }
+
+    #[test]
+    fn keys_are_case_insensitive() {
+        let xfcc: Xfcc = "hash=h;uri=spiffe://example.org/a".parse().unwrap();
+
+        let peer = xfcc.peer().unwrap();
+        assert_eq!(peer.hash.as_deref(), Some("h"));
+        assert_eq!(peer.uri, vec!["spiffe://example.org/a".to_owned()]);
+    }
+
+    #[test]
+    fn rejects_empty_headers() {
+        assert_eq!("".parse::<Xfcc>(), Err(XfccError::Empty));
+        assert_eq!(" , ".parse::<Xfcc>(), Err(XfccError::Empty));
+    }
+
+    #[test]
+    fn rejects_pairs_without_a_separator() {
+        assert_eq!(
+            "Hash".parse::<Xfcc>(),
+            Err(XfccError::MissingSeparator {
+                pair: "Hash".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        for header in [r#"Subject="CN=a"#, r#"Subject="CN=a"b"#] {
+            assert_eq!(
+                header.parse::<Xfcc>(),
+                Err(XfccError::UnterminatedQuote {
+                    key: "Subject".to_owned()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_duplicate_keys() {
+        assert_eq!(
+            "Issuer=x".parse::<Xfcc>(),
+            Err(XfccError::UnknownKey {
+                key: "Issuer".to_owned()
+            })
+        );
+        assert_eq!(
+            "Hash=a;Hash=b".parse::<Xfcc>(),
+            Err(XfccError::DuplicateKey {
+                key: "Hash".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_certificates() {
+        assert_eq!(
+            "Cert=not-a-pem".parse::<Xfcc>(),
+            Err(XfccError::InvalidPem {
+                key: "Cert".to_owned()
+            })
+        );
+    }
+}